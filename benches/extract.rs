@@ -0,0 +1,143 @@
+/*
+    This file is a part of telegram-media-deserialize.
+
+    Copyright (C) 2022 Apple Sheeple <AppleSheeple at github>
+
+    telegram-media-deserialize is free software: you can
+    redistribute it and/or modify it under the terms of
+    the Affero GNU General Public License as published by
+    the Free Software Foundation.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+    Affero GNU General Public License for more details.
+
+    You should have received a copy of the Affero GNU General Public License
+    along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+
+//! Extraction benchmarks over a synthetic serialized cache.
+//!
+//! Run with `cargo bench`. The cache size defaults to 512MiB, and can be set
+//! with the `TMD_BENCH_MIB` environment variable. Files are created in the
+//! system temporary directory, and removed afterwards.
+
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use telegram_media_deserialize::{DeserializedFile, GapPolicy, RawSlice, SeekWriter, SerializedFile, PART_SIZE, SLICE_SIZE};
+
+const PARTS_PER_SLICE: u32 = 64;
+const RUNS: usize = 3;
+
+/// Writes a serialized cache of `media_len` bytes of pseudo-random media, with slices in the
+/// order a streaming player reads them: the first slice, the last one (e.g. for a moov atom at
+/// the end), then the rest. The last part is short, like the last part of a real file.
+fn make_cache(path: &Path, media_len: u32) -> io::Result<()> {
+    let mut out = BufWriter::new(File::create(path)?);
+    let slice_len = PARTS_PER_SLICE * PART_SIZE;
+    let slices_count = media_len.div_ceil(slice_len);
+    let order = (0..slices_count.min(1))
+        .chain((1..slices_count).rev().take(1))
+        .chain(1..slices_count.saturating_sub(1));
+
+    let mut part = vec![0; PART_SIZE as usize];
+    let mut state = 0x2545_f491_4f6c_dd1du64;
+    for slice_i in order {
+        let start = slice_i * slice_len;
+        let end = (start + slice_len).min(media_len);
+        let parts_count = (end - start).div_ceil(PART_SIZE);
+        out.write_all(&parts_count.to_le_bytes())?;
+        for out_offset in (start..end).step_by(PART_SIZE as usize) {
+            let part_size = (end - out_offset).min(PART_SIZE);
+            for chunk in part.chunks_mut(8) {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                chunk.copy_from_slice(&state.to_le_bytes()[..chunk.len()]);
+            }
+            out.write_all(&out_offset.to_le_bytes())?;
+            out.write_all(&part_size.to_le_bytes())?;
+            out.write_all(&part[..part_size as usize])?;
+        }
+    }
+    out.flush()
+}
+
+fn bench<F: FnMut() -> u64>(name: &str, mut f: F) {
+    let mut best = Duration::MAX;
+    let mut written = 0;
+    for _ in 0..RUNS {
+        let start = Instant::now();
+        written = f();
+        best = best.min(start.elapsed());
+    }
+    let mib = written as f64 / (1024.0 * 1024.0);
+    println!("{name:<40} {mib:>8.1}MiB in {:>8.3}s ({:>8.1}MiB/s, best of {RUNS})",
+        best.as_secs_f64(), mib / best.as_secs_f64());
+}
+
+fn main() {
+    let mib: u32 = env::var("TMD_BENCH_MIB").ok()
+        .and_then(|mib| mib.parse().ok())
+        .unwrap_or(512);
+    // part offsets are 32-bit, so the media is at most 4GiB
+    let media_len = (u64::from(mib) * 1024 * 1024).checked_sub(u64::from(PART_SIZE / 2))
+        .and_then(|len| u32::try_from(len).ok())
+        .expect("TMD_BENCH_MIB must be from 1 to 4096");
+
+    let dir = env::temp_dir().join(format!("tmd-bench-{}", std::process::id()));
+    fs::create_dir_all(&dir).expect("creating bench dir");
+    let cache = dir.join("cache");
+    let out: PathBuf = dir.join("out");
+    make_cache(&cache, media_len).expect("writing synthetic cache");
+
+    let cache_name = cache.to_string_lossy().into_owned();
+    let out_name = out.to_string_lossy().into_owned();
+    let open = || SerializedFile::from_name(cache_name.clone()).expect("opening cache");
+    let open_mmap = || SerializedFile::from_name_mmap(cache_name.clone()).expect("mapping cache");
+
+    bench("get_info", || {
+        let info = open().get_info().expect("parsing cache");
+        info.end_offset()
+    });
+
+    bench("get_info (mmap)", || {
+        let info = open_mmap().get_info().expect("parsing cache");
+        info.end_offset()
+    });
+
+    let info = open().get_info().expect("parsing cache");
+
+    bench("write_to (DeserializedFile)", || {
+        let _ = fs::remove_file(&out);
+        let mut deserialized_file = DeserializedFile::from_name(out_name.clone()).expect("creating output");
+        open().write_to(&info, &mut deserialized_file).expect("extracting");
+        info.end_offset()
+    });
+
+    bench("write_to (DeserializedFile, mmap)", || {
+        let _ = fs::remove_file(&out);
+        let mut deserialized_file = DeserializedFile::from_name(out_name.clone()).expect("creating output");
+        open_mmap().write_to(&info, &mut deserialized_file).expect("extracting");
+        info.end_offset()
+    });
+
+    bench("write_to (SeekWriter<File>)", || {
+        let mut sink = SeekWriter(File::create(&out).expect("creating output"));
+        open().write_to(&info, &mut sink).expect("extracting");
+        info.end_offset()
+    });
+
+    bench("write_stream_to (io::sink)", || {
+        open().write_stream_to(&info, &mut [] as &mut [RawSlice], SLICE_SIZE, GapPolicy::Zero, &mut io::sink())
+            .expect("extracting")
+    });
+
+    fs::remove_dir_all(&dir).expect("removing bench dir");
+}
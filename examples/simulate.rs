@@ -0,0 +1,83 @@
+/*
+    This file is a part of telegram-media-deserialize.
+
+    Copyright (C) 2022 Apple Sheeple <AppleSheeple at github>
+
+    telegram-media-deserialize is free software: you can
+    redistribute it and/or modify it under the terms of
+    the Affero GNU General Public License as published by
+    the Free Software Foundation.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+    Affero GNU General Public License for more details.
+
+    You should have received a copy of the Affero GNU General Public License
+    along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+
+//! Writes the cache files Telegram Desktop would store while streaming a media file.
+//!
+//! Usage: cargo run --example simulate -- <media_file> <out_dir> [played_bytes]
+
+use std::env;
+use std::fs::{self, File};
+use std::io::BufWriter;
+use std::path::Path;
+use std::process::ExitCode;
+
+use telegram_media_deserialize::simulator::PlayerSimulator;
+use telegram_media_deserialize::{Error, Res, Serializer};
+
+fn run(media_name: &str, out_dir: &str, played: Option<u64>) -> Res<()> {
+    let mut media = File::open(media_name)
+        .map_err(|e| Error::io("opening for read", media_name, None, e))?;
+    let mut simulator = PlayerSimulator::from_media(&mut media)?;
+    if let Some(played) = played {
+        simulator.set_played(played);
+    }
+    let cache = simulator.cache();
+
+    fs::create_dir_all(out_dir)
+        .map_err(|e| Error::io("creating", out_dir, None, e))?;
+    let serialized_name = Path::new(out_dir).join("serialized").to_string_lossy().into_owned();
+    let mut serializer = Serializer::from_name(serialized_name.clone())?;
+    cache.write_serialized(&mut media, &mut serializer)?;
+    serializer.finish()?;
+
+    let mut args = serialized_name.clone();
+    for (i, (index, _)) in cache.raw_slices.iter().enumerate() {
+        let slice_name = Path::new(out_dir).join(format!("slice{index}")).to_string_lossy().into_owned();
+        let slice_file = File::options().write(true).create_new(true).open(&slice_name)
+            .map_err(|e| Error::io("creating for writing", &slice_name, None, e))?;
+        cache.write_raw_slice(&mut media, i, &mut BufWriter::new(slice_file))?;
+        args = format!("--slice-at {index} {slice_name} {args}");
+    }
+
+    match simulator.index() {
+        Some(index) => println!("Index at {}..{} read before playback", index.start, index.end),
+        None => println!("No index to seek to before playback"),
+    }
+    print!("{}", cache.coverage());
+    println!("Reassemble with: telegram-media-deserialize {args} <deserialized_file>");
+    Ok(())
+}
+
+fn main() -> ExitCode {
+    let args = env::args().skip(1).collect::<Vec<_>>();
+    let played = args.get(2).map(|played| played.parse());
+    let (Some(media_name), Some(out_dir), None | Some(Ok(_))) = (args.first(), args.get(1), &played) else {
+        eprintln!("Usage: simulate <media_file> <out_dir> [played_bytes]");
+        return ExitCode::from(2);
+    };
+
+    match run(media_name, out_dir, played.and_then(Result::ok)) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("Error: {e}");
+            ExitCode::from(e.exit_code())
+        },
+    }
+}
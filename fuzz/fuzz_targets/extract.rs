@@ -0,0 +1,61 @@
+#![no_main]
+
+//! Extracts arbitrary input as a serialized cache file, with all the extraction paths.
+
+use std::io::{self, Cursor, Read};
+
+use libfuzzer_sys::fuzz_target;
+use telegram_media_deserialize::{GapPolicy, HolePolicy, MediaSize, MediaStream, Mp4Check, RawSlice, SerializedFile, WriteAt, SLICE_SIZE};
+
+/// Discards writes, only checking them against the expected output length.
+struct Discard {
+    len: u64,
+}
+
+impl WriteAt for Discard {
+    fn write_all_at(&mut self, buf: &[u8], offset: u64) -> io::Result<()> {
+        assert!(offset + buf.len() as u64 <= self.len);
+        Ok(())
+    }
+}
+
+fuzz_target!(|data: &[u8]| {
+    let open = || SerializedFile::from_reader("fuzz".into(), Cursor::new(data)).expect("in-memory input");
+    let Ok(info) = open().get_info() else {
+        return;
+    };
+    let no_slices: &mut [RawSlice<Cursor<&[u8]>>] = &mut [];
+
+    let _ = open().write_to(&info, &mut Discard{len: info.end_offset()});
+
+    for gap_policy in [GapPolicy::Zero, GapPolicy::Cut] {
+        if let Ok(written) = open().write_stream_to(&info, no_slices, SLICE_SIZE, gap_policy, &mut io::sink()) {
+            assert!(written <= info.end_offset());
+        }
+    }
+
+    // The input can describe a stream of up to 4GiB, only read the start of it.
+    for hole_policy in [HolePolicy::Zero, HolePolicy::Error] {
+        let stream = MediaStream::from_info(open(), info.clone(), hole_policy);
+        let _ = io::copy(&mut stream.take(1 << 20), &mut io::sink());
+    }
+
+    // Only the covered container headers are read.
+    let mut stream = MediaStream::from_info(open(), info.clone(), HolePolicy::Zero);
+    let media_size = MediaSize::infer(&info, &info.coverage(), &mut stream).expect("in-memory input");
+    if let Some(container_size) = media_size.container {
+        assert!(container_size.size > 0);
+    }
+
+    // Boxes are contiguous, and their missing bytes are within them.
+    let coverage = info.coverage();
+    if let Some(check) = Mp4Check::walk(&mut stream, &coverage, &coverage).expect("in-memory input") {
+        let mut offset = 0;
+        for b in &check.boxes {
+            assert_eq!(b.offset, offset);
+            assert!(b.missing_bytes <= b.end - b.offset);
+            offset = b.end;
+        }
+        let _ = check.to_string();
+    }
+});
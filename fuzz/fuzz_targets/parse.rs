@@ -0,0 +1,89 @@
+#![no_main]
+
+//! Parses arbitrary input as a serialized cache file, and everything derived from the result.
+
+use std::io::{self, Cursor};
+
+use libfuzzer_sys::fuzz_target;
+use telegram_media_deserialize::{MediaSize, OverlapPolicy, Report, SerializedFile, Trailer, MAX_OVERLAPS};
+
+fuzz_target!(|data: &[u8]| {
+    let len = data.len() as u64;
+    let Ok(mut serialized_file) = SerializedFile::from_reader("fuzz".into(), Cursor::new(data)) else {
+        return;
+    };
+    let Ok(info) = serialized_file.get_info() else {
+        return;
+    };
+
+    // Every parsed part header lies within the input.
+    for slice in info.slices() {
+        assert!(slice.in_offset + 4 <= len);
+        assert!(slice.parts.len() <= slice.parts_count as usize);
+        for part in &slice.parts {
+            assert!(part.in_offset <= len);
+            assert!(part.part_size > 0);
+        }
+    }
+    assert!(info.trailing_bytes() <= len);
+    if let Trailer::Unknown{len: trailer_len, head} = info.trailer() {
+        assert_eq!(*trailer_len, info.trailing_bytes());
+        assert!(head.len() as u64 <= *trailer_len);
+    }
+    match (info.trailer(), info.header_slice().and_then(|hs| hs.media_size)) {
+        (Trailer::HeaderSlice(_), media_size) => assert!(media_size >= Some(info.end_offset())),
+        (_, Some(media_size)) => assert!(media_size <= info.end_offset()),
+        _ => (),
+    }
+    assert_eq!(info.parts().len(), info.slices().iter().map(|s| s.parts.len()).sum::<usize>());
+
+    let coverage = info.coverage();
+    assert!(coverage.covered_bytes() <= coverage.total_len());
+    assert!(info.last_contiguous_offset() <= info.end_offset());
+    let media_size = MediaSize{inferred: info.media_size(), container: None};
+    if let Some(size) = media_size.inferred {
+        assert!(size >= info.end_offset());
+    }
+    let _ = info.to_string();
+    let _ = coverage.to_string();
+
+    // Overlaps are within both parts, and resolving them keeps the same coverage.
+    let validation = serialized_file.validate(&info).expect("in-memory input");
+    assert!(validation.overlaps.len() <= MAX_OVERLAPS);
+    for o in &validation.overlaps {
+        assert!(o.first.in_offset <= o.second.in_offset);
+        assert!(!o.range.is_empty());
+        assert!(u64::from(o.first.out_offset) <= o.range.start && o.range.end <= o.first.out_end());
+        assert!(u64::from(o.second.out_offset) <= o.range.start && o.range.end <= o.second.out_end());
+    }
+    for policy in [OverlapPolicy::Last, OverlapPolicy::First] {
+        let resolved = info.with_overlap_policy(policy);
+        assert!(resolved.parts().windows(2).all(|w| w[0].out_end() <= u64::from(w[1].out_offset)));
+        assert_eq!(resolved.coverage(), coverage);
+    }
+
+    let report = Report{input: "fuzz", ordered_info: &info, raw_slices: Vec::new(), coverage: &coverage, media_size,
+        mp4: None, validation: &validation, overlap_policy: OverlapPolicy::Last};
+    report.write_json(&mut io::sink()).expect("writing to a sink");
+
+    // Parts recovered after a corrupt header lie within the input too.
+    let Ok(mut serialized_file) = SerializedFile::from_reader("fuzz".into(), Cursor::new(data)) else {
+        return;
+    };
+    serialized_file.set_resync(true);
+    let Ok(info) = serialized_file.get_info() else {
+        return;
+    };
+    for rp in info.recovered() {
+        assert!(rp.part.in_offset + u64::from(rp.part.part_size) <= len);
+        assert!((0.0..=1.0).contains(&rp.confidence));
+    }
+    let info = info.with_recovered(0.0);
+    let coverage = info.coverage();
+    let media_size = MediaSize{inferred: info.media_size(), container: None};
+    assert!(coverage.covered_bytes() <= coverage.total_len());
+    let validation = serialized_file.validate(&info).expect("in-memory input");
+    let report = Report{input: "fuzz", ordered_info: &info, raw_slices: Vec::new(), coverage: &coverage, media_size,
+        mp4: None, validation: &validation, overlap_policy: OverlapPolicy::Last};
+    report.write_json(&mut io::sink()).expect("writing to a sink");
+});
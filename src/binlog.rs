@@ -0,0 +1,271 @@
+/*
+    This file is a part of telegram-media-deserialize.
+
+    Copyright (C) 2022 Apple Sheeple <AppleSheeple at github>
+
+    telegram-media-deserialize is free software: you can
+    redistribute it and/or modify it under the terms of
+    the Affero GNU General Public License as published by
+    the Free Software Foundation.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+    Affero GNU General Public License for more details.
+
+    You should have received a copy of the Affero GNU General Public License
+    along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+
+//! Reading the `Storage::Cache::Database` binlog of a `media_cache` directory.
+//!
+//! The database lives in `media_cache/<version>/`, where `<version>` is read from the
+//! `media_cache/version` file (an i32 LE). Each cache entry is stored in its own encrypted
+//! file, placed at `<version>/<place[0]>/<place[1..]>` (a 7 bytes place id, each byte written
+//! as two uppercase hex digits, low nibble first).
+//!
+//! The binlog (`<version>/binlog`) is an encrypted file too. Its payload starts with a 16 bytes
+//! header (a format byte, 24 bits of flags, then reserved fields), followed by records:
+//!
+//! ```text
+//! Store       (0x01): type, tag, size (3 bytes LE), place (7 bytes), checksum (u32), key (16 bytes)
+//! MultiStore  (0x02): type, count (3 bytes LE), reserved (12 bytes), then `count` Store records
+//! MultiRemove (0x03): type, count (3 bytes LE), reserved (12 bytes), then `count` keys
+//! MultiAccess (0x04): type, count (3 bytes LE), time (12 bytes), then `count` keys
+//! ```
+//!
+//! A key is two u64 LE values (`high`, `low`). If the header has the *track estimated time*
+//! flag set, Store records are followed by a time (12 bytes) and 4 reserved bytes. A time is
+//! a relative (u64, split in two u32 LE values) and a system (u32 LE, unix seconds) time.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use crate::{EncryptedFile, EncryptionKey, Error, Res};
+
+const HEADER_SIZE: usize = 16;
+const KEY_SIZE: usize = 16;
+const STORE_SIZE: usize = 32;
+const STORE_WITH_TIME_SIZE: usize = 48;
+const MULTI_HEADER_SIZE: usize = 16;
+const TRACK_ESTIMATED_TIME_FLAG: u32 = 0x01;
+
+const STORE_TYPE: u8 = 0x01;
+const MULTI_STORE_TYPE: u8 = 0x02;
+const MULTI_REMOVE_TYPE: u8 = 0x03;
+const MULTI_ACCESS_TYPE: u8 = 0x04;
+
+/// A `Storage::Cache::Key`.
+///
+/// Media streamed in multiple slices is stored under consecutive keys, with the same `high`
+/// value and `low` incremented by one for each slice.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct CacheKey {
+    pub high: u64,
+    pub low: u64,
+}
+
+impl CacheKey {
+    fn from_bytes(bytes: &[u8]) -> Self {
+        let high = u64::from_le_bytes(bytes[..8].try_into().expect("8 bytes"));
+        let low = u64::from_le_bytes(bytes[8..16].try_into().expect("8 bytes"));
+        Self{high, low}
+    }
+}
+
+impl fmt::Display for CacheKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:016X}:{:016X}", self.high, self.low)
+    }
+}
+
+/// An estimated access time.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct AccessTime {
+    /// Monotonic time, relative to the database.
+    pub relative: u64,
+    /// Unix time in seconds.
+    pub system: u32,
+}
+
+impl AccessTime {
+    fn from_bytes(bytes: &[u8]) -> Self {
+        let u32_at = |i: usize| u32::from_le_bytes(bytes[i..i + 4].try_into().expect("4 bytes"));
+        let relative = u64::from(u32_at(0)) | (u64::from(u32_at(4)) << 32);
+        Self{relative, system: u32_at(8)}
+    }
+}
+
+/// A cache entry, as described by the latest binlog records for its key.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CacheEntry {
+    pub key: CacheKey,
+    /// Path of the (encrypted) entry file.
+    pub path: PathBuf,
+    /// Size of the stored value (without encryption padding).
+    pub size: u32,
+    pub tag: u8,
+    pub checksum: u32,
+    /// Only set if the database tracks access times.
+    pub access_time: Option<AccessTime>,
+}
+
+/// The key -> entry table of a cache database.
+#[derive(Debug, Clone, Default)]
+pub struct Binlog {
+    dir: PathBuf,
+    entries: BTreeMap<CacheKey, CacheEntry>,
+}
+
+impl Binlog {
+    /// Reads the binlog of the current database version in the `media_cache` directory `base`.
+    pub fn from_media_cache(base: &Path, key: &EncryptionKey) -> Res<Self> {
+        let version_path = base.join("version");
+        let version = fs::read(&version_path)
+            .map_err(|e| Error::io("reading", &version_path, None, e))?;
+        let version = <[u8; 4]>::try_from(version)
+            .map(i32::from_le_bytes)
+            .map_err(|_| Error::CorruptBinlog{path: version_path, offset: 0, reason: "bad version file size"})?;
+
+        Self::from_version_dir(&base.join(version.to_string()), key)
+    }
+
+    /// Reads the binlog in the database directory `dir` (i.e. `media_cache/<version>`).
+    pub fn from_version_dir(dir: &Path, key: &EncryptionKey) -> Res<Self> {
+        let path = dir.join("binlog");
+        let name = path.to_string_lossy().into_owned();
+        let mut binlog_file = EncryptedFile::from_name(name, key)?;
+
+        let mut data = Vec::with_capacity(binlog_file.len() as usize);
+        binlog_file.read_to_end(&mut data)
+            .map_err(|e| Error::io("reading", &path, None, e))?;
+
+        let mut ret = Self{dir: dir.to_owned(), entries: BTreeMap::new()};
+        ret.apply_records(&path, &data)?;
+        Ok(ret)
+    }
+
+    /// Applies all complete records, in order. A truncated or unknown record ends the binlog.
+    fn apply_records(&mut self, path: &Path, data: &[u8]) -> Res<()> {
+        let header = data.get(..HEADER_SIZE)
+            .ok_or_else(|| Error::CorruptBinlog{path: path.to_owned(), offset: 0, reason: "truncated header"})?;
+        let flags = u32::from_le_bytes(header[..4].try_into().expect("4 bytes")) >> 8;
+        let with_time = flags & TRACK_ESTIMATED_TIME_FLAG != 0;
+        let store_size = match with_time {
+            true => STORE_WITH_TIME_SIZE,
+            false => STORE_SIZE,
+        };
+
+        let mut offset = HEADER_SIZE;
+        while offset < data.len() {
+            let record = &data[offset..];
+            let multi_count = || record.get(..MULTI_HEADER_SIZE).map(|h| read_u24_le(&h[1..4]));
+
+            let record_size = match record[0] {
+                STORE_TYPE if record.len() >= store_size => {
+                    self.store(record, with_time);
+                    store_size
+                },
+                MULTI_STORE_TYPE => match multi_count() {
+                    Some(count) if record.len() >= MULTI_HEADER_SIZE + count * store_size => {
+                        record[MULTI_HEADER_SIZE..]
+                            .chunks_exact(store_size)
+                            .take(count)
+                            .for_each(|store| self.store(store, with_time));
+                        MULTI_HEADER_SIZE + count * store_size
+                    },
+                    _ => break,
+                },
+                MULTI_REMOVE_TYPE => match multi_count() {
+                    Some(count) if record.len() >= MULTI_HEADER_SIZE + count * KEY_SIZE => {
+                        record[MULTI_HEADER_SIZE..]
+                            .chunks_exact(KEY_SIZE)
+                            .take(count)
+                            .for_each(|key| { self.entries.remove(&CacheKey::from_bytes(key)); });
+                        MULTI_HEADER_SIZE + count * KEY_SIZE
+                    },
+                    _ => break,
+                },
+                MULTI_ACCESS_TYPE => match multi_count() {
+                    Some(count) if record.len() >= MULTI_HEADER_SIZE + count * KEY_SIZE => {
+                        let time = AccessTime::from_bytes(&record[4..MULTI_HEADER_SIZE]);
+                        for key in record[MULTI_HEADER_SIZE..].chunks_exact(KEY_SIZE).take(count) {
+                            if let Some(entry) = self.entries.get_mut(&CacheKey::from_bytes(key)) {
+                                entry.access_time = Some(time);
+                            }
+                        }
+                        MULTI_HEADER_SIZE + count * KEY_SIZE
+                    },
+                    _ => break,
+                },
+                _ => break,
+            };
+            offset += record_size;
+        }
+        Ok(())
+    }
+
+    fn store(&mut self, record: &[u8], with_time: bool) {
+        let tag = record[1];
+        let size = read_u24_le(&record[2..5]) as u32;
+        let place = &record[5..12];
+        let checksum = u32::from_le_bytes(record[12..16].try_into().expect("4 bytes"));
+        let key = CacheKey::from_bytes(&record[16..32]);
+        let access_time = with_time.then(|| AccessTime::from_bytes(&record[32..44]));
+        let path = self.dir.join(place_path(place));
+
+        self.entries.insert(key, CacheEntry{key, path, size, tag, checksum, access_time});
+    }
+
+    pub fn entries(&self) -> impl Iterator<Item=&CacheEntry> {
+        self.entries.values()
+    }
+
+    pub fn get(&self, key: &CacheKey) -> Option<&CacheEntry> {
+        self.entries.get(key)
+    }
+
+    /// Looks up the entry stored in the file at `path`.
+    pub fn find_by_path(&self, path: &Path) -> Option<&CacheEntry> {
+        let canonical = |p: &Path| fs::canonicalize(p).unwrap_or_else(|_| p.to_owned());
+        let path = canonical(path);
+        self.entries().find(|entry| canonical(&entry.path) == path)
+    }
+
+    /// Entries of the same media as `first`, i.e. with the same `high` key and a `low` key
+    /// at most `max_slices` after it, paired with their slice index (`low - first.low`).
+    ///
+    /// `first` itself (index 0) is included, if it exists.
+    pub fn slices(&self, first: CacheKey, max_slices: u64) -> Vec<(u64, &CacheEntry)> {
+        let last = CacheKey{high: first.high, low: first.low.saturating_add(max_slices)};
+        self.entries.range(first..=last)
+            .map(|(key, entry)| (key.low - first.low, entry))
+            .collect()
+    }
+
+    /// Keys that look like the first slice of a media, i.e. with no entry at `low - 1`.
+    pub fn first_slices(&self) -> impl Iterator<Item=&CacheEntry> {
+        self.entries()
+            .filter(|entry| entry.key.low
+                .checked_sub(1)
+                .map(|low| !self.entries.contains_key(&CacheKey{high: entry.key.high, low}))
+                .unwrap_or(true))
+    }
+}
+
+fn read_u24_le(bytes: &[u8]) -> usize {
+    usize::from(bytes[0]) | (usize::from(bytes[1]) << 8) | (usize::from(bytes[2]) << 16)
+}
+
+/// `<place[0]>/<place[1..]>`, each byte written as two uppercase hex digits, low nibble first.
+fn place_path(place: &[u8]) -> PathBuf {
+    const HEX: &[u8; 16] = b"0123456789ABCDEF";
+    let hex = |b: &u8| [HEX[usize::from(b & 0x0F)] as char, HEX[usize::from(b >> 4)] as char];
+    let dir = place[..1].iter().flat_map(hex).collect::<String>();
+    let file = place[1..].iter().flat_map(hex).collect::<String>();
+    PathBuf::from(dir).join(file)
+}
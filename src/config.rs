@@ -0,0 +1,60 @@
+/*
+    This file is a part of telegram-media-deserialize.
+
+    Copyright (C) 2022 Apple Sheeple <AppleSheeple at github>
+
+    telegram-media-deserialize is free software: you can
+    redistribute it and/or modify it under the terms of
+    the Affero GNU General Public License as published by
+    the Free Software Foundation.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+    Affero GNU General Public License for more details.
+
+    You should have received a copy of the Affero GNU General Public License
+    along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+
+use crate::{PART_SIZE, SLICE_SIZE};
+
+/// Limits used to parse serialized cache files, which follow Telegram Desktop's streaming
+/// constants.
+///
+/// The default is [`TDESKTOP_4`](Self::TDESKTOP_4). Files from builds with other constants can
+/// be parsed by setting the fields directly.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ParserConfig {
+    /// Maximum part count in a slice header.
+    pub max_parts_count: u32,
+    /// Maximum part size in a part header.
+    pub max_part_size: u32,
+    /// Size of the parts read by the streaming loader. Parts start at multiples of it, and
+    /// only the last part of the media is shorter.
+    pub part_size: u32,
+    /// Size of a slice, raw slice `i` starts at `i * slice_size`.
+    pub slice_size: u64,
+    /// Maximum number of overlapping runs of plausible headers considered at once by the
+    /// resync (see [`SerializedFile::set_resync`](crate::SerializedFile::set_resync)), which
+    /// bounds its memory use on large files.
+    pub max_resync_candidates: usize,
+}
+
+impl ParserConfig {
+    /// Telegram Desktop 4.x (as of Dec 2022): up to 80 parts per slice, 128KiB parts, 8MiB slices.
+    pub const TDESKTOP_4: Self = Self {
+        max_parts_count: 80,
+        max_part_size: 128 * 1024,
+        part_size: PART_SIZE,
+        slice_size: SLICE_SIZE,
+        max_resync_candidates: 4096,
+    };
+}
+
+impl Default for ParserConfig {
+    fn default() -> Self {
+        Self::TDESKTOP_4
+    }
+}
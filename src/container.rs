@@ -0,0 +1,263 @@
+/*
+    This file is a part of telegram-media-deserialize.
+
+    Copyright (C) 2022 Apple Sheeple <AppleSheeple at github>
+
+    telegram-media-deserialize is free software: you can
+    redistribute it and/or modify it under the terms of
+    the Affero GNU General Public License as published by
+    the Free Software Foundation.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+    Affero GNU General Public License for more details.
+
+    You should have received a copy of the Affero GNU General Public License
+    along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+
+//! Minimal parsing of MP4 (ISO-BMFF) and Matroska top-level structure.
+
+use std::io::{self, ErrorKind, Read, Seek, SeekFrom};
+use std::ops::Range;
+
+/// Matroska EBML header element ID.
+pub(crate) const EBML: u32 = 0x1A45_DFA3;
+/// Matroska Segment element ID.
+pub(crate) const SEGMENT: u32 = 0x1853_8067;
+/// Matroska SeekHead element ID.
+pub(crate) const SEEK_HEAD: u32 = 0x114D_9B74;
+/// Matroska Cluster element ID.
+pub(crate) const CLUSTER: u32 = 0x1F43_B675;
+/// Matroska Cues element ID.
+pub(crate) const CUES: u32 = 0x1C53_BB6B;
+
+const SEEK: u32 = 0x4DBB;
+const SEEK_ID: u32 = 0x53AB;
+const SEEK_POSITION: u32 = 0x53AC;
+
+/// A top-level MP4 box.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Mp4Box {
+    pub kind: [u8; 4],
+    pub offset: u64,
+    pub header_len: u64,
+    /// Box size including the header, `None` if the box extends to the end of the stream.
+    pub size: Option<u64>,
+}
+
+impl Mp4Box {
+    /// Byte range of the box, given the stream length `len` (used if the box has no size).
+    pub fn range(&self, len: u64) -> Range<u64> {
+        self.offset..self.size.map_or(len, |size| self.offset.saturating_add(size))
+    }
+}
+
+/// Reads the top-level boxes of an MP4 stream of length `len`.
+///
+/// Returns `None` if the stream doesn't start with an `ftyp` box. Walking stops at the first
+/// invalid box header, or at a box extending to the end of the stream.
+pub(crate) fn mp4_boxes<R: Read + Seek>(r: &mut R, len: u64) -> io::Result<Option<Vec<Mp4Box>>> {
+    let mut boxes = Vec::new();
+    let mut offset = 0u64;
+    while let Some(mp4_box) = read_mp4_box(r, offset, len)? {
+        if boxes.is_empty() && &mp4_box.kind != b"ftyp" {
+            return Ok(None);
+        }
+        boxes.push(mp4_box);
+        match mp4_box.size {
+            Some(size) => offset = offset.saturating_add(size),
+            None => break,
+        }
+    }
+    Ok((!boxes.is_empty()).then_some(boxes))
+}
+
+/// Reads the header of the MP4 box at `offset`, if a valid one fits before `len`.
+pub(crate) fn read_mp4_box<R: Read + Seek>(r: &mut R, offset: u64, len: u64) -> io::Result<Option<Mp4Box>> {
+    if offset.saturating_add(8) > len {
+        return Ok(None);
+    }
+    let mut header = [0; 16];
+    r.seek(SeekFrom::Start(offset))?;
+    r.read_exact(&mut header[..8])?;
+    let kind = header[4..8].try_into().expect("4 bytes");
+    let (header_len, size) = match u32::from_be_bytes(header[..4].try_into().expect("4 bytes")) {
+        0 => (8, None),
+        1 if offset.saturating_add(16) <= len => {
+            r.read_exact(&mut header[8..])?;
+            (16, Some(u64::from_be_bytes(header[8..].try_into().expect("8 bytes"))))
+        },
+        1 => return Ok(None),
+        size => (8, Some(u64::from(size))),
+    };
+    if size.is_some_and(|size| size < header_len) {
+        return Ok(None);
+    }
+    Ok(Some(Mp4Box{kind, offset, header_len, size}))
+}
+
+/// A Matroska element.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Element {
+    pub id: u32,
+    pub offset: u64,
+    pub header_len: u64,
+    /// Data size, `None` if unknown (e.g. a live stream).
+    pub size: Option<u64>,
+}
+
+impl Element {
+    /// Offset of the element data.
+    pub fn data_offset(&self) -> u64 {
+        self.offset + self.header_len
+    }
+
+    /// Offset right after the element, if its size is known.
+    pub fn end(&self) -> Option<u64> {
+        self.size.map(|size| self.data_offset().saturating_add(size))
+    }
+}
+
+/// Top-level structure of a Matroska stream.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Matroska {
+    pub segment: Element,
+    /// Children of the segment, up to the first one of unknown size.
+    pub children: Vec<Element>,
+    /// SeekHead entries, as `(element id, offset in the stream)`.
+    pub seeks: Vec<(u32, u64)>,
+}
+
+impl Matroska {
+    /// Offset of the first element with `id`, from the SeekHead or the segment children.
+    pub fn position(&self, id: u32) -> Option<u64> {
+        self.seeks.iter()
+            .find(|&&(seek_id, _)| seek_id == id)
+            .map(|&(_, offset)| offset)
+            .or_else(|| self.children.iter().find(|e| e.id == id).map(|e| e.offset))
+    }
+}
+
+/// Reads the top-level structure of a Matroska (or WebM) stream of length `len`.
+///
+/// Returns `None` if the stream doesn't start with an EBML header followed by a segment.
+pub(crate) fn matroska<R: Read + Seek>(r: &mut R, len: u64) -> io::Result<Option<Matroska>> {
+    let Some(ebml) = read_element(r, 0, len)? else {
+        return Ok(None);
+    };
+    let Some(segment_offset) = ebml.end().filter(|_| ebml.id == EBML) else {
+        return Ok(None);
+    };
+    let Some(segment) = read_element(r, segment_offset, len)?.filter(|e| e.id == SEGMENT) else {
+        return Ok(None);
+    };
+
+    let segment_end = segment.end().unwrap_or(len).min(len);
+    let mut children = Vec::new();
+    let mut offset = segment.data_offset();
+    while let Some(child) = read_element(r, offset, segment_end)? {
+        children.push(child);
+        match child.end() {
+            Some(end) => offset = end,
+            None => break,
+        }
+    }
+
+    let mut seeks = Vec::new();
+    for seek_head in children.iter().filter(|e| e.id == SEEK_HEAD) {
+        let seek_head_end = seek_head.end().unwrap_or(segment_end).min(segment_end);
+        for seek in read_children(r, seek_head.data_offset(), seek_head_end)?.iter().filter(|e| e.id == SEEK) {
+            let (mut id, mut position) = (None, None);
+            let seek_end = seek.end().unwrap_or(seek_head_end).min(seek_head_end);
+            for entry in read_children(r, seek.data_offset(), seek_end)? {
+                match entry.id {
+                    SEEK_ID => id = read_uint(r, &entry)?.and_then(|id| u32::try_from(id).ok()),
+                    SEEK_POSITION => position = read_uint(r, &entry)?,
+                    _ => (),
+                }
+            }
+            if let (Some(id), Some(position)) = (id, position) {
+                seeks.push((id, segment.data_offset().saturating_add(position)));
+            }
+        }
+    }
+
+    Ok(Some(Matroska{segment, children, seeks}))
+}
+
+/// Reads the header of the element at `offset`, if a valid one fits before `end`.
+pub(crate) fn read_element<R: Read + Seek>(r: &mut R, offset: u64, end: u64) -> io::Result<Option<Element>> {
+    let mut buf = [0; 16];
+    let header_max = end.saturating_sub(offset).min(16) as usize;
+    r.seek(SeekFrom::Start(offset))?;
+    let read = read_up_to(r, &mut buf[..header_max])?;
+    let buf = &buf[..read];
+
+    let Some((id_len, id)) = read_vint(buf, false).filter(|&(id_len, _)| id_len <= 4) else {
+        return Ok(None);
+    };
+    let Some((size_len, size)) = read_vint(&buf[id_len..], true) else {
+        return Ok(None);
+    };
+    let header_len = (id_len + size_len) as u64;
+    // All ones (after the length marker) means an unknown size.
+    let size = (size != (1 << (7 * size_len)) - 1).then_some(size);
+    Ok(Some(Element{id: id as u32, offset, header_len, size}))
+}
+
+/// Reads the children of an element with data in `offset..end`, up to the first one of unknown size.
+fn read_children<R: Read + Seek>(r: &mut R, mut offset: u64, end: u64) -> io::Result<Vec<Element>> {
+    let mut children = Vec::new();
+    while let Some(child) = read_element(r, offset, end)? {
+        children.push(child);
+        match child.end() {
+            Some(child_end) => offset = child_end,
+            None => break,
+        }
+    }
+    Ok(children)
+}
+
+/// Reads the data of an unsigned integer element.
+fn read_uint<R: Read + Seek>(r: &mut R, element: &Element) -> io::Result<Option<u64>> {
+    let Some(size) = element.size.filter(|&size| size <= 8) else {
+        return Ok(None);
+    };
+    let mut buf = [0; 8];
+    r.seek(SeekFrom::Start(element.data_offset()))?;
+    r.read_exact(&mut buf[8 - size as usize..])?;
+    Ok(Some(u64::from_be_bytes(buf)))
+}
+
+/// Decodes an EBML variable length integer, returning its length and value
+/// (with the length marker kept for IDs, and stripped for sizes).
+fn read_vint(buf: &[u8], strip_marker: bool) -> Option<(usize, u64)> {
+    let first = *buf.first()?;
+    let len = first.leading_zeros() as usize + 1;
+    if len > 8 || buf.len() < len {
+        return None;
+    }
+    let value = buf[..len].iter().fold(0u64, |acc, &b| acc << 8 | u64::from(b));
+    if strip_marker {
+        Some((len, value & ((1 << (7 * len)) - 1)))
+    } else {
+        Some((len, value))
+    }
+}
+
+/// Like `read_exact`, but stops at the end of the stream, returning the number of bytes read.
+fn read_up_to<R: Read>(r: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut read = 0;
+    while read < buf.len() {
+        match r.read(&mut buf[read..]) {
+            Ok(0) => break,
+            Ok(n) => read += n,
+            Err(e) if e.kind() == ErrorKind::Interrupted => (),
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(read)
+}
@@ -0,0 +1,158 @@
+/*
+    This file is a part of telegram-media-deserialize.
+
+    Copyright (C) 2022 Apple Sheeple <AppleSheeple at github>
+
+    telegram-media-deserialize is free software: you can
+    redistribute it and/or modify it under the terms of
+    the Affero GNU General Public License as published by
+    the Free Software Foundation.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+    Affero GNU General Public License for more details.
+
+    You should have received a copy of the Affero GNU General Public License
+    along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+
+use std::fmt;
+use std::ops::Range;
+
+/// Covered (and missing) byte ranges of the deserialized media stream.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Coverage {
+    /// Sorted, non-overlapping and non-adjacent covered ranges.
+    covered: Vec<Range<u64>>,
+    total_len: u64,
+}
+
+impl Coverage {
+    /// Merges overlapping and adjacent `ranges`.
+    ///
+    /// `total_len` is the full length of the media stream. If it's not known, the end of
+    /// the last covered range is used.
+    pub fn new(ranges: impl IntoIterator<Item=Range<u64>>, total_len: Option<u64>) -> Self {
+        let mut ranges = ranges.into_iter()
+            .filter(|r| !r.is_empty())
+            .collect::<Vec<_>>();
+        ranges.sort_by_key(|r| r.start);
+
+        let mut covered: Vec<Range<u64>> = Vec::with_capacity(ranges.len());
+        for range in ranges {
+            match covered.last_mut() {
+                Some(last) if range.start <= last.end => last.end = last.end.max(range.end),
+                _ => covered.push(range),
+            }
+        }
+
+        let mut coverage = Self{covered, total_len: 0};
+        coverage.total_len = total_len.unwrap_or(coverage.covered_end());
+        coverage
+    }
+
+    /// Adds a covered `range`.
+    pub fn insert(&mut self, range: Range<u64>) {
+        let total_len = Some(self.total_len.max(range.end));
+        *self = Self::new(self.covered.drain(..).chain([range]), total_len);
+    }
+
+    pub fn covered(&self) -> &[Range<u64>] {
+        &self.covered
+    }
+
+    /// Ranges in `0..total_len` not covered by any part.
+    pub fn missing(&self) -> Vec<Range<u64>> {
+        let mut ret = Vec::with_capacity(self.covered.len() + 1);
+        let mut pos = 0;
+        for range in &self.covered {
+            let gap_end = range.start.min(self.total_len);
+            if gap_end > pos {
+                ret.push(pos..gap_end);
+            }
+            pos = pos.max(range.end);
+        }
+        if pos < self.total_len {
+            ret.push(pos..self.total_len);
+        }
+        ret
+    }
+
+    pub fn total_len(&self) -> u64 {
+        self.total_len
+    }
+
+    /// Sets the full length of the media stream, e.g. once it's known (see
+    /// [`MediaSize`](crate::MediaSize)). It's never less than the end of the last covered range.
+    pub fn set_total_len(&mut self, total_len: u64) {
+        self.total_len = total_len.max(self.covered_end());
+    }
+
+    /// End of the last covered range.
+    pub fn covered_end(&self) -> u64 {
+        self.covered.last().map(|r| r.end).unwrap_or(0)
+    }
+
+    pub fn covered_bytes(&self) -> u64 {
+        self.covered.iter()
+            .map(|r| r.end.min(self.total_len).saturating_sub(r.start))
+            .sum()
+    }
+
+    pub fn missing_bytes(&self) -> u64 {
+        self.total_len - self.covered_bytes()
+    }
+
+    /// Covered bytes, as a percentage of `total_len`.
+    pub fn percent(&self) -> f64 {
+        match self.total_len {
+            0 => 0.0,
+            total_len => self.covered_bytes() as f64 * 100.0 / total_len as f64,
+        }
+    }
+
+    /// Whether the byte at `offset` is covered.
+    pub fn contains(&self, offset: u64) -> bool {
+        let i = self.covered.partition_point(|r| r.end <= offset);
+        self.covered.get(i).is_some_and(|r| r.start <= offset)
+    }
+
+    /// Number of covered bytes in `range`.
+    pub fn covered_bytes_in(&self, range: Range<u64>) -> u64 {
+        let i = self.covered.partition_point(|r| r.end <= range.start);
+        self.covered[i..].iter()
+            .take_while(|r| r.start < range.end)
+            .map(|r| r.end.min(range.end) - r.start.max(range.start))
+            .sum()
+    }
+
+    /// Whether all of `range` is covered.
+    pub fn contains_range(&self, range: Range<u64>) -> bool {
+        if range.is_empty() {
+            return true;
+        }
+        let i = self.covered.partition_point(|r| r.end <= range.start);
+        self.covered.get(i).is_some_and(|r| r.start <= range.start && range.end <= r.end)
+    }
+}
+
+impl fmt::Display for Coverage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (covered_bytes, total_len, percent) = (self.covered_bytes(), self.total_len, self.percent());
+        writeln!(f, "Coverage: {covered_bytes} of {total_len} bytes ({percent:.2}%)")?;
+
+        let mut missing = self.missing().into_iter().peekable();
+        for covered in &self.covered {
+            while let Some(gap) = missing.next_if(|gap| gap.start < covered.start) {
+                writeln!(f, " Missing: {}..{} ({} bytes)", gap.start, gap.end, gap.end - gap.start)?;
+            }
+            writeln!(f, " Covered: {}..{} ({} bytes)", covered.start, covered.end, covered.end - covered.start)?;
+        }
+        for gap in missing {
+            writeln!(f, " Missing: {}..{} ({} bytes)", gap.start, gap.end, gap.end - gap.start)?;
+        }
+        Ok(())
+    }
+}
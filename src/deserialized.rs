@@ -0,0 +1,208 @@
+/*
+    This file is a part of telegram-media-deserialize.
+
+    Copyright (C) 2022 Apple Sheeple <AppleSheeple at github>
+
+    telegram-media-deserialize is free software: you can
+    redistribute it and/or modify it under the terms of
+    the Affero GNU General Public License as published by
+    the Free Software Foundation.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+    Affero GNU General Public License for more details.
+
+    You should have received a copy of the Affero GNU General Public License
+    along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+
+use std::ops::Range;
+use std::path::PathBuf;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write, Seek, SeekFrom};
+
+use crate::{Coverage, Error, Res, WriteAt};
+
+/// The deserialized (output) media file.
+///
+/// Implements [`WriteAt`], so it can be passed as a sink to
+/// [`SerializedFile::write_to`](crate::SerializedFile::write_to). [`Write`] and [`Seek`]
+/// are implemented too.
+#[derive(Debug)]
+pub struct DeserializedFile {
+    name: String,
+    file: File,
+}
+
+impl DeserializedFile {
+    /// Creates a new file named `name`. Fails if a file with that name already exists.
+    pub fn from_name(name: String) -> Res<Self> {
+        let path  = PathBuf::from(name.clone());
+
+        (!path.exists())
+            .then_some(())
+            .ok_or_else(|| Error::OutputExists{path: path.clone()})?;
+
+
+        let file = OpenOptions::new()
+            .create_new(true)
+            .write(true)
+            .open(&path)
+            .map_err(|e| Error::io("creating for writing", &path, None, e))?;
+
+        Ok(Self {name, file})
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Sets the file length, extending it with a hole if needed.
+    pub fn set_len(&mut self, len: u64) -> Res<()> {
+        self.file.set_len(len)
+            .map_err(|e| Error::io("setting length of", &self.name, Some(len), e))
+    }
+
+    /// Filesystem block size, the granularity of holes in a sparse file.
+    #[cfg(unix)]
+    pub fn block_size(&self) -> Res<u64> {
+        use std::os::unix::fs::MetadataExt;
+        self.file.metadata()
+            .map(|m| m.blksize())
+            .map_err(|e| Error::io("getting metadata of", &self.name, None, e))
+    }
+
+    #[cfg(not(unix))]
+    pub fn block_size(&self) -> Res<u64> {
+        Err(Error::Unsupported{what: "getting the filesystem block size"})
+    }
+
+    /// Allocates the covered ranges of `coverage`, extending the file to the end of the last
+    /// one, so that only missing ranges are left as holes (in a sparse file).
+    #[cfg(target_os = "linux")]
+    pub fn preallocate(&mut self, coverage: &Coverage) -> Res<()> {
+        use std::os::fd::AsRawFd;
+
+        for range in coverage.covered() {
+            let (start, len) = (range.start as libc::off_t, (range.end - range.start) as libc::off_t);
+            // SAFETY: fallocate() on a valid, open file descriptor.
+            let ret = unsafe { libc::fallocate(self.file.as_raw_fd(), 0, start, len) };
+            if ret != 0 {
+                return Err(Error::io("preallocating", &self.name, Some(range.start), io::Error::last_os_error()));
+            }
+        }
+        Ok(())
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn preallocate(&mut self, _coverage: &Coverage) -> Res<()> {
+        Err(Error::Unsupported{what: "preallocating sparse files"})
+    }
+
+    /// Lists the holes of the file, using `SEEK_HOLE`/`SEEK_DATA`.
+    ///
+    /// The file position is restored afterwards.
+    #[cfg(target_os = "linux")]
+    pub fn holes(&mut self) -> Res<Vec<Range<u64>>> {
+        use std::os::fd::AsRawFd;
+
+        let fd = self.file.as_raw_fd();
+        let lseek = |offset: u64, whence| {
+            // SAFETY: lseek() on a valid, open file descriptor.
+            match unsafe { libc::lseek(fd, offset as libc::off_t, whence) } {
+                -1 => Err(io::Error::last_os_error()),
+                ret => Ok(ret as u64),
+            }
+        };
+
+        let saved_pos = self.file.stream_position()
+            .map_err(|e| Error::io("getting stream position of", &self.name, None, e))?;
+        let len = self.file.metadata()
+            .map_err(|e| Error::io("getting metadata of", &self.name, None, e))?
+            .len();
+
+        let mut holes = Vec::new();
+        let mut pos = 0;
+        while pos < len {
+            let data = match lseek(pos, libc::SEEK_DATA) {
+                Ok(data) => data,
+                Err(e) if e.raw_os_error() == Some(libc::ENXIO) => len,
+                Err(e) => return Err(Error::io("seeking data in", &self.name, Some(pos), e)),
+            };
+            if data > pos {
+                holes.push(pos..data);
+            }
+            if data >= len {
+                break;
+            }
+            pos = lseek(data, libc::SEEK_HOLE)
+                .map_err(|e| Error::io("seeking hole in", &self.name, Some(data), e))?;
+        }
+
+        self.file.seek(SeekFrom::Start(saved_pos))
+            .map_err(|e| Error::io("seeking", &self.name, Some(saved_pos), e))?;
+        Ok(holes)
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn holes(&mut self) -> Res<Vec<Range<u64>>> {
+        Err(Error::Unsupported{what: "listing holes of sparse files"})
+    }
+
+    /// Checks that the holes of the file match the missing ranges of `coverage` (see
+    /// [`expected_holes`]), and returns their number.
+    pub fn check_holes(&mut self, coverage: &Coverage) -> Res<usize> {
+        let holes = self.holes()?;
+        let expected = expected_holes(coverage, self.block_size()?);
+        match holes == expected {
+            true => Ok(holes.len()),
+            false => Err(Error::HoleMismatch{path: self.name.clone().into(), holes, expected}),
+        }
+    }
+}
+
+/// The ranges that should be holes in a sparse file with the missing ranges of
+/// `coverage`, i.e. the missing ranges shrunk to whole blocks of `block_size`.
+pub fn expected_holes(coverage: &Coverage, block_size: u64) -> Vec<Range<u64>> {
+    let block_size = block_size.max(1);
+    coverage.missing().into_iter()
+        .map(|r| {
+            let start = r.start.div_ceil(block_size) * block_size;
+            // a hole at the end of the file doesn't have to end at a block boundary
+            let end = match r.end == coverage.total_len() {
+                true => r.end,
+                false => r.end / block_size * block_size,
+            };
+            start..end
+        })
+        .filter(|r| r.start < r.end)
+        .collect()
+}
+
+impl Write for DeserializedFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.file.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl Seek for DeserializedFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.file.seek(pos)
+    }
+}
+
+impl WriteAt for DeserializedFile {
+    fn write_all_at(&mut self, buf: &[u8], offset: u64) -> io::Result<()> {
+        self.file.write_all_at(buf, offset)
+    }
+
+    fn as_file(&self) -> Option<&File> {
+        Some(&self.file)
+    }
+}
@@ -0,0 +1,268 @@
+/*
+    This file is a part of telegram-media-deserialize.
+
+    Copyright (C) 2022 Apple Sheeple <AppleSheeple at github>
+
+    telegram-media-deserialize is free software: you can
+    redistribute it and/or modify it under the terms of
+    the Affero GNU General Public License as published by
+    the Free Software Foundation.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+    Affero GNU General Public License for more details.
+
+    You should have received a copy of the Affero GNU General Public License
+    along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+
+//! Telegram Desktop's `media_cache` files are stored encrypted (`Storage::EncryptedFile`).
+//!
+//! An encrypted file starts with a 64 bytes random *salt*, followed by an AES-256-CTR
+//! encrypted 48 bytes header and the (padded) payload, encrypted in the same CTR stream.
+//!
+//! The CTR key and IV are derived from the 256 bytes local key and the salt:
+//!
+//! ```text
+//! key = sha256(local_key[..128] + salt[..32])
+//! iv  = sha256(local_key[128..] + salt[32..])[..16]
+//! ```
+//!
+//! The header holds a format byte and reserved fields (all zero), the application version
+//! and a checksum. The checksum is not verified here, a wrong key is instead detected by
+//! the format/reserved fields not decrypting to zero.
+
+use std::fmt;
+use std::path::PathBuf;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom};
+
+use aes::Aes256;
+use aes::cipher::{BlockEncrypt, KeyInit};
+use aes::cipher::generic_array::GenericArray;
+use sha2::{Digest, Sha256};
+
+use crate::{Error, Res};
+
+pub const KEY_SIZE: usize = 256;
+const SALT_SIZE: usize = 64;
+const BLOCK_SIZE: usize = 16;
+const ENCRYPTED_HEADER_SIZE: usize = 48;
+const HEADER_SIZE: u64 = (SALT_SIZE + ENCRYPTED_HEADER_SIZE) as u64;
+const READ_CHUNK_SIZE: usize = 64 * 1024;
+
+/// The local key used to encrypt `media_cache` files.
+#[derive(Clone)]
+pub struct EncryptionKey([u8; KEY_SIZE]);
+
+impl fmt::Debug for EncryptionKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("EncryptionKey(..)")
+    }
+}
+
+impl EncryptionKey {
+    pub fn new(data: [u8; KEY_SIZE]) -> Self {
+        Self(data)
+    }
+
+    /// Reads a raw 256 bytes key from the file named `name`.
+    pub fn from_name(name: &str) -> Res<Self> {
+        let path = PathBuf::from(name);
+        path.exists()
+            .then_some(())
+            .ok_or_else(|| Error::InputMissing{path: path.clone()})?;
+
+        let data = std::fs::read(&path)
+            .map_err(|e| Error::io("reading key from", &path, None, e))?;
+
+        let data = data.try_into()
+            .map_err(|data: Vec<u8>| Error::InvalidKeyFile{path, len: data.len() as u64})?;
+
+        Ok(Self(data))
+    }
+
+    pub fn data(&self) -> &[u8; KEY_SIZE] {
+        &self.0
+    }
+
+    fn prepare_ctr_state(&self, salt: &[u8; SALT_SIZE]) -> CtrState {
+        let (key_l, key_r) = self.0.split_at(KEY_SIZE / 2);
+        let (salt_l, salt_r) = salt.split_at(SALT_SIZE / 2);
+
+        let key = Sha256::new().chain_update(key_l).chain_update(salt_l).finalize();
+        let iv = Sha256::new().chain_update(key_r).chain_update(salt_r).finalize();
+
+        let cipher = Aes256::new(&key);
+        let mut ctr_iv = [0; BLOCK_SIZE];
+        ctr_iv.copy_from_slice(&iv[..BLOCK_SIZE]);
+
+        CtrState{cipher, iv: ctr_iv}
+    }
+}
+
+struct CtrState {
+    cipher: Aes256,
+    iv: [u8; BLOCK_SIZE],
+}
+
+impl CtrState {
+    /// The IV is a big-endian 128-bit counter, incremented once per block.
+    fn counter(&self, block_index: u64) -> [u8; BLOCK_SIZE] {
+        let iv = u128::from_be_bytes(self.iv);
+        iv.wrapping_add(block_index.into()).to_be_bytes()
+    }
+
+    /// Decrypts (or encrypts) `data` in-place. `offset` is the block aligned position of
+    /// `data` in the encrypted stream (which starts right after the salt).
+    fn apply(&self, data: &mut [u8], offset: u64) {
+        debug_assert_eq!(offset % BLOCK_SIZE as u64, 0);
+        let first_block = offset / BLOCK_SIZE as u64;
+        for (i, chunk) in data.chunks_mut(BLOCK_SIZE).enumerate() {
+            let mut block = GenericArray::from(self.counter(first_block + i as u64));
+            self.cipher.encrypt_block(&mut block);
+            chunk.iter_mut()
+                .zip(block.iter())
+                .for_each(|(b, k)| *b ^= k);
+        }
+    }
+}
+
+/// A decrypting `Read + Seek` view over the payload of an encrypted `media_cache` file.
+///
+/// Nothing is written to disk, so it can be passed directly to
+/// [`SerializedFile::from_reader`](crate::SerializedFile::from_reader).
+pub struct EncryptedFile<R = File> {
+    name: String,
+    file: R,
+    state: CtrState,
+    len: u64,
+    pos: u64,
+    buf: Vec<u8>,
+    padded: bool,
+}
+
+impl<R> fmt::Debug for EncryptedFile<R> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("EncryptedFile")
+            .field("name", &self.name)
+            .field("len", &self.len)
+            .field("pos", &self.pos)
+            .finish_non_exhaustive()
+    }
+}
+
+impl EncryptedFile {
+    /// Opens the existing encrypted file named `name` for reading.
+    pub fn from_name(name: String, key: &EncryptionKey) -> Res<Self> {
+        let path  = PathBuf::from(name.clone());
+        path.exists()
+            .then_some(())
+            .ok_or_else(|| Error::InputMissing{path: path.clone()})?;
+
+        let file = OpenOptions::new()
+            .read(true)
+            .open(&path)
+            .map_err(|e| Error::io("opening for read", &path, None, e))?;
+
+        Self::from_reader(name, file, key)
+    }
+}
+
+impl<R: Read + Seek> EncryptedFile<R> {
+    /// Reads the salt and checks the header of an already opened encrypted stream.
+    /// `name` is only used in messages.
+    pub fn from_reader(name: String, mut file: R, key: &EncryptionKey) -> Res<Self> {
+        let file_len = file.seek(SeekFrom::End(0))
+            .map_err(|e| Error::io("getting length of", &name, None, e))?;
+
+        (file_len >= HEADER_SIZE)
+            .then_some(())
+            .ok_or_else(|| Error::TruncatedHeader{path: name.clone().into(), len: file_len})?;
+
+        let mut salt = [0; SALT_SIZE];
+        let mut header = [0; ENCRYPTED_HEADER_SIZE];
+        file.seek(SeekFrom::Start(0))
+            .and_then(|_| file.read_exact(&mut salt))
+            .and_then(|_| file.read_exact(&mut header))
+            .map_err(|e| Error::io("reading header from", &name, Some(0), e))?;
+
+        let state = key.prepare_ctr_state(&salt);
+        state.apply(&mut header, 0);
+
+        // format (1 byte), reserved1 (3 bytes) and reserved2 (4 bytes) are all zero
+        header[..8].iter().all(|&b| b == 0)
+            .then_some(())
+            .ok_or_else(|| Error::WrongKey{path: name.clone().into()})?;
+
+        let len = file_len - HEADER_SIZE;
+        Ok(Self {name, file, state, len, pos: 0, buf: Vec::new(), padded: true})
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Length of the decrypted payload, including padding (unless [truncated](Self::truncate)).
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    /// Only use the first `len` bytes of the payload, to drop the padding (up to a block) that
+    /// the encryption adds. The payload size isn't stored in the file, but in the cache
+    /// database (see [`CacheEntry::size`](crate::binlog::CacheEntry::size)).
+    pub fn truncate(&mut self, len: u64) {
+        self.len = self.len.min(len);
+        self.padded = false;
+    }
+
+    /// Whether the payload may end with padding, i.e. it wasn't [truncated](Self::truncate).
+    pub fn is_padded(&self) -> bool {
+        self.padded
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+impl<R: Read + Seek> Read for EncryptedFile<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos >= self.len || buf.is_empty() {
+            return Ok(0);
+        }
+
+        let aligned = self.pos - self.pos % BLOCK_SIZE as u64;
+        let skip = (self.pos - aligned) as usize;
+        let want = buf.len()
+            .min(READ_CHUNK_SIZE)
+            .min((self.len - self.pos) as usize);
+        let raw_len = (skip + want)
+            .next_multiple_of(BLOCK_SIZE)
+            .min((self.len - aligned) as usize);
+
+        self.buf.resize(raw_len, 0);
+        self.file.seek(SeekFrom::Start(HEADER_SIZE + aligned))?;
+        self.file.read_exact(&mut self.buf)?;
+        self.state.apply(&mut self.buf, ENCRYPTED_HEADER_SIZE as u64 + aligned);
+
+        buf[..want].copy_from_slice(&self.buf[skip..skip + want]);
+        self.pos += want as u64;
+        Ok(want)
+    }
+}
+
+impl<R: Read + Seek> Seek for EncryptedFile<R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let new_pos = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => self.len.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.pos.checked_add_signed(offset),
+        };
+        self.pos = new_pos.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput,
+                "invalid seek to a negative or overflowing position"))?;
+        Ok(self.pos)
+    }
+}
@@ -0,0 +1,159 @@
+/*
+    This file is a part of telegram-media-deserialize.
+
+    Copyright (C) 2022 Apple Sheeple <AppleSheeple at github>
+
+    telegram-media-deserialize is free software: you can
+    redistribute it and/or modify it under the terms of
+    the Affero GNU General Public License as published by
+    the Free Software Foundation.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+    Affero GNU General Public License for more details.
+
+    You should have received a copy of the Affero GNU General Public License
+    along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+
+use std::fmt;
+use std::io;
+use std::ops::Range;
+use std::path::PathBuf;
+
+/// Errors returned by this crate.
+#[derive(Debug)]
+pub enum Error {
+    /// The input file does not exist or is not accessible.
+    InputMissing { path: PathBuf },
+    /// The output file already exists.
+    OutputExists { path: PathBuf },
+    /// An I/O operation failed. `path` is `None` for caller-provided sinks.
+    Io {
+        op: &'static str,
+        path: Option<PathBuf>,
+        offset: Option<u64>,
+        source: io::Error,
+    },
+    /// A slice header has a part count of zero or above the allowed maximum.
+    MalformedSliceHeader { path: PathBuf, in_offset: u64, parts: u32, max: u32 },
+    /// A part header has a part size of zero or above the allowed maximum.
+    PartSizeOutOfRange { path: PathBuf, in_offset: u64, part_size: u32, max: u32 },
+    /// A part payload ends before `part_size` bytes could be read.
+    TruncatedPart { path: PathBuf, in_offset: u64, part_size: u32, read: u64 },
+    /// A raw key file is not exactly [`KEY_SIZE`](crate::encrypted::KEY_SIZE) bytes long.
+    InvalidKeyFile { path: PathBuf, len: u64 },
+    /// An encrypted file is shorter than its salt and header.
+    TruncatedHeader { path: PathBuf, len: u64 },
+    /// The header of an encrypted file did not decrypt correctly with the given key.
+    WrongKey { path: PathBuf },
+    /// A `key_datas` file is malformed.
+    CorruptKeyFile { path: PathBuf, reason: &'static str },
+    /// The local key could not be decrypted with the given passcode (or with no passcode).
+    WrongPasscode { path: PathBuf },
+    /// A cache database binlog (or version file) is malformed.
+    CorruptBinlog { path: PathBuf, offset: u64, reason: &'static str },
+    /// A raw slice file ended before its expected length.
+    TruncatedSlice { path: PathBuf, len: u64, read: u64 },
+    /// A read from a [`MediaStream`](crate::MediaStream) hit a range not covered by any part.
+    Hole { offset: u64, end: u64 },
+    /// The operation is not supported on this platform.
+    Unsupported { what: &'static str },
+    /// The holes of a sparse output file don't match its missing ranges.
+    HoleMismatch { path: PathBuf, holes: Vec<Range<u64>>, expected: Vec<Range<u64>> },
+}
+
+impl Error {
+    /// An [`Error::Io`] for `op` (e.g. "reading") failing on the file at `path`.
+    pub fn io(op: &'static str, path: impl Into<PathBuf>, offset: Option<u64>, source: io::Error) -> Self {
+        Self::Io { op, path: Some(path.into()), offset, source }
+    }
+
+    pub(crate) fn sink(op: &'static str, offset: u64, source: io::Error) -> Self {
+        Self::Io { op, path: None, offset: Some(offset), source }
+    }
+
+    /// Process exit code for this error.
+    ///
+    /// `1` is left for unexpected failures, and `2` is used by the CLI for usage errors.
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            Self::Io{..} => 3,
+            Self::InputMissing{..} => 4,
+            Self::OutputExists{..} => 5,
+            Self::MalformedSliceHeader{..} => 6,
+            Self::PartSizeOutOfRange{..} => 7,
+            Self::TruncatedPart{..} => 8,
+            Self::InvalidKeyFile{..} => 9,
+            Self::TruncatedHeader{..} => 10,
+            Self::WrongKey{..} => 11,
+            Self::CorruptKeyFile{..} => 12,
+            Self::WrongPasscode{..} => 13,
+            Self::CorruptBinlog{..} => 14,
+            Self::TruncatedSlice{..} => 15,
+            Self::Hole{..} => 16,
+            Self::Unsupported{..} => 17,
+            Self::HoleMismatch{..} => 18,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InputMissing{path} => write!(f, "'{}' not accessible or does not exist", path.display()),
+            Self::OutputExists{path} => write!(f, "'{}' already exists", path.display()),
+            Self::Io{op, path, offset, source} => {
+                write!(f, "{op} ")?;
+                match path {
+                    Some(path) => write!(f, "'{}'", path.display())?,
+                    None => write!(f, "output")?,
+                }
+                if let Some(offset) = offset {
+                    write!(f, " at offset={offset}")?;
+                }
+                write!(f, " failed: {source}")
+            },
+            Self::MalformedSliceHeader{path, in_offset, parts, max} => write!(f,
+                "'{}': slice header at in_offset={in_offset} has parts={parts}, which is zero or > max allowed({max})",
+                path.display()),
+            Self::PartSizeOutOfRange{path, in_offset, part_size, max} => write!(f,
+                "'{}': part header at in_offset={in_offset} has part_size={part_size}, which is zero or > max allowed({max})",
+                path.display()),
+            Self::TruncatedPart{path, in_offset, part_size, read} => write!(f,
+                "'{}': part at in_offset={in_offset} is truncated, only {read} of {part_size} bytes available",
+                path.display()),
+            Self::InvalidKeyFile{path, len} => write!(f,
+                "'{}': key file is {len} bytes, expected {}", path.display(), crate::encrypted::KEY_SIZE),
+            Self::TruncatedHeader{path, len} => write!(f,
+                "'{}': file is too short ({len} bytes) to be an encrypted cache file", path.display()),
+            Self::WrongKey{path} => write!(f,
+                "'{}': wrong key, or not an encrypted cache file", path.display()),
+            Self::CorruptKeyFile{path, reason} => write!(f,
+                "'{}': corrupt key file: {reason}", path.display()),
+            Self::WrongPasscode{path} => write!(f,
+                "'{}': wrong or missing local passcode", path.display()),
+            Self::CorruptBinlog{path, offset, reason} => write!(f,
+                "'{}': corrupt cache database at offset={offset}: {reason}", path.display()),
+            Self::TruncatedSlice{path, len, read} => write!(f,
+                "'{}': slice is truncated, only {read} of {len} bytes available", path.display()),
+            Self::Hole{offset, end} => write!(f,
+                "no data available at offset={offset} (missing up to offset={end})"),
+            Self::Unsupported{what} => write!(f, "{what} is not supported on this platform"),
+            Self::HoleMismatch{path, holes, expected} => write!(f,
+                "'{}': holes {holes:?} don't match expected holes {expected:?} (the filesystem may not support sparse files)",
+                path.display()),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Io{source, ..} => Some(source),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,116 @@
+/*
+    This file is a part of telegram-media-deserialize.
+
+    Copyright (C) 2022 Apple Sheeple <AppleSheeple at github>
+
+    telegram-media-deserialize is free software: you can
+    redistribute it and/or modify it under the terms of
+    the Affero GNU General Public License as published by
+    the Free Software Foundation.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+    Affero GNU General Public License for more details.
+
+    You should have received a copy of the Affero GNU General Public License
+    along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+//! Telegram Desktop's cached `media_cache` can be decrypted using a python script available here:
+//! https://github.com/lilydjwg/telegram-cache-decryption
+//!
+//! Or natively, without writing the decrypted data to disk, using [`EncryptedFile`].
+//!
+//! You may notice than not all decrypted media files are playable, and there are no files
+//! that are larger than 10MiB.
+//!
+//! Telegram Desktop (as of Dec 2022) seem to split larger media files into multiple cache
+//! files, the first of which is serialized for streaming purposes. Other cache files may
+//! not exist if the media is not fully cached.
+//!
+//! Serialization is simple, the serialized cache file contains one or more *slices*, each
+//! slice is split into multiple *parts*.
+//!
+//! A *slice* header is simply 4 bytes indicating the number of parts in it.
+//!
+//! A *part* header is simply 8 bytes, with the first four indicating the deserialized media
+//! stream offset, followed by four bytes indicating the part byte size.
+//!
+//! Note that parts are not necessarily contiguous, or ordered over multiple slices. The reader
+//! side of this serialized cache file emulates a media player, so if an MP4 file has a moov atom
+//! necessary for playback at the end of the media file, the reader will seek to the end and read
+//! from there, then come back (in the next slice).
+//!
+//! The next split cache files are not serialized, each one is the raw data of a slice, and belongs
+//! at `index * slice_size` in the deserialized stream (see [`RawSlice`]). Parts written with a
+//! forward seek (as described above) that a raw slice covers are skipped, and the slice's data is
+//! used instead (see [`SerializedFile::write_with_slices_to`]).
+//!
+//! Final note, there are a few bytes left after the parsed slices in the serialized file. I don't
+//! know what they are. But simply discarding them worked for me. They are decoded where
+//! possible, see [`Trailer`] and [`HeaderSlice`].
+//!
+//! # Library usage
+//!
+//! ```no_run
+//! use telegram_media_deserialize::{SerializedFile, DeserializedFile};
+//!
+//! let mut serialized_file = SerializedFile::from_name("cache_file".into())?;
+//! let ordered_info = serialized_file.get_info()?;
+//!
+//! println!("{} parts, last contiguous offset: {}",
+//!     ordered_info.parts().len(), ordered_info.last_contiguous_offset());
+//!
+//! let mut deserialized_file = DeserializedFile::from_name("media.mp4".into())?;
+//! serialized_file.write_to(&ordered_info, &mut deserialized_file)?;
+//! # Ok::<(), telegram_media_deserialize::Error>(())
+//! ```
+
+pub mod binlog;
+mod config;
+mod container;
+mod coverage;
+mod deserialized;
+pub mod encrypted;
+mod error;
+pub mod mapfile;
+mod media_size;
+mod mp4_check;
+mod raw_slice;
+mod report;
+mod resync;
+mod serialized;
+mod serializer;
+pub mod simulator;
+mod stream;
+pub mod tdata;
+mod trailer;
+mod validate;
+mod write_at;
+
+pub use config::ParserConfig;
+pub use coverage::Coverage;
+pub use deserialized::{expected_holes, DeserializedFile};
+pub use encrypted::{EncryptedFile, EncryptionKey};
+pub use error::Error;
+pub use media_size::{container_size, Container, ContainerSize, MediaSize};
+pub use mp4_check::{BoxStatus, Mp4Check, TopLevelBox, WalkEnd, REQUIRED_BOXES};
+pub use raw_slice::RawSlice;
+pub use report::{RawSliceReport, Report};
+pub use resync::RecoveredPart;
+pub use serialized::{GapPolicy, PartInfo, OrderedPartInfos, SerializedFile, SliceInfo, StopReason};
+pub use serializer::Serializer;
+pub use stream::{HolePolicy, MediaStream};
+pub use trailer::{HeaderSlice, Trailer};
+pub use validate::{Overlap, OverlapPolicy, Validation, MAX_OVERLAPS};
+pub use write_at::{SeekWriter, WriteAt};
+
+pub type Res<T> = Result<T, Error>;
+
+/// Size of a part, as read by Telegram Desktop's streaming loader (see [`ParserConfig`]).
+pub const PART_SIZE: u32 = 128 * 1024;
+
+/// Size of a slice (64 parts), the unit in which streamed media is stored in the cache (see
+/// [`ParserConfig`]).
+pub const SLICE_SIZE: u64 = 64 * PART_SIZE as u64;
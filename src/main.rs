@@ -17,285 +17,492 @@
     along with this program.  If not, see <http://www.gnu.org/licenses/>.
 */
 
-/// Telegram Desktop's cached `media_cache` can be decrypted using a python script available here:
-/// https://github.com/lilydjwg/telegram-cache-decryption
-///
-/// You may notice than not all decrypted media files are playable, and there are no files
-/// that are larger than 10MiB.
-///
-/// Telegram Desktop (as of Dec 2022) seem to split larger media files into multiple cache
-/// files, the first of which is serialized for streaming purposes. Other cache files may
-/// not exist if the media is not fully cached.
-///
-/// Serialization is simple, the serialized cache file contains one or more *slices*, each
-/// slice is split into multiple *parts*.
-///
-/// A *slice* header is simply 4 bytes indicating the number of parts in it.
-///
-/// A *part* header is simply 8 bytes, with the first four indicating the deserialized media
-/// stream offset, followed by four bytes indicating the part byte size.
-///
-/// Note that parts are not necessarily contiguous, or ordered over multiple slices. The reader
-/// side of this serialized cache file emulates a media player, so if an MP4 file has a moov atom
-/// necessary for playback at the end of the media file, the reader will seek to the end and read
-/// from there, then come back (in the next slice).
-/// 
-/// The next split cache files are not serialized, and can simply be appended. **But** it should be
-/// noted that parts written with a forward seek (as described above) leaving a hole in
-/// the deserialized stream should be discarded. In-order data written to the deserialized file
-/// wouldn't exceed 8MiB (Check 'Last contiguous offset' value in program output).
-///
-/// Final note, there are a few bytes left after the parsed slices in the serialized file. I don't
-/// know what they are. But simply discarding them worked for me.
-/// 
 
 use std::env;
-use std::path::PathBuf;
-use std::fs::{File, Metadata, OpenOptions};
-use std::io::{Read, Write, Seek, SeekFrom};
-
-type Res<T> = Result<T, String>;
-
-#[derive(Debug)]
-struct DeserializedFile {
-    name: String,
-    file: File,
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, Write};
+use std::path::Path;
+use std::process::ExitCode;
+
+use telegram_media_deserialize::{Error, Res, SerializedFile, DeserializedFile, EncryptedFile, EncryptionKey, RawSlice};
+use telegram_media_deserialize::{Coverage, GapPolicy, HolePolicy, MediaSize, MediaStream, Mp4Check, OrderedPartInfos, OverlapPolicy, ParserConfig, RawSliceReport, Report, Validation};
+use telegram_media_deserialize::binlog::Binlog;
+use telegram_media_deserialize::mapfile::{write_mapfile, MissingStatus};
+use telegram_media_deserialize::tdata::KeyData;
+
+const USAGE: &str = "\
+Usage: telegram-media-deserialize [options] <serialized_file> <deserialized_file|->
+       telegram-media-deserialize <key options> --list-cache <media_cache_dir>
+
+Use - as <deserialized_file> to write to stdout.
+
+Options:
+  --key-file <file>   decrypt <serialized_file> as a media_cache file, using
+                      the raw 256 bytes local key stored in <file>
+  --tdata <dir>       decrypt <serialized_file> as a media_cache file, using
+                      the local key read from <dir>/key_datas
+  --key-name <name>   read <dir>/key_<name>s instead (default: data)
+  --passcode <pass>   local passcode to decrypt the key with, if one is set
+  --slice <file>      follow-up raw slice file, holding the slice after the
+                      previous one (starting with slice 1, may be repeated)
+  --slice-at <index> <file>
+                      follow-up raw slice file, holding slice <index>
+  --slice-size <size> slice size in bytes (default: 8388608), raw slices
+                      are written at <index> * <size>
+  --max-parts <count> maximum part count in a slice header (default: 80)
+  --max-part-size <size>
+                      maximum part size in bytes (default: 131072)
+  --part-size <size>  size in bytes of the parts read by Telegram Desktop
+                      (default: 131072), used by --resync
+  --media-cache <dir> find the follow-up raw slice files of <serialized_file>
+                      in the media_cache database in <dir> (unless given with
+                      --slice or --slice-at), and cut the decrypted files to
+                      their size in it (requires --key-file or --tdata)
+  --resync            if parsing stops at a corrupt header, scan the rest of
+                      <serialized_file> for plausible slice and part headers
+  --resync-min-confidence <confidence>
+                      minimum confidence (0 to 1, default: 0.5) of recovered
+                      parts to write to the output
+  --mmap              map <serialized_file> into memory instead of reading it
+                      (can't be used with --key-file or --tdata)
+  --sparse            preallocate covered ranges of <deserialized_file>, leaving
+                      missing ranges as holes, and check the holes afterwards
+                      (Linux only)
+  --overlaps <policy> copy of overlapping parts to write, the one later in
+                      <serialized_file> (last, the default) or the first one
+  --gaps <policy>     when writing to stdout, pad gaps with zeros (zero, the
+                      default) or stop at the first gap (cut)
+  --report <format>   report format, text (default, to stderr) or json
+  --report-file <file>
+                      write the report to <file> (default: stdout for json,
+                      required if writing the output to stdout)
+  --mapfile <file>    write a GNU ddrescue mapfile of the output to <file>
+  --mapfile-missing <status>
+                      ddrescue status of missing ranges, non-tried (default)
+                      or bad
+  --list-cache <dir>  list the entries of the media_cache database in <dir>
+                      (requires --key-file or --tdata)";
+const USAGE_EXIT_CODE: u8 = 2;
+const DEFAULT_RESYNC_MIN_CONFIDENCE: f64 = 0.5;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+enum ReportFormat {
+    #[default]
+    Text,
+    Json,
 }
 
-impl DeserializedFile {
-    fn from_name(name: String) -> Res<Self> {
-        let path  = PathBuf::from(name.clone());
-
-        (!path.exists())
-            .then_some(())
-            .ok_or_else(|| format!("'{name}' already exists"))?;
-
-
-        let file = OpenOptions::new()
-            .create_new(true)
-            .write(true)
-            .open(path)
-            .map_err(|e| format!("failed to create '{name}' for writing: {e}"))?;
+#[derive(Debug, Default)]
+struct Args {
+    key_file: Option<String>,
+    tdata: Option<String>,
+    key_name: Option<String>,
+    passcode: Option<String>,
+    list_cache: Option<String>,
+    slices: Vec<(u64, String)>,
+    slice_size: Option<u64>,
+    max_parts: Option<u32>,
+    max_part_size: Option<u32>,
+    part_size: Option<u32>,
+    report: ReportFormat,
+    report_file: Option<String>,
+    mapfile: Option<String>,
+    gaps: GapPolicy,
+    overlaps: OverlapPolicy,
+    mmap: bool,
+    resync: bool,
+    resync_min_confidence: Option<f64>,
+    sparse: bool,
+    mapfile_missing: MissingStatus,
+    media_cache: Option<String>,
+    serialized_file: String,
+    deserialized_file: String,
+}
 
-        Ok(Self {name, file})
+impl Args {
+    fn parse(mut args: impl Iterator<Item=String>) -> Option<Self> {
+        let mut ret = Self::default();
+        let mut positional = Vec::with_capacity(2);
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--key-file" => ret.key_file = Some(args.next()?),
+                "--tdata" => ret.tdata = Some(args.next()?),
+                "--key-name" => ret.key_name = Some(args.next()?),
+                "--passcode" => ret.passcode = Some(args.next()?),
+                "--list-cache" => ret.list_cache = Some(args.next()?),
+                "--slice" => {
+                    let index = ret.slices.last().map(|(i, _)| i + 1).unwrap_or(1);
+                    ret.slices.push((index, args.next()?));
+                },
+                "--slice-at" => {
+                    let index = args.next()?.parse().ok()?;
+                    ret.slices.push((index, args.next()?));
+                },
+                "--slice-size" => ret.slice_size = Some(args.next()?.parse().ok().filter(|&s| s > 0)?),
+                "--max-parts" => ret.max_parts = Some(args.next()?.parse().ok().filter(|&n| n > 0)?),
+                "--max-part-size" => ret.max_part_size = Some(args.next()?.parse().ok().filter(|&s| s > 0)?),
+                "--part-size" => ret.part_size = Some(args.next()?.parse().ok().filter(|&s| s > 0)?),
+                "--media-cache" => ret.media_cache = Some(args.next()?),
+                "--report" => ret.report = match args.next()?.as_str() {
+                    "text" => ReportFormat::Text,
+                    "json" => ReportFormat::Json,
+                    _ => return None,
+                },
+                "--report-file" => ret.report_file = Some(args.next()?),
+                "--mapfile" => ret.mapfile = Some(args.next()?),
+                "--mmap" => ret.mmap = true,
+                "--resync" => ret.resync = true,
+                "--resync-min-confidence" => ret.resync_min_confidence =
+                    Some(args.next()?.parse().ok().filter(|c| (0.0..=1.0).contains(c))?),
+                "--sparse" => ret.sparse = true,
+                "--gaps" => ret.gaps = match args.next()?.as_str() {
+                    "zero" => GapPolicy::Zero,
+                    "cut" => GapPolicy::Cut,
+                    _ => return None,
+                },
+                "--overlaps" => ret.overlaps = match args.next()?.as_str() {
+                    "last" => OverlapPolicy::Last,
+                    "first" => OverlapPolicy::First,
+                    _ => return None,
+                },
+                "--mapfile-missing" => ret.mapfile_missing = match args.next()?.as_str() {
+                    "non-tried" => MissingStatus::NonTried,
+                    "bad" => MissingStatus::Bad,
+                    _ => return None,
+                },
+                opt if opt.starts_with("--") => return None,
+                _ => positional.push(arg),
+            }
+        }
+        let tdata_only = ret.key_name.is_some() || ret.passcode.is_some();
+        if (ret.key_file.is_some() && ret.tdata.is_some()) || (tdata_only && ret.tdata.is_none()) {
+            return None;
+        }
+        let has_key = ret.key_file.is_some() || ret.tdata.is_some();
+        if ret.list_cache.is_some() {
+            return (positional.is_empty() && has_key).then_some(ret);
+        }
+        if (ret.media_cache.is_some() && !has_key) || (ret.mmap && has_key)
+            || (ret.resync_min_confidence.is_some() && !ret.resync) {
+            return None;
+        }
+        let [serialized_file, deserialized_file] = <[String; 2]>::try_from(positional).ok()?;
+        let stdout_json_report = ret.report == ReportFormat::Json && ret.report_file.is_none();
+        if deserialized_file == "-" && (stdout_json_report || ret.sparse) {
+            return None;
+        }
+        ret.serialized_file = serialized_file;
+        ret.deserialized_file = deserialized_file;
+        Some(ret)
     }
 
-    fn _seek_from_start(&mut self, offset: u64) -> Res<u64> {
-        self.file.seek(SeekFrom::Start(offset))
-            .map_err(|e| format!("failed to seek '{}' at offset={offset}: {e}", self.name))
+    /// The default parser limits, with the options overriding them.
+    fn config(&self) -> ParserConfig {
+        let default = ParserConfig::default();
+        ParserConfig {
+            max_parts_count: self.max_parts.unwrap_or(default.max_parts_count),
+            max_part_size: self.max_part_size.unwrap_or(default.max_part_size),
+            part_size: self.part_size.unwrap_or(default.part_size),
+            slice_size: self.slice_size.unwrap_or(default.slice_size),
+            ..default
+        }
     }
 }
 
-#[derive(Debug)]
-struct PartInfo {
-    in_offset: u64,
-    out_offset: u32,
-    part_size: u32,
+fn write_report(report: &Report, args: &Args) -> Res<()> {
+    let mut report_file: Box<dyn Write> = match &args.report_file {
+        Some(name) => Box::new(File::create(name)
+            .map_err(|e| Error::io("creating", name, None, e))?),
+        None => Box::new(io::stdout().lock()),
+    };
+    let report_name = args.report_file.as_deref().unwrap_or("stdout");
+    report.write_json(&mut report_file)
+        .and_then(|_| report_file.flush())
+        .map_err(|e| Error::io("writing report to", report_name, None, e))
 }
 
-struct OrderedPartInfos(Vec<PartInfo>);
-
+fn extract<R, S>(mut serialized_file: SerializedFile<R>, mut slices: Vec<RawSlice<S>>, args: &Args) -> Res<()>
+where
+    R: Read + Seek,
+    S: Read + Seek,
+{
+    let config = args.config();
+    let slice_size = config.slice_size;
+    let text_report = args.report == ReportFormat::Text;
+
+    serialized_file.set_config(config);
+    serialized_file.set_verbose(text_report);
+    serialized_file.set_resync(args.resync);
+    let min_confidence = args.resync_min_confidence.unwrap_or(DEFAULT_RESYNC_MIN_CONFIDENCE);
+    slices.iter_mut().for_each(|slice| slice.truncate(slice_size));
+
+    if args.deserialized_file == "-" {
+        let (parsed, validation) = parse(&mut serialized_file, min_confidence)?;
+        let ordered_info = parsed.with_overlap_policy(args.overlaps);
+        let mut stdout = io::stdout().lock();
+        let written = serialized_file.write_stream_to(&ordered_info, &mut slices, slice_size, args.gaps, &mut stdout)?;
+        // e.g. with --gaps cut, the parts after the first gap weren't written
+        let coverage = written_coverage(&coverage(&ordered_info, &slices, slice_size), written);
+        // the container headers are only read from the parts
+        let name = serialized_file.name().to_owned();
+        let parts_coverage = ordered_info.coverage();
+        let mut stream = MediaStream::from_info(serialized_file, ordered_info.clone(), HolePolicy::Zero);
+        let media_size = media_size(&parsed, &parts_coverage, &slices, slice_size, &mut stream, &name)?;
+        let coverage = media_coverage(&coverage, &media_size);
+        let mp4 = Mp4Check::walk(&mut stream, &parts_coverage, &coverage)
+            .map_err(|e| Error::io("reading MP4 boxes from", &name, None, e))?;
+        return report(&name, &ordered_info, &slices, &coverage, media_size, mp4, &validation, args);
+    }
 
-#[derive(Debug)]
-struct SerializedFile {
-    name: String,
-    metadata: Metadata,
-    file: File,
-    rd_buf: [u8; 4096],
-    b4_buf: [u8; 4],
+    let (parsed, validation) = parse(&mut serialized_file, min_confidence)?;
+    let ordered_info = parsed.with_overlap_policy(args.overlaps);
+    let coverage = coverage(&ordered_info, &slices, slice_size);
+
+    // the output is only created once the input is parsed, and removed if writing it fails
+    let mut deserialized_file = DeserializedFile::from_name(args.deserialized_file.clone())?;
+    // like stdout, the output ends with the last covered byte, the missing bytes after it (e.g.
+    // of a truncated final part) are only reported
+    let written = coverage.covered_end();
+    let mut write = |deserialized_file: &mut DeserializedFile| {
+        if args.sparse {
+            deserialized_file.preallocate(&coverage)?;
+        }
+        serialized_file.write_with_slices_to(&ordered_info, &mut slices, slice_size, deserialized_file)?;
+        deserialized_file.set_len(written)
+    };
+    if let Err(e) = write(&mut deserialized_file) {
+        drop(deserialized_file);
+        let _ = fs::remove_file(&args.deserialized_file);
+        return Err(e);
+    }
+    // a mismatch is returned after the report
+    let holes = match args.sparse {
+        true => check_holes(&mut deserialized_file, &Coverage::new(coverage.covered().to_vec(), Some(written)), text_report),
+        false => Ok(()),
+    };
+    let mut output = File::open(&args.deserialized_file)
+        .map_err(|e| Error::io("opening for read", &args.deserialized_file, None, e))?;
+    let media_size = media_size(&parsed, &coverage, &slices, slice_size, &mut output, &args.deserialized_file)?;
+    let coverage = media_coverage(&coverage, &media_size);
+    let mp4 = Mp4Check::walk(&mut output, &coverage, &coverage)
+        .map_err(|e| Error::io("reading MP4 boxes from", &args.deserialized_file, None, e))?;
+    report(serialized_file.name(), &ordered_info, &slices, &coverage, media_size, mp4, &validation, args)?;
+    holes
 }
 
-impl SerializedFile {
-    fn from_name(name: String) -> Res<Self> {
-        let path  = PathBuf::from(name.clone());
-        path.exists()
-            .then_some(())
-            .ok_or_else(|| format!("'{name}' not accessible or does not exist"))?;
-
-        let file = OpenOptions::new()
-            .read(true)
-            .open(path)
-            .map_err(|e| format!("failed to open '{name}' for read: {e}"))?;
-
-        let metadata = file.metadata()
-            .map_err(|e| format!("failed to get metadata for '{name}': {e}"))?;
-
-        let rd_buf = [0; 4096];
-        let b4_buf = [0; 4];
+/// Parses `serialized_file` (with the recovered parts of at least `min_confidence`), and checks
+/// the parts. Overlaps are left to resolve with `--overlaps`, the media size is inferred from
+/// the whole parts.
+fn parse<R: Read + Seek>(serialized_file: &mut SerializedFile<R>, min_confidence: f64)
+    -> Res<(OrderedPartInfos, Validation)> {
+    let ordered_info = serialized_file.get_info()?.with_recovered(min_confidence);
+    let validation = serialized_file.validate(&ordered_info)?;
+    Ok((ordered_info, validation))
+}
 
-        Ok(Self {name, metadata, file, rd_buf, b4_buf})
-    }
+/// Infers the media size, reading the container headers covered by `stream_coverage` from
+/// `stream` (named `name`).
+fn media_size<R, S>(ordered_info: &OrderedPartInfos, stream_coverage: &Coverage, slices: &[RawSlice<S>],
+    slice_size: u64, stream: &mut R, name: &str) -> Res<MediaSize>
+where
+    R: Read + Seek,
+{
+    MediaSize::infer_with_slices(ordered_info, slices, slice_size, stream_coverage, stream)
+        .map_err(|e| Error::io("reading container headers from", name, None, e))
+}
 
-    fn _seek_from_start(&mut self, offset: u64) -> Res<u64> {
-        self.file.seek(SeekFrom::Start(offset))
-            .map_err(|e| format!("failed to seek '{}' to offset={offset}: {e}", self.name))
+/// `coverage`, extended to the media size if known.
+fn media_coverage(coverage: &Coverage, media_size: &MediaSize) -> Coverage {
+    let mut coverage = coverage.clone();
+    if let Some(size) = media_size.size() {
+        coverage.set_total_len(size);
     }
+    coverage
+}
 
-    fn _seek_from_curr(&mut self, offset: i64) -> Res<u64> {
-        self.file.seek(SeekFrom::Current(offset))
-            .map_err(|e| format!("failed to seek '{}' from current position with offset={offset}: {e}", self.name))
-    }
+/// `coverage`, with only the bytes before `written` (the output length) covered.
+fn written_coverage(coverage: &Coverage, written: u64) -> Coverage {
+    let covered = coverage.covered().iter().map(|r| r.start..r.end.min(written));
+    Coverage::new(covered, Some(coverage.total_len()))
+}
 
-    fn _get_pos(&mut self) -> Res<u64> {
-        self.file.stream_position()
-            .map_err(|e| format!("getting stream position of '{}' failed: {e}", self.name))
+fn coverage<S>(ordered_info: &OrderedPartInfos, slices: &[RawSlice<S>], slice_size: u64) -> Coverage {
+    let mut coverage = ordered_info.coverage();
+    for slice in slices {
+        coverage.insert(slice.out_range(slice_size));
     }
+    coverage
+}
 
-    fn _read_u32_le(&mut self) -> Res<u32> {
-        self.file.read_exact(&mut self.b4_buf)
-            .map_err(|e| format!("reading 4 bytes from '{}' failed: {e}", self.name))?;
+/// Checks that the holes of the (sparse) output match the missing ranges.
+fn check_holes(deserialized_file: &mut DeserializedFile, coverage: &Coverage, verbose: bool) -> Res<()> {
+    let holes = deserialized_file.check_holes(coverage)?;
+    verbose.then(|| eprintln!("Sparse output: {holes} hole(s), matching missing ranges"));
+    Ok(())
+}
 
-        Ok(u32::from_le_bytes(self.b4_buf))
+#[allow(clippy::too_many_arguments)]
+fn report<S>(input: &str, ordered_info: &OrderedPartInfos, slices: &[RawSlice<S>], coverage: &Coverage,
+    media_size: MediaSize, mp4: Option<Mp4Check>, validation: &Validation, args: &Args) -> Res<()> {
+    let slice_size = args.config().slice_size;
+    let text_report = args.report == ReportFormat::Text;
+
+    if let Some(mapfile) = &args.mapfile {
+        let mut file = File::create(mapfile)
+            .map_err(|e| Error::io("creating", mapfile, None, e))?;
+        write_mapfile(coverage, args.mapfile_missing, &mut file)
+            .map_err(|e| Error::io("writing mapfile to", mapfile, None, e))?;
     }
 
-    fn read_part(&mut self, part_size: u32) -> Res<Vec<u8>> {
-        let part_size = usize::try_from(part_size)
-            .map_err(|_| format!("failed to convert {part_size}u64 to a usize value"))?;
-        let mut part_buf = Vec::with_capacity(part_size);
-        'rd: loop {
-            match self.file.read(&mut self.rd_buf) {
-                Ok(n) => {
-                    let n2 = n.min(part_size - part_buf.len());
-                    part_buf.extend_from_slice(&self.rd_buf[0..n2]);
-                    //eprintln!("read {n} bytes, save {n2} bytes, part_buf len={}", part_buf.len());
-                    if part_buf.len() == part_size {
-                        break 'rd;
-                    }
-                },
-                Err(e) => {
-                    let total_read = part_buf.len();
-                    (total_read == part_size)
-                        .then_some(())
-                        .ok_or_else(|| format!("failed to read part of size {part_size} from {}, \
-                                only {total_read} bytes read: {e}", self.name))?;
-                    break 'rd;
-                }
+    if text_report {
+        if !validation.is_clean() {
+            eprint!("\n{validation}");
+            let conflicts = validation.conflicts().count();
+            if conflicts > 0 {
+                eprintln!("{conflicts} conflicting overlap(s), using the {} copy", args.overlaps.name());
             }
         }
-        assert_eq!(part_buf.len(), part_size);
-        Ok(part_buf)
-    }
-
-    fn order_and_report_info(mut info: Vec<PartInfo>) -> OrderedPartInfos {
-        info.sort_by_key(|pi| pi.out_offset);
-
-        match info.len() {
-            0 | 1 => (),
-            len => { 
-                let mut last_contigous_i = 0;
-                'contig: for i in 1..len {
-                    let prev = &info[i-1];
-                    let curr = &info[i];
-                    if curr.out_offset == prev.out_offset + prev.part_size {
-                        last_contigous_i = i;
-                    } else {
-                        break 'contig;
-                    }
-                }
-                // report
-                let first_part = &info[0];
-                let last_part = &info[len-1];
-                let last_contiguous = &info[last_contigous_i];
-                let last_contiguous_offset = last_contiguous.out_offset + last_contiguous.part_size;
-                let last_contiguous_offset_kib = (last_contiguous_offset as f64) / 1024.0;
-                let last_contiguous_offset_mib = last_contiguous_offset_kib / 1024.0;
-                let discontinuity_len = last_part.out_offset - last_contiguous_offset;
-                eprintln!("\n=======\nAfter ordering part info by out_offset:\n \
-                            First part: {first_part:?}\n \
-                            Last contiguous: {last_contiguous:?}\n \
-                            Last contiguous offset: {last_contiguous_offset} bytes \
-                            ({last_contiguous_offset_kib:.4}KiB/\
-                            {last_contiguous_offset_mib:.4}MiB) \
-                            (Discontinuity: {discontinuity_len} bytes)\n \
-                            Last part: {last_part:?}\n=======");
-            },
+        eprintln!("\n{media_size}");
+        match media_size.size() {
+            Some(size) => eprintln!("{} of {size} bytes present", coverage.covered_bytes()),
+            None => eprintln!("{} bytes present", coverage.covered_bytes()),
         }
-
-        OrderedPartInfos(info)
+        eprintln!("\n{coverage}");
+        if let Some(mp4) = &mp4 {
+            eprintln!("{mp4}");
+        }
+        return Ok(());
     }
 
-    fn get_info(&mut self) -> Res<OrderedPartInfos> {
-        const MAX_PARTS_COUNT: u32 = 80;
-        const MAX_PART_SIZE: u32 = 128 * 1024;
-
-        let mut ret_vec = Vec::with_capacity(128);
-
-        let _ = self._seek_from_start(0)?;
-
-        let mut slice_i = 0;
-        let mut in_offset = 0;
-        // TODO: loop limit in-case a bad file is encountered
-        'out: while in_offset < self.metadata.len() {
-            let parts_res = self._read_u32_le();
-
-            if parts_res.is_err() {
-                eprintln!("reached EOF, will stop parsing..");
-                break 'out;
-            }
-
-            let parts = parts_res?;
-
-            if parts == 0 || parts > MAX_PARTS_COUNT {
-                eprintln!("Slice{slice_i}: in_offset={in_offset}, \
-                    parsed parts={parts} is zero or > max allowed({MAX_PARTS_COUNT}), will stop parsing..");
-                eprintln!("in_offset={in_offset}, stopped parsing with {} bytes remaining in file.", self.metadata.len() - in_offset);
-                break 'out;
-            }
-            eprintln!("Slice{slice_i}: in_offset={in_offset}, parts={parts}");
-
-            let mut read_parts = 0;
-
-            while read_parts < parts {
-                in_offset = self._get_pos()?;
-
-                let out_offset = self._read_u32_le()?;
-                let part_size = self._read_u32_le()?;
-
-                if part_size == 0 || part_size > MAX_PART_SIZE {
-                    eprintln!("Slice{slice_i}/Part{read_parts}: in_offset={in_offset}, \
-                        part_size={part_size} is zero or > max_allowed({MAX_PART_SIZE}), will stop parsing..");
-                    eprintln!("in_offset={in_offset}, stopped parsing with {} bytes remaining in file.", self.metadata.len() - in_offset);
-                    break 'out;
-                }
-
-                in_offset = self._get_pos()?;
-                eprintln!("Slice{slice_i}/Part{read_parts}: in_offset={in_offset}, out_offset={out_offset}, part_size={part_size}");
-                ret_vec.push(PartInfo{in_offset, out_offset, part_size});
+    let raw_slices = slices.iter()
+        .map(|slice| RawSliceReport {
+            name: slice.name().into(),
+            index: slice.index(),
+            out_offset: slice.out_offset(slice_size),
+            len: slice.len(),
+        })
+        .collect();
+    let report = Report {
+        input,
+        ordered_info,
+        raw_slices,
+        coverage,
+        media_size,
+        mp4,
+        validation,
+        overlap_policy: args.overlaps,
+    };
+    write_report(&report, args)
+}
 
-                in_offset = self._seek_from_curr(part_size as i64)?;
-                read_parts += 1;
-            }
-            slice_i += 1;
-        }
-        Ok(Self::order_and_report_info(ret_vec))
+/// Opens the encrypted file named `name`, cut to its size in the cache database if it's found
+/// there, to drop the encryption padding.
+fn open_encrypted(name: &str, key: &EncryptionKey, binlog: Option<&Binlog>) -> Res<EncryptedFile> {
+    let mut encrypted_file = EncryptedFile::from_name(name.to_owned(), key)?;
+    match binlog.map(|binlog| binlog.find_by_path(Path::new(name))) {
+        Some(Some(entry)) => encrypted_file.truncate(entry.size.into()),
+        Some(None) => eprintln!("'{name}' not found in the cache database, its size may include encryption padding"),
+        None => (),
     }
+    Ok(encrypted_file)
+}
 
-    fn write_to_deserialized_file(&mut self, mut deserialized_file: DeserializedFile) -> Res<()> {
-            let ordered_info = self.get_info()?;
-        for PartInfo{in_offset, out_offset, part_size} in ordered_info.0 {
-            let _ = self._seek_from_start(in_offset)?;
-            let part_bytes = self.read_part(part_size)?;
-            let _ = deserialized_file._seek_from_start(out_offset.into())?;
-            eprintln!("writing {part_size} from {}@{in_offset} to {}@{out_offset}", self.name, deserialized_file.name);
-            deserialized_file.file.write_all(&part_bytes)
-                .map_err(|e| format!("failed to write part(size={part_size}) to {}@{out_offset}: {e}", self.name))?;
+/// Opens the follow-up raw slices of `serialized_file`, as found in the cache database, cut to
+/// their size in it.
+fn find_slices(binlog: &Binlog, serialized_file: &str, key: &EncryptionKey) -> Res<Vec<RawSlice<EncryptedFile>>> {
+    const MAX_SLICES: u64 = 1024;
+
+    let Some(first) = binlog.find_by_path(Path::new(serialized_file)) else {
+        eprintln!("'{serialized_file}' not found in the cache database, no slices will be added");
+        return Ok(Vec::new());
+    };
+
+    let mut slices = Vec::new();
+    let mut expected_index = 1;
+    for (index, entry) in binlog.slices(first.key, MAX_SLICES).into_iter().skip(1) {
+        if index != expected_index {
+            eprintln!("slices {expected_index}..{index} of {} are missing from the cache database, \
+                they will be left as holes", first.key);
         }
-        Ok(())
+        eprintln!("found slice {index} of {}: {} ({} bytes)", first.key, entry.path.display(), entry.size);
+        let name = entry.path.to_string_lossy().into_owned();
+        let mut encrypted_file = EncryptedFile::from_name(name.clone(), key)?;
+        encrypted_file.truncate(entry.size.into());
+        slices.push(RawSlice::from_reader(name, encrypted_file, index)?);
+        expected_index = index + 1;
     }
+    Ok(slices)
 }
 
-fn main() -> Res<()> {
-    const USAGE: &str = "Usage: telegram-media-deserialize <serialized_file> <deserialized_file>";
-    let mut args = env::args();
-
-    let _exec = args.next().expect(USAGE);
-    let serialized_file = args.next().expect(USAGE);
-    let deserialized_file = args.next().expect(USAGE);
+fn read_key(args: &Args) -> Res<Option<EncryptionKey>> {
+    if let Some(key_file) = &args.key_file {
+        return EncryptionKey::from_name(key_file).map(Some);
+    }
+    let Some(tdata) = &args.tdata else {
+        return Ok(None);
+    };
+    let key_name = args.key_name.as_deref().unwrap_or("data");
+    let key_data = KeyData::from_tdata(Path::new(tdata), key_name)?;
+    key_data.local_key(args.passcode.as_ref().map(String::as_bytes)).map(Some)
+}
 
-    args.next().is_none().then_some(()).expect(USAGE);
+fn list_cache(media_cache_dir: &str, key: &EncryptionKey) -> Res<()> {
+    let binlog = Binlog::from_media_cache(Path::new(media_cache_dir), key)?;
+    for entry in binlog.entries() {
+        let access_time = entry.access_time.map(|t| t.system.to_string()).unwrap_or("-".into());
+        println!("{} tag={} size={} access_time={access_time} {}",
+            entry.key, entry.tag, entry.size, entry.path.display());
+    }
+    Ok(())
+}
 
-    let mut serialized_file = SerializedFile::from_name(serialized_file)?;
-    let deserialized_file = DeserializedFile::from_name(deserialized_file)?;
+fn run(args: Args) -> Res<()> {
+    if let Some(media_cache_dir) = &args.list_cache {
+        let key = read_key(&args)?.expect("checked when parsing args");
+        return list_cache(media_cache_dir, &key);
+    }
+    match read_key(&args)? {
+        Some(key) => {
+            let binlog = args.media_cache.as_ref()
+                .map(|media_cache_dir| Binlog::from_media_cache(Path::new(media_cache_dir), &key))
+                .transpose()?;
+            let encrypted_file = open_encrypted(&args.serialized_file, &key, binlog.as_ref())?;
+            let padded = encrypted_file.is_padded();
+            let mut serialized_file = SerializedFile::from_reader(args.serialized_file.clone(), encrypted_file)?;
+            serialized_file.set_padded(padded);
+            let slices = match &binlog {
+                Some(binlog) if args.slices.is_empty() => find_slices(binlog, &args.serialized_file, &key)?,
+                _ => args.slices.iter()
+                    .map(|(index, name)| RawSlice::from_reader(name.clone(), open_encrypted(name, &key, binlog.as_ref())?, *index))
+                    .collect::<Res<_>>()?,
+            };
+            extract(serialized_file, slices, &args)
+        },
+        None => {
+            let serialized_file = if args.mmap {
+                SerializedFile::from_name_mmap(args.serialized_file.clone())?
+            } else {
+                SerializedFile::from_name(args.serialized_file.clone())?
+            };
+            let slices = args.slices.iter()
+                .map(|(index, name)| RawSlice::from_name(name.clone(), *index))
+                .collect::<Res<_>>()?;
+            extract(serialized_file, slices, &args)
+        },
+    }
+}
 
-    serialized_file.write_to_deserialized_file(deserialized_file)
+fn main() -> ExitCode {
+    let Some(args) = Args::parse(env::args().skip(1)) else {
+        eprintln!("{USAGE}");
+        return ExitCode::from(USAGE_EXIT_CODE);
+    };
+
+    match run(args) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("Error: {e}");
+            ExitCode::from(e.exit_code())
+        },
+    }
 }
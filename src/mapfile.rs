@@ -0,0 +1,70 @@
+/*
+    This file is a part of telegram-media-deserialize.
+
+    Copyright (C) 2022 Apple Sheeple <AppleSheeple at github>
+
+    telegram-media-deserialize is free software: you can
+    redistribute it and/or modify it under the terms of
+    the Affero GNU General Public License as published by
+    the Free Software Foundation.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+    Affero GNU General Public License for more details.
+
+    You should have received a copy of the Affero GNU General Public License
+    along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+
+//! Writing GNU ddrescue mapfiles.
+//!
+//! A mapfile starts with a status line (`current_pos current_status current_pass`), followed by
+//! one line per block (`pos size status`), positions and sizes in hex. Covered ranges are marked
+//! as finished (`+`), missing ones as non-tried (`?`) or bad (`-`).
+
+use std::io::{self, Write};
+
+use crate::Coverage;
+
+/// ddrescue status of missing ranges.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum MissingStatus {
+    /// `?`, for data that may still be recovered (e.g. from other cache files).
+    #[default]
+    NonTried,
+    /// `-`, for data known to be lost.
+    Bad,
+}
+
+impl MissingStatus {
+    fn as_char(self) -> char {
+        match self {
+            Self::NonTried => '?',
+            Self::Bad => '-',
+        }
+    }
+}
+
+/// Writes a ddrescue mapfile for `coverage` to `w`.
+pub fn write_mapfile<W: Write>(coverage: &Coverage, missing_status: MissingStatus, w: &mut W) -> io::Result<()> {
+    writeln!(w, "# Mapfile. Created by {} v{}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"))?;
+    writeln!(w, "# current_pos  current_status  current_pass")?;
+    writeln!(w, "0x00000000     +               1")?;
+    writeln!(w, "#      pos        size  status")?;
+
+    let covered = coverage.covered().iter()
+        .map(|r| (r.start, r.end.min(coverage.total_len()), '+'));
+    let missing = coverage.missing().into_iter()
+        .map(|r| (r.start, r.end, missing_status.as_char()));
+    let mut blocks = covered.chain(missing)
+        .filter(|(start, end, _)| start < end)
+        .collect::<Vec<_>>();
+    blocks.sort_by_key(|&(start, _, _)| start);
+
+    for (start, end, status) in blocks {
+        writeln!(w, "0x{start:08X}  0x{:08X}  {status}", end - start)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,171 @@
+/*
+    This file is a part of telegram-media-deserialize.
+
+    Copyright (C) 2022 Apple Sheeple <AppleSheeple at github>
+
+    telegram-media-deserialize is free software: you can
+    redistribute it and/or modify it under the terms of
+    the Affero GNU General Public License as published by
+    the Free Software Foundation.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+    Affero GNU General Public License for more details.
+
+    You should have received a copy of the Affero GNU General Public License
+    along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+
+//! The full size of the media, which isn't stored in the cache, inferred from the parts and
+//! cross-checked with the container headers.
+
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read, Seek};
+
+use crate::{Coverage, OrderedPartInfos, RawSlice};
+use crate::container::{read_element, read_mp4_box, EBML, SEGMENT};
+
+/// A media container format.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Container {
+    Mp4,
+    Matroska,
+}
+
+impl Container {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Mp4 => "mp4",
+            Self::Matroska => "matroska",
+        }
+    }
+}
+
+/// Size of the media according to its container headers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ContainerSize {
+    pub container: Container,
+    pub size: u64,
+}
+
+/// The full size of the media, as far as it can be known.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MediaSize {
+    /// Size inferred from the serialized file, i.e. the header slice metadata or a short final
+    /// part (see [`OrderedPartInfos::media_size`]).
+    pub inferred: Option<u64>,
+    /// Size from the container headers (see [`container_size`]).
+    pub container: Option<ContainerSize>,
+}
+
+impl MediaSize {
+    /// Infers the media size from `ordered_info`, and cross-checks it with the container headers
+    /// read from `stream` (the deserialized media stream, e.g. a [`MediaStream`](crate::MediaStream)),
+    /// where covered by `coverage`.
+    pub fn infer<R: Read + Seek>(ordered_info: &OrderedPartInfos, coverage: &Coverage, stream: &mut R) -> io::Result<Self> {
+        Self::infer_with_slices::<R, File>(ordered_info, &[], 0, coverage, stream)
+    }
+
+    /// Like [`infer`](Self::infer), with the follow-up raw `slices` written after the parts (see
+    /// [`SerializedFile::write_with_slices_to`](crate::SerializedFile::write_with_slices_to)).
+    /// `stream` may only hold the parts, as container headers are only read where covered by
+    /// `coverage`.
+    ///
+    /// Telegram Desktop fills every slice but the last one, so a raw slice shorter than
+    /// `slice_size` at the end of the data ends the media. A short final part before the end
+    /// of the data (i.e. of a raw slice) doesn't.
+    pub fn infer_with_slices<R, S>(ordered_info: &OrderedPartInfos, slices: &[RawSlice<S>], slice_size: u64,
+        coverage: &Coverage, stream: &mut R) -> io::Result<Self>
+    where
+        R: Read + Seek,
+    {
+        let data_end = slices.iter()
+            .map(|slice| slice.out_range(slice_size).end)
+            .fold(ordered_info.end_offset(), u64::max);
+        let short_final_slice = slices.iter()
+            .any(|slice| slice.len() < slice_size && slice.out_range(slice_size).end == data_end);
+        let inferred = match ordered_info.media_size() {
+            _ if short_final_slice => Some(data_end),
+            Some(size) if size < data_end => None,
+            inferred => inferred,
+        };
+        let container = container_size(stream, coverage)?;
+        Ok(Self{inferred, container})
+    }
+
+    /// The media size, as inferred from the parts, or else from the container headers.
+    pub fn size(&self) -> Option<u64> {
+        self.inferred.or(self.container.map(|cs| cs.size))
+    }
+
+    /// Whether the inferred and container sizes match, if both are known.
+    pub fn consistent(&self) -> Option<bool> {
+        Some(self.inferred? == self.container?.size)
+    }
+}
+
+impl fmt::Display for MediaSize {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (self.inferred, self.container) {
+            (None, None) => write!(f, "Media size: unknown"),
+            (Some(inferred), None) => write!(f, "Media size: {inferred} bytes (from the serialized file)"),
+            (None, Some(cs)) => write!(f, "Media size: {} bytes (from {} headers)", cs.size, cs.container.name()),
+            (Some(inferred), Some(cs)) if inferred == cs.size =>
+                write!(f, "Media size: {inferred} bytes (from the serialized file, matching {} headers)", cs.container.name()),
+            (Some(inferred), Some(cs)) =>
+                write!(f, "Media size: {inferred} bytes (from the serialized file, but {} headers say {} bytes)", cs.container.name(), cs.size),
+        }
+    }
+}
+
+/// Reads the media size from the container headers in `stream`, only reading the headers
+/// covered by `coverage`.
+///
+/// For MP4, top-level boxes are followed from the start of the stream, up to the first one
+/// ending at or after the covered data (so boxes after it that were never cached are missed).
+/// For Matroska, the size of the segment is used.
+pub fn container_size<R: Read + Seek>(stream: &mut R, coverage: &Coverage) -> io::Result<Option<ContainerSize>> {
+    if let Some(size) = mp4_size(stream, coverage)? {
+        return Ok(Some(ContainerSize{container: Container::Mp4, size}));
+    }
+    Ok(matroska_size(stream, coverage)?.map(|size| ContainerSize{container: Container::Matroska, size}))
+}
+
+fn mp4_size<R: Read + Seek>(stream: &mut R, coverage: &Coverage) -> io::Result<Option<u64>> {
+    let data_end = coverage.covered_end();
+    let mut offset = 0;
+    while offset < data_end {
+        if !coverage.contains_range(offset..offset.saturating_add(8)) {
+            return Ok(None);
+        }
+        let Some(mp4_box) = read_mp4_box(stream, offset, data_end)? else {
+            return Ok(None);
+        };
+        if (offset == 0 && &mp4_box.kind != b"ftyp") || !coverage.contains_range(offset..offset + mp4_box.header_len) {
+            return Ok(None);
+        }
+        // a box extending to the end of the stream has no size
+        let Some(size) = mp4_box.size else {
+            return Ok(None);
+        };
+        offset = offset.saturating_add(size);
+    }
+    Ok((offset > 0).then_some(offset))
+}
+
+fn matroska_size<R: Read + Seek>(stream: &mut R, coverage: &Coverage) -> io::Result<Option<u64>> {
+    let data_end = coverage.covered_end();
+    let Some(ebml) = read_element(stream, 0, data_end)?
+        .filter(|e| e.id == EBML && coverage.contains_range(0..e.data_offset())) else {
+        return Ok(None);
+    };
+    let Some(segment_offset) = ebml.end() else {
+        return Ok(None);
+    };
+    let segment = read_element(stream, segment_offset, data_end)?
+        .filter(|e| e.id == SEGMENT && coverage.contains_range(e.offset..e.data_offset()));
+    Ok(segment.and_then(|e| e.end()))
+}
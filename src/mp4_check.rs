@@ -0,0 +1,230 @@
+/*
+    This file is a part of telegram-media-deserialize.
+
+    Copyright (C) 2022 Apple Sheeple <AppleSheeple at github>
+
+    telegram-media-deserialize is free software: you can
+    redistribute it and/or modify it under the terms of
+    the Affero GNU General Public License as published by
+    the Free Software Foundation.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+    Affero GNU General Public License for more details.
+
+    You should have received a copy of the Affero GNU General Public License
+    along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+
+//! A check of the top-level MP4 (ISO-BMFF) boxes of the deserialized media stream, to tell
+//! whether it will play.
+
+use std::fmt;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::ops::Range;
+
+use crate::Coverage;
+use crate::container::read_mp4_box;
+
+/// Boxes a player needs: the file type, the metadata (sample tables), and the media data.
+pub const REQUIRED_BOXES: [[u8; 4]; 3] = [*b"ftyp", *b"moov", *b"mdat"];
+
+/// A top-level MP4 box, and how much of it is present.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TopLevelBox {
+    pub kind: [u8; 4],
+    pub offset: u64,
+    /// Box size including the header, `None` if the box extends to the end of the stream.
+    pub size: Option<u64>,
+    /// Offset right after the box (the end of the stream if it has no size).
+    pub end: u64,
+    /// Bytes of the box that are missing from the stream.
+    pub missing_bytes: u64,
+}
+
+impl TopLevelBox {
+    /// The box type, with bytes that aren't printable ASCII replaced by `?`.
+    pub fn kind_str(&self) -> String {
+        self.kind.iter()
+            .map(|&b| if b.is_ascii_graphic() || b == b' ' { char::from(b) } else { '?' })
+            .collect()
+    }
+
+    pub fn range(&self) -> Range<u64> {
+        self.offset..self.end
+    }
+
+    /// Whether all the bytes of the box are present.
+    pub fn is_covered(&self) -> bool {
+        self.missing_bytes == 0
+    }
+}
+
+/// Presence and completeness of a kind of top-level box.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BoxStatus {
+    /// No box of this kind was found (possibly after the walk stopped, see [`WalkEnd`]).
+    Missing,
+    /// Some bytes of a box of this kind are missing, or it extends past the end of the stream.
+    Incomplete,
+    /// All boxes of this kind are fully present.
+    Complete,
+}
+
+impl BoxStatus {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Missing => "missing",
+            Self::Incomplete => "incomplete",
+            Self::Complete => "complete",
+        }
+    }
+}
+
+/// Where the walk over the top-level boxes stopped.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum WalkEnd {
+    /// The last box ends at the end of the stream.
+    #[default]
+    End,
+    /// The last box ends past the end of the stream (at `end`), e.g. if the media is truncated.
+    PastEnd { end: u64 },
+    /// The box header at `offset` is missing from the stream, later boxes are unknown.
+    MissingHeader { offset: u64 },
+    /// The box header at `offset` is invalid, later boxes are unknown.
+    InvalidHeader { offset: u64 },
+}
+
+impl WalkEnd {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::End => "end",
+            Self::PastEnd{..} => "past_end",
+            Self::MissingHeader{..} => "missing_header",
+            Self::InvalidHeader{..} => "invalid_header",
+        }
+    }
+
+    /// Offset in the stream where the walk stopped.
+    pub fn offset(&self) -> Option<u64> {
+        match *self {
+            Self::End => None,
+            Self::PastEnd{end: offset} | Self::MissingHeader{offset} | Self::InvalidHeader{offset} => Some(offset),
+        }
+    }
+}
+
+/// The top-level boxes of an MP4 stream, as far as their headers are present.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Mp4Check {
+    /// Boxes in stream order.
+    pub boxes: Vec<TopLevelBox>,
+    pub end: WalkEnd,
+    /// Length of the stream the boxes were checked against.
+    pub len: u64,
+}
+
+impl Mp4Check {
+    /// Walks the top-level boxes of `stream` (the deserialized media stream, e.g. a
+    /// [`MediaStream`](crate::MediaStream) or the output file), up to `coverage.total_len()`.
+    ///
+    /// Box headers are only read where covered by `stream_coverage`, i.e. where `stream` holds
+    /// the data, while present bytes are counted with `coverage` (usually the same, but `stream`
+    /// may lack e.g. the raw slices). Boxes whose data is missing are still skipped over, so a
+    /// `moov` box after a partial `mdat` box is found.
+    ///
+    /// Returns `None` if the stream doesn't start with an `ftyp` box.
+    pub fn walk<R: Read + Seek>(stream: &mut R, stream_coverage: &Coverage, coverage: &Coverage) -> io::Result<Option<Self>> {
+        let len = coverage.total_len();
+        let mut check = Self{len, ..Self::default()};
+        let mut offset = 0;
+        while offset < len {
+            // the header is read within the covered range it starts in
+            let readable = stream_coverage.covered().iter().find(|r| r.contains(&offset));
+            let Some(readable) = readable.filter(|r| offset.saturating_add(8) <= r.end) else {
+                check.end = WalkEnd::MissingHeader{offset};
+                break;
+            };
+            let mp4_box = match read_mp4_box(stream, offset, readable.end)? {
+                Some(mp4_box) if check.boxes.is_empty() && &mp4_box.kind != b"ftyp" => return Ok(None),
+                Some(mp4_box) => mp4_box,
+                // a 64-bit size that is missing
+                None if offset.saturating_add(16) > readable.end && read_u32_be(stream, offset)? == 1 => {
+                    check.end = WalkEnd::MissingHeader{offset};
+                    break;
+                },
+                None => {
+                    check.end = WalkEnd::InvalidHeader{offset};
+                    break;
+                },
+            };
+            let end = mp4_box.range(len).end;
+            check.boxes.push(TopLevelBox {
+                kind: mp4_box.kind,
+                offset,
+                size: mp4_box.size,
+                end,
+                missing_bytes: (end - offset) - coverage.covered_bytes_in(offset..end),
+            });
+            if end > len {
+                check.end = WalkEnd::PastEnd{end};
+            }
+            offset = end;
+        }
+        Ok((!check.boxes.is_empty()).then_some(check))
+    }
+
+    /// Status of the boxes of type `kind`.
+    pub fn status(&self, kind: &[u8; 4]) -> BoxStatus {
+        let mut boxes = self.boxes.iter().filter(|b| &b.kind == kind).peekable();
+        if boxes.peek().is_none() {
+            return BoxStatus::Missing;
+        }
+        match boxes.all(|b| b.is_covered() && b.end <= self.len) {
+            true => BoxStatus::Complete,
+            false => BoxStatus::Incomplete,
+        }
+    }
+
+    /// Whether the [`REQUIRED_BOXES`] are complete, i.e. the media should play in full. Otherwise,
+    /// it may still play in part, e.g. with a complete `moov` box and the start of `mdat`.
+    pub fn playable(&self) -> bool {
+        REQUIRED_BOXES.iter().all(|kind| self.status(kind) == BoxStatus::Complete)
+    }
+}
+
+impl fmt::Display for Mp4Check {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "MP4 boxes:")?;
+        for b in &self.boxes {
+            let size = if b.size.is_none() { ", to the end" } else { "" };
+            match b.missing_bytes {
+                0 => writeln!(f, " {} at {}..{}{size}: present", b.kind_str(), b.offset, b.end)?,
+                missing => writeln!(f, " {} at {}..{}{size}: {missing} bytes missing", b.kind_str(), b.offset, b.end)?,
+            }
+        }
+        match self.end {
+            WalkEnd::End => (),
+            WalkEnd::PastEnd{end} => writeln!(f, " (the last box ends at {end}, past the end of the media at {})", self.len)?,
+            WalkEnd::MissingHeader{offset} => writeln!(f, " (box header at {offset} is missing, later boxes are unknown)")?,
+            WalkEnd::InvalidHeader{offset} => writeln!(f, " (box header at {offset} is invalid, later boxes are unknown)")?,
+        }
+        let statuses = REQUIRED_BOXES.iter()
+            .map(|kind| format!("{} {}", String::from_utf8_lossy(kind), self.status(kind).name()))
+            .collect::<Vec<_>>();
+        write!(f, "{}, ", statuses.join(", "))?;
+        match self.playable() {
+            true => write!(f, "should play"),
+            false => write!(f, "may not play (in full)"),
+        }
+    }
+}
+
+fn read_u32_be<R: Read + Seek>(r: &mut R, offset: u64) -> io::Result<u32> {
+    let mut buf = [0; 4];
+    r.seek(SeekFrom::Start(offset))?;
+    r.read_exact(&mut buf)?;
+    Ok(u32::from_be_bytes(buf))
+}
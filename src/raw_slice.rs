@@ -0,0 +1,130 @@
+/*
+    This file is a part of telegram-media-deserialize.
+
+    Copyright (C) 2022 Apple Sheeple <AppleSheeple at github>
+
+    telegram-media-deserialize is free software: you can
+    redistribute it and/or modify it under the terms of
+    the Affero GNU General Public License as published by
+    the Free Software Foundation.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+    Affero GNU General Public License for more details.
+
+    You should have received a copy of the Affero GNU General Public License
+    along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write, Seek, SeekFrom};
+use std::ops::Range;
+use std::path::PathBuf;
+
+use crate::{Error, Res, WriteAt};
+use crate::write_at::{copy_at, read_chunks, CopyError};
+
+/// A follow-up (not serialized) slice cache file, holding a contiguous range of
+/// the deserialized media stream.
+///
+/// A slice with index `i` starts at offset `i * slice_size` of the deserialized media stream.
+/// The serialized file itself is slice `0`.
+#[derive(Debug)]
+pub struct RawSlice<R = File> {
+    name: String,
+    file: R,
+    /// `file` as a plain [`File`], for in-kernel copies.
+    direct: Option<File>,
+    index: u64,
+    len: u64,
+}
+
+impl RawSlice {
+    /// Opens the existing file named `name`, holding slice `index`, for reading.
+    pub fn from_name(name: String, index: u64) -> Res<Self> {
+        let path  = PathBuf::from(name.clone());
+        path.exists()
+            .then_some(())
+            .ok_or_else(|| Error::InputMissing{path: path.clone()})?;
+
+        let file = OpenOptions::new()
+            .read(true)
+            .open(&path)
+            .map_err(|e| Error::io("opening for read", &path, None, e))?;
+
+        let direct = file.try_clone().ok();
+        Ok(Self{direct, ..Self::from_reader(name, file, index)?})
+    }
+}
+
+impl<R> RawSlice<R> {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn index(&self) -> u64 {
+        self.index
+    }
+
+    /// Offset of the slice in the deserialized media stream.
+    pub fn out_offset(&self, slice_size: u64) -> u64 {
+        self.index.saturating_mul(slice_size)
+    }
+
+    /// Range of the slice in the deserialized media stream.
+    pub fn out_range(&self, slice_size: u64) -> Range<u64> {
+        let start = self.out_offset(slice_size);
+        start..start.saturating_add(self.len)
+    }
+
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Only use the first `len` bytes of the slice, e.g. to drop the encryption padding
+    /// of a decrypted slice, using the size recorded in the cache database.
+    pub fn truncate(&mut self, len: u64) {
+        self.len = self.len.min(len);
+    }
+}
+
+impl<R: Read + Seek> RawSlice<R> {
+    /// Wraps an already opened raw slice stream, holding slice `index`.
+    /// `name` is only used in messages.
+    pub fn from_reader(name: String, mut file: R, index: u64) -> Res<Self> {
+        let len = file.seek(SeekFrom::End(0))
+            .map_err(|e| Error::io("getting length of", &name, None, e))?;
+
+        Ok(Self {name, file, direct: None, index, len})
+    }
+
+    /// Copies the slice to `out_offset` in `sink`, through `buf`.
+    pub(crate) fn copy_to<W: WriteAt>(&mut self, out_offset: u64, sink: &mut W, buf: &mut [u8]) -> Res<()> {
+        let copied = copy_at(&mut self.file, self.direct.as_ref(), &mut None, 0, self.len, sink, out_offset, buf);
+        self.check_copy(0, out_offset, copied)
+    }
+
+    /// Copies the slice, skipping its first `skip` bytes, to the current position of `sink`
+    /// (`out_offset` is only used in messages), through `buf`.
+    pub(crate) fn copy_range_to<W: Write>(&mut self, skip: u64, out_offset: u64, sink: &mut W, buf: &mut [u8]) -> Res<()> {
+        let len = self.len.saturating_sub(skip);
+        let copied = read_chunks(&mut self.file, &mut None, skip, len, buf, |chunk, _| sink.write_all(chunk));
+        self.check_copy(skip, out_offset, copied)
+    }
+
+    fn check_copy(&self, skip: u64, out_offset: u64, copied: Result<u64, CopyError>) -> Res<()> {
+        match copied {
+            Ok(copied) if skip + copied == self.len => Ok(()),
+            Ok(copied) => Err(Error::TruncatedSlice{path: self.name.clone().into(), len: self.len, read: skip + copied}),
+            Err(CopyError::Read{copied, source}) =>
+                Err(Error::io("reading slice from", &self.name, Some(skip + copied), source)),
+            Err(CopyError::Write{copied, source}) => Err(Error::sink("writing slice to", out_offset + copied, source)),
+        }
+    }
+}
@@ -0,0 +1,319 @@
+/*
+    This file is a part of telegram-media-deserialize.
+
+    Copyright (C) 2022 Apple Sheeple <AppleSheeple at github>
+
+    telegram-media-deserialize is free software: you can
+    redistribute it and/or modify it under the terms of
+    the Affero GNU General Public License as published by
+    the Free Software Foundation.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+    Affero GNU General Public License for more details.
+
+    You should have received a copy of the Affero GNU General Public License
+    along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+
+use std::fmt::{self, Write as _};
+use std::io::Write;
+
+use crate::{Coverage, HeaderSlice, MediaSize, Mp4Check, OrderedPartInfos, OverlapPolicy, PartInfo, StopReason, Trailer, Validation};
+
+/// A minimal JSON value, written by its [`Display`](fmt::Display) implementation.
+#[derive(Debug, Clone, PartialEq)]
+enum Json {
+    Null,
+    Bool(bool),
+    Num(u64),
+    Float(f64),
+    Str(String),
+    Arr(Vec<Json>),
+    Obj(Vec<(&'static str, Json)>),
+}
+
+impl From<bool> for Json {
+    fn from(v: bool) -> Self {
+        Self::Bool(v)
+    }
+}
+
+impl From<u64> for Json {
+    fn from(v: u64) -> Self {
+        Self::Num(v)
+    }
+}
+
+impl From<u32> for Json {
+    fn from(v: u32) -> Self {
+        Self::Num(v.into())
+    }
+}
+
+impl From<&str> for Json {
+    fn from(v: &str) -> Self {
+        Self::Str(v.into())
+    }
+}
+
+impl<T: Into<Json>> From<Option<T>> for Json {
+    fn from(v: Option<T>) -> Self {
+        v.map(Into::into).unwrap_or(Self::Null)
+    }
+}
+
+fn write_json_str(f: &mut fmt::Formatter<'_>, s: &str) -> fmt::Result {
+    f.write_char('"')?;
+    for c in s.chars() {
+        match c {
+            '"' => f.write_str("\\\"")?,
+            '\\' => f.write_str("\\\\")?,
+            '\n' => f.write_str("\\n")?,
+            '\r' => f.write_str("\\r")?,
+            '\t' => f.write_str("\\t")?,
+            c if u32::from(c) < 0x20 => write!(f, "\\u{:04x}", u32::from(c))?,
+            c => f.write_char(c)?,
+        }
+    }
+    f.write_char('"')
+}
+
+impl fmt::Display for Json {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Null => f.write_str("null"),
+            Self::Bool(v) => write!(f, "{v}"),
+            Self::Num(v) => write!(f, "{v}"),
+            Self::Float(v) if v.is_finite() => write!(f, "{v}"),
+            Self::Float(_) => f.write_str("null"),
+            Self::Str(s) => write_json_str(f, s),
+            Self::Arr(values) => {
+                f.write_char('[')?;
+                for (i, v) in values.iter().enumerate() {
+                    if i > 0 {
+                        f.write_char(',')?;
+                    }
+                    write!(f, "{v}")?;
+                }
+                f.write_char(']')
+            },
+            Self::Obj(fields) => {
+                f.write_char('{')?;
+                for (i, (k, v)) in fields.iter().enumerate() {
+                    if i > 0 {
+                        f.write_char(',')?;
+                    }
+                    write_json_str(f, k)?;
+                    write!(f, ":{v}")?;
+                }
+                f.write_char('}')
+            },
+        }
+    }
+}
+
+/// A raw slice, as written to the deserialized media stream.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RawSliceReport {
+    pub name: String,
+    pub index: u64,
+    pub out_offset: u64,
+    pub len: u64,
+}
+
+/// A structured report of a deserialization run.
+#[derive(Debug, Clone)]
+pub struct Report<'a> {
+    /// Name of the serialized file.
+    pub input: &'a str,
+    pub ordered_info: &'a OrderedPartInfos,
+    pub raw_slices: Vec<RawSliceReport>,
+    /// Coverage of the output, up to the media size if known.
+    pub coverage: &'a Coverage,
+    pub media_size: MediaSize,
+    /// Top-level boxes of the output, if it's an MP4 stream.
+    pub mp4: Option<Mp4Check>,
+    /// Suspicious parts, found before overlaps were resolved.
+    pub validation: &'a Validation,
+    /// How overlaps were resolved in the output.
+    pub overlap_policy: OverlapPolicy,
+}
+
+impl Report<'_> {
+    fn part_json(pi: &PartInfo) -> Json {
+        Json::Obj(vec![
+            ("in_offset", pi.in_offset.into()),
+            ("out_offset", pi.out_offset.into()),
+            ("part_size", pi.part_size.into()),
+        ])
+    }
+
+    fn stop_reason_json(stop_reason: StopReason) -> Json {
+        let (kind, value) = match stop_reason {
+            StopReason::EndOfFile => ("end_of_file", None),
+            StopReason::TruncatedSliceHeader{..} => ("truncated_slice_header", None),
+            StopReason::BadPartsCount{parts, ..} => ("bad_parts_count", Some(parts)),
+            StopReason::BadPartSize{part_size, ..} => ("bad_part_size", Some(part_size)),
+            StopReason::TruncatedPartHeader{..} => ("truncated_part_header", None),
+            StopReason::TruncatedPart{part_size, ..} => ("truncated_part", Some(part_size)),
+        };
+        Json::Obj(vec![
+            ("kind", kind.into()),
+            ("in_offset", stop_reason.in_offset().into()),
+            ("value", value.into()),
+        ])
+    }
+
+    fn trailer_json(trailer: &Trailer) -> Json {
+        let (kind, head) = match trailer {
+            Trailer::None => ("none", None),
+            Trailer::Padding{..} => ("padding", None),
+            Trailer::HeaderSlice(_) => ("header_slice", None),
+            Trailer::EmptySlice => ("empty_slice", None),
+            Trailer::Unknown{head, ..} => ("unknown", Some(head.iter().map(|b| format!("{b:02x}")).collect::<String>())),
+        };
+        Json::Obj(vec![
+            ("kind", kind.into()),
+            ("head", head.as_deref().into()),
+        ])
+    }
+
+    fn header_slice_json(header_slice: Option<HeaderSlice>) -> Json {
+        header_slice
+            .map(|hs| Json::Obj(vec![
+                ("full_in_cache", hs.full_in_cache.into()),
+                ("media_size", hs.media_size.into()),
+            ]))
+            .unwrap_or(Json::Null)
+    }
+
+    fn media_size_json(media_size: &MediaSize) -> Json {
+        let container = media_size.container
+            .map(|cs| Json::Obj(vec![
+                ("format", cs.container.name().into()),
+                ("size", cs.size.into()),
+            ]))
+            .unwrap_or(Json::Null);
+        Json::Obj(vec![
+            ("size", media_size.size().into()),
+            ("inferred", media_size.inferred.into()),
+            ("container", container),
+            ("consistent", media_size.consistent().into()),
+        ])
+    }
+
+    fn mp4_json(mp4: Option<&Mp4Check>) -> Json {
+        let Some(mp4) = mp4 else {
+            return Json::Null;
+        };
+        let boxes = mp4.boxes.iter()
+            .map(|b| Json::Obj(vec![
+                ("type", b.kind_str().as_str().into()),
+                ("offset", b.offset.into()),
+                ("size", b.size.into()),
+                ("end", b.end.into()),
+                ("missing_bytes", b.missing_bytes.into()),
+                ("covered", b.is_covered().into()),
+            ]))
+            .collect();
+        Json::Obj(vec![
+            ("boxes", Json::Arr(boxes)),
+            ("walk_end", Json::Obj(vec![
+                ("kind", mp4.end.name().into()),
+                ("offset", mp4.end.offset().into()),
+            ])),
+            ("ftyp", mp4.status(b"ftyp").name().into()),
+            ("moov", mp4.status(b"moov").name().into()),
+            ("mdat", mp4.status(b"mdat").name().into()),
+            ("playable", mp4.playable().into()),
+        ])
+    }
+
+    fn validation_json(validation: &Validation, overlap_policy: OverlapPolicy) -> Json {
+        let overlaps = validation.overlaps.iter()
+            .map(|o| Json::Obj(vec![
+                ("start", o.range.start.into()),
+                ("end", o.range.end.into()),
+                ("first", Self::part_json(&o.first)),
+                ("second", Self::part_json(&o.second)),
+                ("identical", o.identical.into()),
+            ]))
+            .collect();
+        Json::Obj(vec![
+            ("misaligned", Json::Arr(validation.misaligned.iter().map(Self::part_json).collect())),
+            ("overlaps", Json::Arr(overlaps)),
+            ("more_overlaps", validation.more_overlaps.into()),
+            ("conflicts", (validation.conflicts().count() as u64).into()),
+            ("overlap_policy", overlap_policy.name().into()),
+        ])
+    }
+
+    fn ranges_json(ranges: &[std::ops::Range<u64>]) -> Json {
+        Json::Arr(ranges.iter()
+            .map(|r| Json::Obj(vec![("start", r.start.into()), ("end", r.end.into())]))
+            .collect())
+    }
+
+    fn to_json(&self) -> Json {
+        let slices = self.ordered_info.slices().iter()
+            .enumerate()
+            .map(|(i, si)| Json::Obj(vec![
+                ("index", (i as u64).into()),
+                ("in_offset", si.in_offset.into()),
+                ("parts_count", si.parts_count.into()),
+                ("parts", Json::Arr(si.parts.iter().map(Self::part_json).collect())),
+            ]))
+            .collect();
+
+        let raw_slices = self.raw_slices.iter()
+            .map(|rs| Json::Obj(vec![
+                ("name", rs.name.as_str().into()),
+                ("index", rs.index.into()),
+                ("out_offset", rs.out_offset.into()),
+                ("len", rs.len.into()),
+            ]))
+            .collect();
+
+        let recovered_parts = self.ordered_info.recovered().iter()
+            .map(|rp| Json::Obj(vec![
+                ("in_offset", rp.part.in_offset.into()),
+                ("out_offset", rp.part.out_offset.into()),
+                ("part_size", rp.part.part_size.into()),
+                ("slice_in_offset", rp.slice_in_offset.into()),
+                ("confidence", Json::Float(rp.confidence)),
+            ]))
+            .collect();
+
+        let coverage = self.coverage;
+        Json::Obj(vec![
+            ("input", self.input.into()),
+            ("slices", Json::Arr(slices)),
+            ("raw_slices", Json::Arr(raw_slices)),
+            ("stop_reason", Self::stop_reason_json(self.ordered_info.stop_reason())),
+            ("recovered_parts", Json::Arr(recovered_parts)),
+            ("trailing_bytes", self.ordered_info.trailing_bytes().into()),
+            ("trailer", Self::trailer_json(self.ordered_info.trailer())),
+            ("header_slice", Self::header_slice_json(self.ordered_info.header_slice())),
+            ("last_contiguous_offset", self.ordered_info.last_contiguous_offset().into()),
+            ("media_size", Self::media_size_json(&self.media_size)),
+            ("mp4", Self::mp4_json(self.mp4.as_ref())),
+            ("validation", Self::validation_json(self.validation, self.overlap_policy)),
+            ("coverage", Json::Obj(vec![
+                ("total_len", coverage.total_len().into()),
+                ("covered_bytes", coverage.covered_bytes().into()),
+                ("missing_bytes", coverage.missing_bytes().into()),
+                ("percent", Json::Float(coverage.percent())),
+                ("covered", Self::ranges_json(coverage.covered())),
+                ("missing", Self::ranges_json(&coverage.missing())),
+            ])),
+        ])
+    }
+
+    /// Writes the report as a single line JSON document.
+    pub fn write_json<W: Write>(&self, w: &mut W) -> std::io::Result<()> {
+        writeln!(w, "{}", self.to_json())
+    }
+}
@@ -0,0 +1,234 @@
+/*
+    This file is a part of telegram-media-deserialize.
+
+    Copyright (C) 2022 Apple Sheeple <AppleSheeple at github>
+
+    telegram-media-deserialize is free software: you can
+    redistribute it and/or modify it under the terms of
+    the Affero GNU General Public License as published by
+    the Free Software Foundation.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+    Affero GNU General Public License for more details.
+
+    You should have received a copy of the Affero GNU General Public License
+    along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+
+//! Recovery of parts after a corrupt slice or part header, by scanning forward for
+//! plausible header sequences.
+
+use std::io;
+use std::rc::Rc;
+
+use crate::{ParserConfig, PartInfo};
+
+/// A part found after a corrupt header, see [`SerializedFile::set_resync`](crate::SerializedFile::set_resync).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RecoveredPart {
+    pub part: PartInfo,
+    /// Offset of the header of the slice the part was found in, `None` if the part was found
+    /// without its slice header (e.g. the rest of the slice with the corrupt header).
+    pub slice_in_offset: Option<u64>,
+    /// How plausible the part is, from 0 to 1.
+    ///
+    /// Found slices score higher the more of their parts were found, parts score higher if
+    /// their `out_offset` is aligned to the [part size](ParserConfig::part_size) and if they are full-sized (or last),
+    /// and both score lower if the bytes after them aren't another plausible header or the
+    /// end of the file.
+    pub confidence: f64,
+}
+
+/// Size of the window of the serialized file read at once while scanning.
+const WINDOW_SIZE: usize = 1024 * 1024;
+
+/// Scans the serialized file for plausible headers, reading it through `read_at` (which reads
+/// exactly `buf.len()` bytes at an offset).
+pub(crate) struct Scanner<F> {
+    config: ParserConfig,
+    /// Length of the serialized file.
+    len: u64,
+    read_at: F,
+    window: Vec<u8>,
+    window_start: u64,
+}
+
+/// Plausible parts found at one offset.
+struct Run {
+    parts: Vec<PartInfo>,
+    /// Slice header offset, and part count, if the run starts with a slice header.
+    slice: Option<(u64, u32)>,
+    end: u64,
+}
+
+/// Runs taken so far, the last one first.
+struct Chain {
+    run: Run,
+    confidence: f64,
+    prev: Option<Rc<Chain>>,
+}
+
+/// A run that isn't followed by the best runs before it yet, as the scan hasn't reached its end.
+struct Candidate {
+    end: u64,
+    /// Plausible bytes (weighted by confidence) of the run and the best runs before it.
+    score: f64,
+    chain: Rc<Chain>,
+}
+
+impl<F: FnMut(u64, &mut [u8]) -> io::Result<()>> Scanner<F> {
+    pub fn new(config: ParserConfig, len: u64, read_at: F) -> Self {
+        Self{config, len, read_at, window: Vec::new(), window_start: 0}
+    }
+
+    /// Scans the serialized file from `in_offset` for plausible slices and parts, in file order.
+    ///
+    /// Every offset is tried, and the non-overlapping runs of parts with the most plausible
+    /// bytes (weighted by confidence) are kept, so a spurious match (e.g. in the corrupt
+    /// header itself) doesn't hide the genuine headers it overlaps. The file is read through a
+    /// bounded window, and at most [`max_resync_candidates`](ParserConfig::max_resync_candidates)
+    /// overlapping runs are considered at once (the least plausible ones are dropped).
+    pub fn scan(&mut self, in_offset: u64) -> io::Result<Vec<RecoveredPart>> {
+        // the best score of the runs that end before the scanned offset, and the runs
+        let mut best: (f64, Option<Rc<Chain>>) = (0.0, None);
+        let mut candidates: Vec<Candidate> = Vec::new();
+        let fold = |best: &mut (f64, Option<Rc<Chain>>), candidate: Candidate| {
+            if candidate.score > best.0 {
+                *best = (candidate.score, Some(candidate.chain));
+            }
+        };
+
+        self.window_start = in_offset;
+        for pos in in_offset..self.len.saturating_sub(3) {
+            self.move_window(pos)?;
+            for candidate in candidates.extract_if(.., |candidate| candidate.end <= pos) {
+                fold(&mut best, candidate);
+            }
+            for run in [self.slice_at(pos)?, self.bare_parts_at(pos)?].into_iter().flatten() {
+                let followed = self.plausible_next(run.end)?;
+                let confidence = self.confidence(&run, followed);
+                let bytes = run.parts.iter().map(|pi| f64::from(pi.part_size)).sum::<f64>();
+                let score = best.0 + confidence * bytes;
+                let chain = Rc::new(Chain{run, confidence, prev: best.1.clone()});
+                candidates.push(Candidate{end: chain.run.end, score, chain});
+            }
+            if candidates.len() > self.config.max_resync_candidates.max(1) {
+                let (weakest, _) = candidates.iter()
+                    .enumerate()
+                    .min_by(|(_, a), (_, b)| a.score.total_cmp(&b.score))
+                    .expect("not empty");
+                candidates.swap_remove(weakest);
+            }
+        }
+        for candidate in candidates {
+            fold(&mut best, candidate);
+        }
+
+        let mut runs = Vec::new();
+        let mut chain = best.1.as_deref();
+        while let Some(Chain{run, confidence, prev}) = chain {
+            runs.push((run, *confidence));
+            chain = prev.as_deref();
+        }
+        let recovered = runs.into_iter()
+            .rev()
+            .flat_map(|(run, confidence)| {
+                let slice_in_offset = run.slice.map(|(in_offset, _)| in_offset);
+                run.parts.iter().map(move |&part| RecoveredPart{part, slice_in_offset, confidence})
+            })
+            .collect();
+        Ok(recovered)
+    }
+
+    /// A slice header at `pos`, followed by (some of) its parts.
+    fn slice_at(&mut self, pos: u64) -> io::Result<Option<Run>> {
+        let Some(parts_count) = self.u32_at(pos)?.filter(|&c| c > 0 && c <= self.config.max_parts_count) else {
+            return Ok(None);
+        };
+        let (parts, end) = self.parts_at(pos + 4, parts_count, false)?;
+        Ok((!parts.is_empty()).then_some(Run{parts, slice: Some((pos, parts_count)), end}))
+    }
+
+    /// Parts without a slice header at `pos`. Only parts aligned to the part size are accepted,
+    /// as there's no part count to back them.
+    fn bare_parts_at(&mut self, pos: u64) -> io::Result<Option<Run>> {
+        let (parts, end) = self.parts_at(pos, self.config.max_parts_count, true)?;
+        Ok((!parts.is_empty()).then_some(Run{parts, slice: None, end}))
+    }
+
+    /// Up to `max` consecutive plausible parts at `pos`, and the offset after the last one.
+    fn parts_at(&mut self, mut pos: u64, max: u32, aligned_only: bool) -> io::Result<(Vec<PartInfo>, u64)> {
+        let mut parts = Vec::new();
+        while parts.len() < max as usize {
+            let (Some(out_offset), Some(part_size)) = (self.u32_at(pos)?, self.u32_at(pos + 4)?) else {
+                break;
+            };
+            if part_size == 0 || part_size > self.config.max_part_size || (aligned_only && out_offset % self.config.part_size != 0) {
+                break;
+            }
+            let payload = pos + 8;
+            if payload + u64::from(part_size) > self.len {
+                break;
+            }
+            parts.push(PartInfo{in_offset: payload, out_offset, part_size});
+            pos = payload + u64::from(part_size);
+        }
+        Ok((parts, pos))
+    }
+
+    /// Whether the data at `pos` starts with a plausible header, or is the end of the file (the
+    /// few trailing bytes usually found there are too short for a part header).
+    fn plausible_next(&mut self, pos: u64) -> io::Result<bool> {
+        let first = self.u32_at(pos)?;
+        let plausible_count = first.is_some_and(|c| c > 0 && c <= self.config.max_parts_count);
+        let plausible_part = first.is_some_and(|o| o % self.config.part_size == 0)
+            && self.u32_at(pos + 4)?.is_some_and(|s| s > 0 && s <= self.config.max_part_size);
+        Ok(self.len - pos < 8 || plausible_count || plausible_part)
+    }
+
+    fn confidence(&self, run: &Run, followed: bool) -> f64 {
+        let last = run.parts.len() - 1;
+        let parts_score = run.parts.iter()
+            .enumerate()
+            .map(|(i, pi)| {
+                let aligned = pi.out_offset % self.config.part_size == 0;
+                let full = pi.part_size == self.config.part_size || i == last;
+                0.4 + 0.3 * f64::from(u8::from(aligned)) + 0.3 * f64::from(u8::from(full))
+            })
+            .sum::<f64>() / run.parts.len() as f64;
+        let completeness = match run.slice {
+            Some((_, parts_count)) => run.parts.len() as f64 / f64::from(parts_count),
+            None => 0.8,
+        };
+        let link = if followed { 1.0 } else { 0.7 };
+        completeness * parts_score * link
+    }
+
+    /// Moves the window to `pos`, unless the headers at `pos` are already in it.
+    fn move_window(&mut self, pos: u64) -> io::Result<()> {
+        let window_end = self.window_start + self.window.len() as u64;
+        if pos + 8 > window_end && window_end < self.len {
+            self.window.resize((self.len - pos).min(WINDOW_SIZE as u64) as usize, 0);
+            (self.read_at)(pos, &mut self.window)?;
+            self.window_start = pos;
+        }
+        Ok(())
+    }
+
+    /// The u32 at `pos`, if it's before the end of the file. Headers further ahead than the
+    /// window (while following a run of parts) are read directly.
+    fn u32_at(&mut self, pos: u64) -> io::Result<Option<u32>> {
+        if pos.checked_add(4).is_none_or(|end| end > self.len) {
+            return Ok(None);
+        }
+        let mut bytes = [0; 4];
+        match pos.checked_sub(self.window_start).map(|start| start as usize) {
+            Some(start) if start + 4 <= self.window.len() => bytes.copy_from_slice(&self.window[start..start + 4]),
+            _ => (self.read_at)(pos, &mut bytes)?,
+        }
+        Ok(Some(u32::from_le_bytes(bytes)))
+    }
+}
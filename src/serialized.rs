@@ -0,0 +1,745 @@
+/*
+    This file is a part of telegram-media-deserialize.
+
+    Copyright (C) 2022 Apple Sheeple <AppleSheeple at github>
+
+    telegram-media-deserialize is free software: you can
+    redistribute it and/or modify it under the terms of
+    the Affero GNU General Public License as published by
+    the Free Software Foundation.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+    Affero GNU General Public License for more details.
+
+    You should have received a copy of the Affero GNU General Public License
+    along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+
+use std::fmt;
+use std::path::PathBuf;
+use std::fs::{File, OpenOptions};
+use std::io::{self, ErrorKind, Read, Write, Seek, SeekFrom};
+
+use memmap2::Mmap;
+
+use crate::{Coverage, Error, HeaderSlice, ParserConfig, RawSlice, RecoveredPart, Res, Trailer, WriteAt};
+use crate::resync::Scanner;
+use crate::trailer::TRAILER_READ_LEN;
+use crate::write_at::{copy_at, read_chunks, sub_slice, write_from_slice, CopyError, COPY_BUF_SIZE};
+
+/// A single part of a slice in the serialized file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PartInfo {
+    /// Offset of the part payload in the serialized file (after the part header).
+    pub in_offset: u64,
+    /// Offset of the part in the deserialized media stream.
+    pub out_offset: u32,
+    /// Size of the part payload in bytes (only the available ones for a truncated
+    /// final part, see [`StopReason::TruncatedPart`]).
+    pub part_size: u32,
+}
+
+impl PartInfo {
+    /// Offset in the deserialized media stream right after this part.
+    pub fn out_end(&self) -> u64 {
+        u64::from(self.out_offset) + u64::from(self.part_size)
+    }
+}
+
+/// A slice in the serialized file, with its parts in file order.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SliceInfo {
+    /// Offset of the slice header in the serialized file.
+    pub in_offset: u64,
+    /// Part count from the slice header.
+    pub parts_count: u32,
+    /// Parsed parts (fewer than `parts_count` if parsing stopped in this slice).
+    pub parts: Vec<PartInfo>,
+}
+
+/// Why parsing of the serialized file stopped.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum StopReason {
+    /// The whole file was parsed.
+    #[default]
+    EndOfFile,
+    /// Less than 4 bytes were left for a slice header.
+    TruncatedSliceHeader { in_offset: u64 },
+    /// A slice header has a part count of zero or above the allowed maximum.
+    BadPartsCount { in_offset: u64, parts: u32 },
+    /// A part header has a part size of zero or above the allowed maximum.
+    BadPartSize { in_offset: u64, part_size: u32 },
+    /// Less than 8 bytes were left for a part header.
+    TruncatedPartHeader { in_offset: u64 },
+    /// The file ends within the payload of the part at `out_offset`, with only `available` of
+    /// its `part_size` bytes. The available bytes are kept as a shorter part.
+    TruncatedPart { in_offset: u64, out_offset: u32, part_size: u32, available: u32 },
+}
+
+impl StopReason {
+    /// Offset in the serialized file where parsing stopped, if it stopped before the end.
+    pub fn in_offset(&self) -> Option<u64> {
+        match *self {
+            Self::EndOfFile => None,
+            Self::TruncatedSliceHeader{in_offset}
+                | Self::BadPartsCount{in_offset, ..}
+                | Self::BadPartSize{in_offset, ..}
+                | Self::TruncatedPartHeader{in_offset}
+                | Self::TruncatedPart{in_offset, ..} => Some(in_offset),
+        }
+    }
+}
+
+/// Parsed part info, ordered by `out_offset`.
+#[derive(Debug, Clone, Default)]
+pub struct OrderedPartInfos {
+    parts: Vec<PartInfo>,
+    slices: Vec<SliceInfo>,
+    recovered: Vec<RecoveredPart>,
+    stop_reason: StopReason,
+    trailing_bytes: u64,
+    trailer: Trailer,
+    header_slice: Option<HeaderSlice>,
+    /// Limits the file was parsed with.
+    config: ParserConfig,
+}
+
+impl OrderedPartInfos {
+    /// Orders `info` by `out_offset`.
+    pub fn new(mut info: Vec<PartInfo>) -> Self {
+        info.sort_by_key(|pi| pi.out_offset);
+        Self{parts: info, ..Self::default()}
+    }
+
+    /// Orders the parts of `slices` by `out_offset`, keeping the parsing details.
+    pub fn from_slices(slices: Vec<SliceInfo>, stop_reason: StopReason, trailing_bytes: u64) -> Self {
+        let parts = slices.iter().flat_map(|si| si.parts.iter().copied()).collect();
+        Self{slices, stop_reason, trailing_bytes, ..Self::new(parts)}
+    }
+
+    pub fn parts(&self) -> &[PartInfo] {
+        &self.parts
+    }
+
+    pub fn into_parts(self) -> Vec<PartInfo> {
+        self.parts
+    }
+
+    /// Parsed slices, in file order.
+    pub fn slices(&self) -> &[SliceInfo] {
+        &self.slices
+    }
+
+    pub fn stop_reason(&self) -> StopReason {
+        self.stop_reason
+    }
+
+    /// Parts recovered after a corrupt header (see [`SerializedFile::set_resync`]), in file order.
+    /// They are not included in [`parts`](Self::parts), unless added with
+    /// [`with_recovered`](Self::with_recovered).
+    pub fn recovered(&self) -> &[RecoveredPart] {
+        &self.recovered
+    }
+
+    /// A copy with the recovered parts with a confidence of at least `min_confidence` added to
+    /// the parts, so that they are written and covered too.
+    pub fn with_recovered(&self, min_confidence: f64) -> Self {
+        let mut parts = self.parts.clone();
+        parts.extend(self.recovered.iter()
+            .filter(|rp| rp.confidence >= min_confidence)
+            .map(|rp| rp.part));
+        self.with_parts(parts)
+    }
+
+    /// A copy with `parts` (ordered by `out_offset`) instead, keeping the parsing details.
+    pub(crate) fn with_parts(&self, mut parts: Vec<PartInfo>) -> Self {
+        parts.sort_by_key(|pi| pi.out_offset);
+        Self{parts, ..self.clone()}
+    }
+
+    /// Bytes left unparsed at the end of the serialized file.
+    pub fn trailing_bytes(&self) -> u64 {
+        self.trailing_bytes
+    }
+
+    /// The [`trailing_bytes`](Self::trailing_bytes), decoded.
+    pub fn trailer(&self) -> &Trailer {
+        &self.trailer
+    }
+
+    /// Metadata of the header slice, if it was fully parsed.
+    pub fn header_slice(&self) -> Option<HeaderSlice> {
+        self.header_slice
+    }
+
+    /// Size of the media, as decoded from the header slice metadata (see
+    /// [`Trailer::HeaderSlice`]), or else if the data ends with a part shorter than the part
+    /// size (see [`ParserConfig::part_size`]). Telegram Desktop reads the media in parts of that
+    /// size, at offsets aligned to it, so only the last part of the media is shorter.
+    ///
+    /// The full size of a truncated final part (see [`StopReason::TruncatedPart`]) is used.
+    pub fn media_size(&self) -> Option<u64> {
+        if let Trailer::HeaderSlice(HeaderSlice{media_size: Some(media_size), ..}) = self.trailer {
+            return Some(media_size);
+        }
+        let part_size = self.config.part_size;
+        let truncated = match self.stop_reason {
+            StopReason::TruncatedPart{in_offset, part_size, ..} => Some((in_offset + 8, part_size)),
+            _ => None,
+        };
+        let (out_offset, size) = self.parts.iter()
+            .map(|pi| match truncated {
+                Some((in_offset, full_size)) if pi.in_offset == in_offset => (pi.out_offset, full_size),
+                _ => (pi.out_offset, pi.part_size),
+            })
+            .max_by_key(|&(out_offset, size)| (u64::from(out_offset) + u64::from(size), out_offset))?;
+        (size < part_size && out_offset % part_size == 0).then(|| u64::from(out_offset) + u64::from(size))
+    }
+
+    /// Index of the last part that is contiguous with the first one, if any parts exist.
+    fn last_contiguous_index(&self) -> Option<usize> {
+        let info = &self.parts;
+        if info.is_empty() {
+            return None;
+        }
+        let mut last_contigous_i = 0;
+        'contig: for i in 1..info.len() {
+            let prev = &info[i-1];
+            let curr = &info[i];
+            if u64::from(curr.out_offset) == prev.out_end() {
+                last_contigous_i = i;
+            } else {
+                break 'contig;
+            }
+        }
+        Some(last_contigous_i)
+    }
+
+    /// Offset in the deserialized media stream up to which data is contiguous
+    /// (starting from the first part).
+    pub fn last_contiguous_offset(&self) -> u64 {
+        self.last_contiguous_index()
+            .map(|i| self.parts[i].out_end())
+            .unwrap_or(0)
+    }
+
+    /// Offset in the deserialized media stream right after the last part.
+    pub fn end_offset(&self) -> u64 {
+        self.parts.iter().map(PartInfo::out_end).max().unwrap_or(0)
+    }
+
+    /// Covered and missing ranges of the deserialized media stream, up to the end of the last part
+    /// (or of the full size of a truncated final part, whose missing bytes are then included).
+    pub fn coverage(&self) -> Coverage {
+        let total_len = match self.stop_reason {
+            StopReason::TruncatedPart{out_offset, part_size, ..} =>
+                Some(self.end_offset().max(u64::from(out_offset) + u64::from(part_size))),
+            _ => None,
+        };
+        Coverage::new(self.parts.iter().map(|pi| u64::from(pi.out_offset)..pi.out_end()), total_len)
+    }
+}
+
+impl fmt::Display for OrderedPartInfos {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let info = &self.parts;
+        let Some(last_contigous_i) = self.last_contiguous_index() else {
+            return Ok(());
+        };
+        if info.len() == 1 {
+            return Ok(());
+        }
+        let first_part = &info[0];
+        let last_part = &info[info.len()-1];
+        let last_contiguous = &info[last_contigous_i];
+        let last_contiguous_offset = last_contiguous.out_end();
+        let last_contiguous_offset_kib = (last_contiguous_offset as f64) / 1024.0;
+        let last_contiguous_offset_mib = last_contiguous_offset_kib / 1024.0;
+        let discontinuity_len = u64::from(last_part.out_offset).saturating_sub(last_contiguous_offset);
+        write!(f, "\n=======\nAfter ordering part info by out_offset:\n \
+                    First part: {first_part:?}\n \
+                    Last contiguous: {last_contiguous:?}\n \
+                    Last contiguous offset: {last_contiguous_offset} bytes \
+                    ({last_contiguous_offset_kib:.4}KiB/\
+                    {last_contiguous_offset_mib:.4}MiB) \
+                    (Discontinuity: {discontinuity_len} bytes)\n \
+                    Last part: {last_part:?}\n=======")
+    }
+}
+
+/// The serialized (input) cache file.
+///
+/// Backed by a [`File`] by default, but any `Read + Seek` source can be used
+/// (e.g. a decrypting [`EncryptedFile`](crate::EncryptedFile)).
+#[derive(Debug)]
+pub struct SerializedFile<R = File> {
+    name: String,
+    len: u64,
+    file: R,
+    /// `file` as a plain [`File`], for in-kernel copies.
+    direct: Option<File>,
+    /// `file` mapped into memory, used instead of `file` if set.
+    mapping: Option<Mmap>,
+    /// Position in `mapping`.
+    map_pos: u64,
+    config: ParserConfig,
+    verbose: bool,
+    resync: bool,
+    padded: bool,
+    copy_buf: Vec<u8>,
+    b4_buf: [u8; 4],
+}
+
+impl SerializedFile {
+    /// Opens the existing file named `name` for reading.
+    pub fn from_name(name: String) -> Res<Self> {
+        let path  = PathBuf::from(name.clone());
+        path.exists()
+            .then_some(())
+            .ok_or_else(|| Error::InputMissing{path: path.clone()})?;
+
+        let file = OpenOptions::new()
+            .read(true)
+            .open(&path)
+            .map_err(|e| Error::io("opening for read", &path, None, e))?;
+
+        let direct = file.try_clone().ok();
+        Ok(Self{direct, ..Self::from_reader(name, file)?})
+    }
+
+    /// Opens the existing file named `name` for reading, and maps it into memory.
+    ///
+    /// Headers are then parsed, and part payloads copied, straight from the mapping, without
+    /// a syscall for each of them. Results are identical to [`from_name`](Self::from_name).
+    ///
+    /// The file must not be truncated while it's mapped (e.g. by Telegram Desktop, while it's
+    /// running), as reading the pages that went away would crash the process.
+    pub fn from_name_mmap(name: String) -> Res<Self> {
+        let serialized_file = Self::from_name(name)?;
+        // SAFETY: the mapping is only ever read, see above about modifications of the file.
+        let mapping = unsafe { Mmap::map(&serialized_file.file) }
+            .map_err(|e| Error::io("mapping", &serialized_file.name, None, e))?;
+        Ok(Self{len: mapping.len() as u64, mapping: Some(mapping), ..serialized_file})
+    }
+
+    /// Whether the file is mapped into memory (see [`from_name_mmap`](Self::from_name_mmap)).
+    pub fn is_mapped(&self) -> bool {
+        self.mapping.is_some()
+    }
+}
+
+impl<R: Read + Seek> SerializedFile<R> {
+    /// Wraps an already opened serialized stream. `name` is only used in messages.
+    pub fn from_reader(name: String, mut file: R) -> Res<Self> {
+        let len = file.seek(SeekFrom::End(0))
+            .map_err(|e| Error::io("getting length of", &name, None, e))?;
+
+        let copy_buf = vec![0; COPY_BUF_SIZE];
+        let b4_buf = [0; 4];
+
+        Ok(Self {name, len, file, direct: None, mapping: None, map_pos: 0, config: ParserConfig::default(), verbose: false, resync: false, padded: false, copy_buf, b4_buf})
+    }
+
+    /// Print parsing and extraction progress to stderr.
+    pub fn set_verbose(&mut self, verbose: bool) {
+        self.verbose = verbose;
+    }
+
+    /// Sets the limits used to parse the file (see [`ParserConfig`]).
+    pub fn set_config(&mut self, config: ParserConfig) {
+        self.config = ParserConfig{part_size: config.part_size.max(1), slice_size: config.slice_size.max(1), ..config};
+    }
+
+    pub fn config(&self) -> &ParserConfig {
+        &self.config
+    }
+
+    /// If parsing stops at a corrupt slice or part header, scan the rest of the file for
+    /// plausible slice and part headers (see [`OrderedPartInfos::recovered`]).
+    pub fn set_resync(&mut self, resync: bool) {
+        self.resync = resync;
+    }
+
+    /// The file may end with up to 15 zero bytes of padding, e.g. a decrypted file that wasn't
+    /// cut to its size from the cache database (see [`EncryptedFile::is_padded`]). Zeros that
+    /// fit in the padding are then decoded as [`Trailer::Padding`].
+    ///
+    /// [`EncryptedFile::is_padded`]: crate::EncryptedFile::is_padded
+    pub fn set_padded(&mut self, padded: bool) {
+        self.padded = padded;
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn _seek_from_start(&mut self, offset: u64) -> Res<u64> {
+        if self.mapping.is_some() {
+            self.map_pos = offset;
+            return Ok(offset);
+        }
+        self.file.seek(SeekFrom::Start(offset))
+            .map_err(|e| Error::io("seeking", &self.name, Some(offset), e))
+    }
+
+    fn _read_u32_le(&mut self) -> Res<u32> {
+        match &self.mapping {
+            Some(mapping) => {
+                sub_slice(mapping, self.map_pos, 4)
+                    .filter(|bytes| bytes.len() == 4)
+                    .map(|bytes| self.b4_buf.copy_from_slice(bytes))
+                    .ok_or_else(|| io::Error::from(ErrorKind::UnexpectedEof))
+                    .map_err(|e| Error::io("reading 4 bytes from", &self.name, Some(self.map_pos), e))?;
+                self.map_pos += 4;
+            },
+            None => {
+                let offset = self.file.stream_position()
+                    .map_err(|e| Error::io("getting position in", &self.name, None, e))?;
+                self.file.read_exact(&mut self.b4_buf)
+                    .map_err(|e| Error::io("reading 4 bytes from", &self.name, Some(offset), e))?;
+            },
+        }
+
+        Ok(u32::from_le_bytes(self.b4_buf))
+    }
+
+    /// Reads `buf.len()` bytes at `in_offset` of the serialized file.
+    pub(crate) fn read_exact_at(&mut self, in_offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        if let Some(mapping) = &self.mapping {
+            let bytes = sub_slice(mapping, in_offset, buf.len() as u64)
+                .filter(|bytes| bytes.len() == buf.len())
+                .ok_or_else(|| io::Error::from(ErrorKind::UnexpectedEof))?;
+            buf.copy_from_slice(bytes);
+            return Ok(());
+        }
+        self.file.seek(SeekFrom::Start(in_offset))?;
+        self.file.read_exact(buf)
+    }
+
+    /// Parses slice and part headers, returning part info ordered by `out_offset`.
+    pub fn get_info(&mut self) -> Res<OrderedPartInfos> {
+        let ParserConfig{max_parts_count, max_part_size, ..} = self.config;
+        let mut slices: Vec<SliceInfo> = Vec::with_capacity(16);
+        let mut stop_reason = StopReason::EndOfFile;
+
+        let _ = self._seek_from_start(0)?;
+
+        let mut slice_i = 0;
+        let mut in_offset = 0;
+        // Offsets are computed here instead of asking the reader, so that every iteration
+        // strictly moves forward (by a slice header, or by a part header and a non-empty
+        // payload), and parsing ends for any input.
+        'out: while in_offset < self.len {
+            let parts_res = self._read_u32_le();
+
+            if parts_res.is_err() {
+                self.verbose.then(|| eprintln!("reached EOF, will stop parsing.."));
+                stop_reason = StopReason::TruncatedSliceHeader{in_offset};
+                break 'out;
+            }
+
+            let parts = parts_res?;
+
+            if parts == 0 || parts > max_parts_count {
+                if self.verbose {
+                    eprintln!("Slice{slice_i}: in_offset={in_offset}, \
+                        parsed parts={parts} is zero or > max allowed({max_parts_count}), will stop parsing..");
+                    eprintln!("in_offset={in_offset}, stopped parsing with {} bytes remaining in file.", self.len - in_offset);
+                }
+                if slices.is_empty() && !self.resync {
+                    let path = self.name.clone().into();
+                    return Err(Error::MalformedSliceHeader{path, in_offset, parts, max: max_parts_count});
+                }
+                stop_reason = StopReason::BadPartsCount{in_offset, parts};
+                break 'out;
+            }
+            self.verbose.then(|| eprintln!("Slice{slice_i}: in_offset={in_offset}, parts={parts}"));
+            slices.push(SliceInfo{in_offset, parts_count: parts, parts: Vec::with_capacity(parts as usize)});
+            in_offset += 4;
+
+            let mut read_parts = 0;
+
+            while read_parts < parts {
+                let header_res = self._read_u32_le()
+                    .and_then(|out_offset| Ok((out_offset, self._read_u32_le()?)));
+
+                let Ok((out_offset, part_size)) = header_res else {
+                    self.verbose.then(|| eprintln!("Slice{slice_i}/Part{read_parts}: in_offset={in_offset}, \
+                        reached EOF in part header, will stop parsing.."));
+                    stop_reason = StopReason::TruncatedPartHeader{in_offset};
+                    break 'out;
+                };
+
+                if part_size == 0 || part_size > max_part_size {
+                    if self.verbose {
+                        eprintln!("Slice{slice_i}/Part{read_parts}: in_offset={in_offset}, \
+                            part_size={part_size} is zero or > max_allowed({max_part_size}), will stop parsing..");
+                        eprintln!("in_offset={in_offset}, stopped parsing with {} bytes remaining in file.", self.len - in_offset);
+                    }
+                    if slices.len() == 1 && read_parts == 0 && !self.resync {
+                        let path = self.name.clone().into();
+                        return Err(Error::PartSizeOutOfRange{path, in_offset, part_size, max: max_part_size});
+                    }
+                    stop_reason = StopReason::BadPartSize{in_offset, part_size};
+                    break 'out;
+                }
+
+                in_offset += 8;
+                let available = self.len.saturating_sub(in_offset).min(part_size.into()) as u32;
+                if available < part_size {
+                    self.verbose.then(|| eprintln!("Slice{slice_i}/Part{read_parts}: in_offset={in_offset}, out_offset={out_offset}, \
+                        part_size={part_size} is truncated, salvaging the {available} available bytes, will stop parsing.."));
+                    if available > 0 {
+                        let slice = slices.last_mut().expect("pushed above");
+                        slice.parts.push(PartInfo{in_offset, out_offset, part_size: available});
+                    }
+                    stop_reason = StopReason::TruncatedPart{in_offset: in_offset - 8, out_offset, part_size, available};
+                    break 'out;
+                }
+
+                self.verbose.then(|| eprintln!("Slice{slice_i}/Part{read_parts}: in_offset={in_offset}, out_offset={out_offset}, part_size={part_size}"));
+                let slice = slices.last_mut().expect("pushed above");
+                slice.parts.push(PartInfo{in_offset, out_offset, part_size});
+
+                in_offset += u64::from(part_size);
+                let _ = self._seek_from_start(in_offset)?;
+                read_parts += 1;
+            }
+            slice_i += 1;
+        }
+
+        let trailing_bytes = match stop_reason {
+            // the available bytes of the part are used
+            StopReason::TruncatedPart{..} => 0,
+            _ => stop_reason.in_offset()
+                .map(|in_offset| self.len.saturating_sub(in_offset))
+                .unwrap_or(0),
+        };
+        // the salvaged part of a truncated header slice doesn't end the media
+        let header_complete = slices.len() > 1 || !matches!(stop_reason, StopReason::TruncatedPart{..});
+        let header_slice = slices.first()
+            .filter(|_| header_complete)
+            .and_then(|si| HeaderSlice::from_slice(si, &self.config));
+        let mut ordered_info = OrderedPartInfos::from_slices(slices, stop_reason, trailing_bytes);
+        ordered_info.config = self.config;
+        ordered_info.trailer = self.read_trailer(stop_reason, trailing_bytes, ordered_info.end_offset())?;
+        ordered_info.header_slice = match ordered_info.trailer {
+            Trailer::HeaderSlice(decoded) => Some(decoded),
+            _ => header_slice,
+        };
+        if let (true, Some(HeaderSlice{full_in_cache: true, media_size: Some(media_size)})) = (self.verbose, ordered_info.header_slice) {
+            eprintln!("Header slice holds the whole media ({media_size} bytes)");
+        }
+        if let (true, StopReason::BadPartsCount{in_offset, ..} | StopReason::BadPartSize{in_offset, ..}) = (self.resync, stop_reason) {
+            ordered_info.recovered = self.resync_from(in_offset + 1)?;
+        }
+        self.verbose.then(|| eprintln!("{ordered_info}"));
+        Ok(ordered_info)
+    }
+
+    /// Reads and decodes the `trailing_bytes` left when parsing stopped for `stop_reason`, after
+    /// parts ending at `parts_end`.
+    fn read_trailer(&mut self, stop_reason: StopReason, trailing_bytes: u64, parts_end: u64) -> Res<Trailer> {
+        let in_offset = self.len - trailing_bytes;
+        let mut head = vec![0; trailing_bytes.min(TRAILER_READ_LEN as u64) as usize];
+        self.read_exact_at(in_offset, &mut head)
+            .map_err(|e| Error::io("reading trailing bytes from", &self.name, Some(in_offset), e))?;
+        let at_slice_boundary = matches!(stop_reason, StopReason::TruncatedSliceHeader{..} | StopReason::BadPartsCount{..});
+        let trailer = Trailer::decode(trailing_bytes, &head, at_slice_boundary, self.padded, parts_end);
+        if self.verbose && trailing_bytes > 0 {
+            eprintln!("in_offset={in_offset}: {trailing_bytes} trailing bytes, decoded as {trailer:?}");
+        }
+        Ok(trailer)
+    }
+
+    /// Scans the file from `in_offset` for plausible slice and part headers.
+    fn resync_from(&mut self, in_offset: u64) -> Res<Vec<RecoveredPart>> {
+        let (config, len) = (self.config, self.len);
+        let recovered = Scanner::new(config, len, |in_offset, buf| self.read_exact_at(in_offset, buf))
+            .scan(in_offset)
+            .map_err(|e| Error::io("reading", &self.name, Some(in_offset), e))?;
+        if self.verbose {
+            eprintln!("Resync from in_offset={in_offset}: recovered {} part(s)", recovered.len());
+            for rp in &recovered {
+                eprintln!("Recovered {:?} (slice header at in_offset={:?}, confidence={:.2})", rp.part, rp.slice_in_offset, rp.confidence);
+            }
+        }
+        Ok(recovered)
+    }
+
+    /// Writes the parts described by `ordered_info` (as returned by [`get_info`](Self::get_info))
+    /// to their `out_offset` in `sink`.
+    ///
+    /// Parts are copied in file order, so the serialized file is read sequentially, and written
+    /// with positioned writes through a single buffer. If parts overlap, the one that comes
+    /// later in the serialized file wins, which other writers don't guarantee. Resolve overlaps
+    /// first with [`OrderedPartInfos::with_overlap_policy`] for the same output everywhere.
+    pub fn write_to<W: WriteAt>(&mut self, ordered_info: &OrderedPartInfos, sink: &mut W) -> Res<()> {
+        let slice_size = self.config.slice_size;
+        self.write_with_slices_to::<File, W>(ordered_info, &mut [], slice_size, sink)
+    }
+
+    fn write_part_to<W: WriteAt>(&mut self, part_info: &PartInfo, pos: &mut Option<u64>, sink: &mut W) -> Res<()> {
+        let &PartInfo{in_offset, out_offset, part_size} = part_info;
+        self.verbose.then(|| eprintln!("writing {part_size} from {}@{in_offset} to @{out_offset}", self.name));
+        let copied = match &self.mapping {
+            Some(mapping) => write_from_slice(mapping, in_offset, part_size.into(),
+                |bytes| sink.write_all_at(bytes, out_offset.into())),
+            None => copy_at(&mut self.file, self.direct.as_ref(), pos, in_offset, part_size.into(),
+                sink, out_offset.into(), &mut self.copy_buf),
+        };
+        self.check_part_copy(part_info, u64::from(out_offset), copied)
+    }
+
+    /// Maps the result of copying (the rest of) a part, the copy starting at `out_offset`.
+    fn check_part_copy(&self, part_info: &PartInfo, out_offset: u64, copied: Result<u64, CopyError>) -> Res<()> {
+        let &PartInfo{in_offset, part_size, ..} = part_info;
+        let skip = out_offset - u64::from(part_info.out_offset);
+        match copied {
+            Ok(copied) if skip + copied == u64::from(part_size) => Ok(()),
+            Ok(copied) => Err(Error::TruncatedPart{path: self.name.clone().into(), in_offset, part_size, read: skip + copied}),
+            Err(CopyError::Read{copied, source}) =>
+                Err(Error::io("reading part from", &self.name, Some(in_offset + skip + copied), source)),
+            Err(CopyError::Write{copied, source}) => Err(Error::sink("writing part to", out_offset + copied, source)),
+        }
+    }
+
+    /// Like [`write_to`](Self::write_to), but also writes the follow-up raw `slices`.
+    ///
+    /// Each raw slice is written at `index * slice_size` (see [`RawSlice`]), and is cut to
+    /// `slice_size`. Ranges of missing slices are left as holes, so that data after them is
+    /// still written at its correct position. Parts written with a forward seek are kept,
+    /// unless they are fully covered by a raw slice (whose data is then used instead).
+    pub fn write_with_slices_to<S, W>(&mut self, ordered_info: &OrderedPartInfos, slices: &mut [RawSlice<S>],
+        slice_size: u64, sink: &mut W) -> Res<()>
+    where
+        S: Read + Seek,
+        W: WriteAt,
+    {
+        slices.iter_mut().for_each(|slice| slice.truncate(slice_size));
+
+        let mut parts = ordered_info.parts().to_vec();
+        parts.sort_by_key(|pi| pi.in_offset);
+
+        let mut pos = None;
+        for part_info in &parts {
+            if Self::covered_by_slice(part_info, slices, slice_size) {
+                self.verbose.then(|| eprintln!("skipping {part_info:?}, covered by a raw slice"));
+                continue;
+            }
+            self.write_part_to(part_info, &mut pos, sink)?;
+        }
+
+        for slice in slices {
+            let out_offset = slice.out_offset(slice_size);
+            self.verbose.then(|| eprintln!("writing {} from {} (slice {}) to @{out_offset}", slice.len(), slice.name(), slice.index()));
+            slice.copy_to(out_offset, sink, &mut self.copy_buf)?;
+        }
+        Ok(())
+    }
+
+    fn covered_by_slice<S>(part_info: &PartInfo, slices: &[RawSlice<S>], slice_size: u64) -> bool {
+        slices.iter().any(|slice| {
+            let range = slice.out_range(slice_size);
+            u64::from(part_info.out_offset) >= range.start && part_info.out_end() <= range.end
+        })
+    }
+
+    /// Writes the deserialized media stream (parts and raw `slices`, see
+    /// [`write_with_slices_to`](Self::write_with_slices_to)) sequentially to a sink that
+    /// can't seek, e.g. stdout or a pipe.
+    ///
+    /// Data is written in `out_offset` order. Gaps are handled according to `gap_policy`. If
+    /// parts overlap, the one starting first is written whole, and only what the others have
+    /// beyond it, unlike [`write_to`](Self::write_to) where the later part in the file wins.
+    /// Resolve overlaps first with [`OrderedPartInfos::with_overlap_policy`] for the same output
+    /// everywhere. Returns the number of bytes written.
+    pub fn write_stream_to<S, W>(&mut self, ordered_info: &OrderedPartInfos, slices: &mut [RawSlice<S>],
+        slice_size: u64, gap_policy: GapPolicy, sink: &mut W) -> Res<u64>
+    where
+        S: Read + Seek,
+        W: Write,
+    {
+        enum Source<'a> {
+            Part(&'a PartInfo),
+            Slice(usize),
+        }
+
+        slices.iter_mut().for_each(|slice| slice.truncate(slice_size));
+
+        let mut sources = ordered_info.parts().iter()
+            .filter(|pi| !Self::covered_by_slice(pi, slices, slice_size))
+            .map(|pi| (u64::from(pi.out_offset), pi.out_end(), Source::Part(pi)))
+            .chain(slices.iter().enumerate()
+                .map(|(i, slice)| {
+                    let range = slice.out_range(slice_size);
+                    (range.start, range.end, Source::Slice(i))
+                }))
+            .collect::<Vec<_>>();
+        sources.sort_by_key(|&(start, _, _)| start);
+
+        let mut pos = 0;
+        let mut in_pos = None;
+        for (start, end, source) in sources {
+            if end <= pos {
+                continue;
+            }
+            if start > pos {
+                if gap_policy == GapPolicy::Cut {
+                    self.verbose.then(|| eprintln!("gap at @{pos}..{start}, cutting output"));
+                    break;
+                }
+                self.verbose.then(|| eprintln!("padding gap at @{pos}..{start} with zeros"));
+                write_zeros(start - pos, pos, sink)?;
+                pos = start;
+            }
+            let skip = pos - start;
+            match source {
+                Source::Part(part_info) => {
+                    let in_offset = part_info.in_offset + skip;
+                    self.verbose.then(|| eprintln!("writing {} from {}@{in_offset} to @{pos}", end - pos, self.name));
+                    let copied = match &self.mapping {
+                        Some(mapping) => write_from_slice(mapping, in_offset, end - pos, |bytes| sink.write_all(bytes)),
+                        None => read_chunks(&mut self.file, &mut in_pos, in_offset, end - pos, &mut self.copy_buf,
+                            |chunk, _| sink.write_all(chunk)),
+                    };
+                    self.check_part_copy(part_info, pos, copied)?;
+                },
+                Source::Slice(i) => {
+                    let slice = &mut slices[i];
+                    self.verbose.then(|| eprintln!("writing {} from {} (slice {}) to @{pos}", end - pos, slice.name(), slice.index()));
+                    slice.copy_range_to(skip, pos, sink, &mut self.copy_buf)?;
+                },
+            }
+            pos = end;
+        }
+        sink.flush()
+            .map_err(|e| Error::sink("flushing", pos, e))?;
+        Ok(pos)
+    }
+}
+
+/// How [`SerializedFile::write_stream_to`] handles gaps between parts.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum GapPolicy {
+    /// Pad gaps with zeros.
+    #[default]
+    Zero,
+    /// Stop writing at the first gap (i.e. at the last contiguous offset).
+    Cut,
+}
+
+fn write_zeros<W: Write>(len: u64, out_offset: u64, sink: &mut W) -> Res<()> {
+    static ZEROS: [u8; 64 * 1024] = [0; 64 * 1024];
+    let mut left = len;
+    while left > 0 {
+        let n = left.min(ZEROS.len() as u64) as usize;
+        sink.write_all(&ZEROS[..n])
+            .map_err(|e| Error::sink("writing zeros to", out_offset + (len - left), e))?;
+        left -= n as u64;
+    }
+    Ok(())
+}
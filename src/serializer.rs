@@ -0,0 +1,133 @@
+/*
+    This file is a part of telegram-media-deserialize.
+
+    Copyright (C) 2022 Apple Sheeple <AppleSheeple at github>
+
+    telegram-media-deserialize is free software: you can
+    redistribute it and/or modify it under the terms of
+    the Affero GNU General Public License as published by
+    the Free Software Foundation.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+    Affero GNU General Public License for more details.
+
+    You should have received a copy of the Affero GNU General Public License
+    along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+
+use crate::{Error, PartInfo, Res, SliceInfo};
+
+/// Writes serialized cache files, in the layout parsed by
+/// [`SerializedFile::get_info`](crate::SerializedFile::get_info).
+///
+/// This is the inverse of deserialization: each slice is written as a 4 bytes part count,
+/// followed by its parts, each one with its 8 bytes header and its payload read from the
+/// media stream. The parser's limits (part count and part size) are not enforced, so
+/// malformed files can be written too.
+#[derive(Debug)]
+pub struct Serializer<W = File> {
+    name: String,
+    sink: W,
+    pos: u64,
+    part_buf: Vec<u8>,
+}
+
+impl Serializer {
+    /// Creates a new file named `name`. Fails if a file with that name already exists.
+    pub fn from_name(name: String) -> Res<Self> {
+        let path  = PathBuf::from(name.clone());
+
+        (!path.exists())
+            .then_some(())
+            .ok_or_else(|| Error::OutputExists{path: path.clone()})?;
+
+        let file = OpenOptions::new()
+            .create_new(true)
+            .write(true)
+            .open(&path)
+            .map_err(|e| Error::io("creating for writing", &path, None, e))?;
+
+        Ok(Self::from_writer(name, file))
+    }
+}
+
+impl<W: Write> Serializer<W> {
+    /// Wraps an already opened sink. `name` is only used in messages.
+    pub fn from_writer(name: String, sink: W) -> Self {
+        Self{name, sink, pos: 0, part_buf: Vec::new()}
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Bytes written so far.
+    pub fn len(&self) -> u64 {
+        self.pos
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pos == 0
+    }
+
+    /// Writes a slice with a part for each `(out_offset, part_size)` read, in order.
+    /// The payloads are read from `media`, at their `out_offset`.
+    ///
+    /// Returns the written slice, as it would be parsed back.
+    pub fn write_slice<R: Read + Seek>(&mut self, media: &mut R, reads: &[(u32, u32)]) -> Res<SliceInfo> {
+        let in_offset = self.pos;
+        let parts_count = u32::try_from(reads.len())
+            .map_err(|_| io::Error::new(ErrorKind::InvalidInput, "too many parts in a slice"))
+            .map_err(|e| Error::io("writing slice header to", &self.name, Some(in_offset), e))?;
+        self.write_all(&parts_count.to_le_bytes(), "writing slice header to")?;
+
+        let mut parts = Vec::with_capacity(reads.len());
+        for &(out_offset, part_size) in reads {
+            self.part_buf.resize(part_size as usize, 0);
+            media.seek(SeekFrom::Start(out_offset.into()))
+                .and_then(|_| media.read_exact(&mut self.part_buf))
+                .map_err(|e| Error::io("reading part payload from", "media stream", Some(out_offset.into()), e))?;
+
+            self.write_all(&out_offset.to_le_bytes(), "writing part header to")?;
+            self.write_all(&part_size.to_le_bytes(), "writing part header to")?;
+            parts.push(PartInfo{in_offset: self.pos, out_offset, part_size});
+            self.sink.write_all(&self.part_buf)
+                .map_err(|e| Error::io("writing part payload to", &self.name, Some(self.pos), e))?;
+            self.pos += u64::from(part_size);
+        }
+        Ok(SliceInfo{in_offset, parts_count, parts})
+    }
+
+    /// Writes each of `slices` (see [`write_slice`](Self::write_slice)).
+    pub fn write_slices<R: Read + Seek>(&mut self, media: &mut R, slices: &[Vec<(u32, u32)>]) -> Res<Vec<SliceInfo>> {
+        slices.iter()
+            .map(|reads| self.write_slice(media, reads))
+            .collect()
+    }
+
+    /// Writes `bytes` as they are, e.g. trailing bytes after the last slice.
+    pub fn write_raw(&mut self, bytes: &[u8]) -> Res<()> {
+        self.write_all(bytes, "writing raw bytes to")
+    }
+
+    /// Flushes and returns the sink.
+    pub fn finish(mut self) -> Res<W> {
+        self.sink.flush()
+            .map_err(|e| Error::io("flushing", &self.name, Some(self.pos), e))?;
+        Ok(self.sink)
+    }
+
+    fn write_all(&mut self, bytes: &[u8], op: &'static str) -> Res<()> {
+        self.sink.write_all(bytes)
+            .map_err(|e| Error::io(op, &self.name, Some(self.pos), e))?;
+        self.pos += bytes.len() as u64;
+        Ok(())
+    }
+}
@@ -0,0 +1,245 @@
+/*
+    This file is a part of telegram-media-deserialize.
+
+    Copyright (C) 2022 Apple Sheeple <AppleSheeple at github>
+
+    telegram-media-deserialize is free software: you can
+    redistribute it and/or modify it under the terms of
+    the Affero GNU General Public License as published by
+    the Free Software Foundation.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+    Affero GNU General Public License for more details.
+
+    You should have received a copy of the Affero GNU General Public License
+    along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+
+//! Simulation of Telegram Desktop's streaming cache, to build realistic test fixtures
+//! from any media file, without real Telegram data.
+//!
+//! A streaming player reads the start of the media, seeks forward to the index if it's at the
+//! end (an MP4 `moov` box after `mdat`, or Matroska `Cues` after the clusters), then comes back
+//! and plays the media sequentially. All reads are done in [`PART_SIZE`] parts, and stored in
+//! slices of [`SLICE_SIZE`] bytes:
+//!
+//! * The first cache file is serialized. It holds a header slice, with the parts read before
+//!   playback (including the forward seek), followed by the rest of the first slice, in the
+//!   order it was played.
+//! * Later slices are stored as raw cache files.
+//!
+//! This mirrors the layout of real cache files, not Telegram Desktop's exact loading logic.
+
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+
+use crate::container::{self, CLUSTER, CUES};
+use crate::{Coverage, Error, Res, Serializer, SliceInfo, PART_SIZE, SLICE_SIZE};
+
+/// Replays the reads of a streaming player over a media stream.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PlayerSimulator {
+    len: u32,
+    index: Option<Range<u64>>,
+    header_parts: u32,
+    played: u64,
+    part_size: u32,
+    slice_size: u64,
+}
+
+impl PlayerSimulator {
+    /// Simulates playing a media stream of `len` bytes to its end, without a forward seek.
+    pub fn new(len: u32) -> Self {
+        Self{len, index: None, header_parts: 1, played: len.into(), part_size: PART_SIZE, slice_size: SLICE_SIZE}
+    }
+
+    /// Simulates playing `media` to its end, seeking forward to its index first if it's at the
+    /// end (see [`index_range`]).
+    pub fn from_media<R: Read + Seek>(media: &mut R) -> Res<Self> {
+        let len = media.seek(SeekFrom::End(0))
+            .map_err(|e| Error::io("getting length of", "media stream", None, e))?;
+        let len = u32::try_from(len)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "offsets of media larger than 4GiB can't be serialized"))
+            .map_err(|e| Error::io("simulating playback of", "media stream", None, e))?;
+        let index = index_range(media)
+            .map_err(|e| Error::io("reading", "media stream", None, e))?;
+        Ok(Self{index, ..Self::new(len)})
+    }
+
+    pub fn len(&self) -> u32 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Range the player seeks forward to, before coming back to play the media.
+    pub fn index(&self) -> Option<Range<u64>> {
+        self.index.clone()
+    }
+
+    pub fn set_index(&mut self, index: Option<Range<u64>>) {
+        self.index = index;
+    }
+
+    /// Number of parts read from the start of the media before seeking to the index (default: 1).
+    pub fn set_header_parts(&mut self, header_parts: u32) {
+        self.header_parts = header_parts;
+    }
+
+    /// Stop playing after the first `played` bytes (default: all of them), e.g. to simulate
+    /// a video that was only partly watched.
+    pub fn set_played(&mut self, played: u64) {
+        self.played = played;
+    }
+
+    pub fn set_part_size(&mut self, part_size: u32) {
+        self.part_size = part_size.max(1);
+    }
+
+    pub fn set_slice_size(&mut self, slice_size: u64) {
+        self.slice_size = slice_size.max(1);
+    }
+
+    /// The `(out_offset, part_size)` reads of the player, in order, and how many of them
+    /// were read before playback started.
+    fn reads_and_header_len(&self) -> (Vec<(u32, u32)>, usize) {
+        let part_size = u64::from(self.part_size);
+        let len = u64::from(self.len);
+        let parts_count = len.div_ceil(part_size);
+        let mut read = vec![false; parts_count as usize];
+        let mut reads = Vec::new();
+        let mut read_parts = |parts: Range<u64>, reads: &mut Vec<(u32, u32)>| {
+            for i in parts.start..parts.end.min(parts_count) {
+                if !std::mem::replace(&mut read[i as usize], true) {
+                    let out_offset = i * part_size;
+                    let size = (len - out_offset).min(part_size);
+                    reads.push((out_offset as u32, size as u32));
+                }
+            }
+        };
+
+        read_parts(0..self.header_parts.into(), &mut reads);
+        if let Some(index) = self.index.as_ref().filter(|index| !index.is_empty()) {
+            read_parts(index.start / part_size..index.end.div_ceil(part_size), &mut reads);
+        }
+        let header_len = reads.len();
+        read_parts(0..self.played.div_ceil(part_size), &mut reads);
+        (reads, header_len)
+    }
+
+    /// The `(out_offset, part_size)` reads of the player, in order.
+    pub fn reads(&self) -> Vec<(u32, u32)> {
+        self.reads_and_header_len().0
+    }
+
+    /// The cache files Telegram Desktop would have stored for the simulated reads.
+    pub fn cache(&self) -> SimulatedCache {
+        let (reads, header_len) = self.reads_and_header_len();
+        let (header, played) = reads.split_at(header_len);
+        let first_slice = played.iter()
+            .copied()
+            .filter(|&(out_offset, _)| u64::from(out_offset) < self.slice_size)
+            .collect::<Vec<_>>();
+        let serialized = [header.to_vec(), first_slice].into_iter()
+            .filter(|parts| !parts.is_empty())
+            .collect();
+
+        let len = u64::from(self.len);
+        let played_end = self.played.div_ceil(self.part_size.into())
+            .saturating_mul(self.part_size.into())
+            .min(len);
+        let raw_slices = (1..played_end.div_ceil(self.slice_size))
+            .map(|index| {
+                let start = index * self.slice_size;
+                (index, start..(start + self.slice_size).min(played_end))
+            })
+            .collect();
+
+        SimulatedCache{len, serialized, raw_slices}
+    }
+}
+
+/// Cache files of a simulated playback, see [`PlayerSimulator::cache`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SimulatedCache {
+    len: u64,
+    /// Slices of the serialized (first) cache file, as `(out_offset, part_size)` reads.
+    pub serialized: Vec<Vec<(u32, u32)>>,
+    /// Raw slice files, as their index, and their range of the media stream.
+    pub raw_slices: Vec<(u64, Range<u64>)>,
+}
+
+impl SimulatedCache {
+    /// Writes the serialized cache file, reading the payloads from `media`.
+    pub fn write_serialized<R, W>(&self, media: &mut R, serializer: &mut Serializer<W>) -> Res<Vec<SliceInfo>>
+    where
+        R: Read + Seek,
+        W: Write,
+    {
+        serializer.write_slices(media, &self.serialized)
+    }
+
+    /// Writes raw slice file `i` (of [`raw_slices`](Self::raw_slices)) to `sink`,
+    /// reading it from `media`.
+    pub fn write_raw_slice<R, W>(&self, media: &mut R, i: usize, sink: &mut W) -> Res<()>
+    where
+        R: Read + Seek,
+        W: Write,
+    {
+        let (_, range) = &self.raw_slices[i];
+        media.seek(SeekFrom::Start(range.start))
+            .map_err(|e| Error::io("seeking", "media stream", Some(range.start), e))?;
+        let len = range.end - range.start;
+        let copied = io::copy(&mut media.take(len), sink)
+            .map_err(|e| Error::io("copying raw slice from", "media stream", Some(range.start), e))?;
+        (copied == len)
+            .then_some(())
+            .ok_or_else(|| Error::io("copying raw slice from", "media stream", Some(range.start + copied),
+                io::ErrorKind::UnexpectedEof.into()))
+    }
+
+    /// Ranges of the media stream held by the cache files.
+    pub fn coverage(&self) -> Coverage {
+        let parts = self.serialized.iter()
+            .flatten()
+            .map(|&(out_offset, part_size)| u64::from(out_offset)..u64::from(out_offset) + u64::from(part_size));
+        Coverage::new(parts.chain(self.raw_slices.iter().map(|(_, range)| range.clone())), Some(self.len))
+    }
+}
+
+/// The index a streaming player would seek forward to before playing `media`: an MP4 `moov`
+/// box after the `mdat` box, or Matroska `Cues` after the first cluster.
+///
+/// Returns `None` if the index comes first, or if `media` is neither MP4 nor Matroska.
+pub fn index_range<R: Read + Seek>(media: &mut R) -> io::Result<Option<Range<u64>>> {
+    let len = media.seek(SeekFrom::End(0))?;
+
+    if let Some(boxes) = container::mp4_boxes(media, len)? {
+        let find = |kind: &[u8; 4]| boxes.iter().find(|b| &b.kind == kind);
+        return Ok(match (find(b"moov"), find(b"mdat")) {
+            (Some(moov), Some(mdat)) if moov.offset > mdat.offset => Some(moov.range(len)),
+            _ => None,
+        });
+    }
+
+    if let Some(matroska) = container::matroska(media, len)? {
+        let (Some(cues), Some(cluster)) = (matroska.position(CUES), matroska.position(CLUSTER)) else {
+            return Ok(None);
+        };
+        if cues < cluster {
+            return Ok(None);
+        }
+        let end = container::read_element(media, cues, len)?
+            .and_then(|cues| cues.end())
+            .unwrap_or(len)
+            .min(len);
+        return Ok(Some(cues..end));
+    }
+
+    Ok(None)
+}
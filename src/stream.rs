@@ -0,0 +1,135 @@
+/*
+    This file is a part of telegram-media-deserialize.
+
+    Copyright (C) 2022 Apple Sheeple <AppleSheeple at github>
+
+    telegram-media-deserialize is free software: you can
+    redistribute it and/or modify it under the terms of
+    the Affero GNU General Public License as published by
+    the Free Software Foundation.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+    Affero GNU General Public License for more details.
+
+    You should have received a copy of the Affero GNU General Public License
+    along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+
+use crate::{Error, OrderedPartInfos, PartInfo, Res, SerializedFile};
+
+/// How [`MediaStream`] reads from ranges not covered by any part.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum HolePolicy {
+    /// Read holes as zeros.
+    #[default]
+    Zero,
+    /// Fail with an [`io::Error`] wrapping an [`Error::Hole`].
+    Error,
+}
+
+/// A `Read + Seek` view of the deserialized media stream, served directly from
+/// the parts of a [`SerializedFile`], without writing anything.
+#[derive(Debug)]
+pub struct MediaStream<R = File> {
+    serialized_file: SerializedFile<R>,
+    ordered_info: OrderedPartInfos,
+    hole_policy: HolePolicy,
+    len: u64,
+    pos: u64,
+}
+
+impl<R: Read + Seek> MediaStream<R> {
+    /// Parses `serialized_file` (see [`SerializedFile::get_info`]) and wraps it.
+    pub fn new(mut serialized_file: SerializedFile<R>, hole_policy: HolePolicy) -> Res<Self> {
+        let ordered_info = serialized_file.get_info()?;
+        Ok(Self::from_info(serialized_file, ordered_info, hole_policy))
+    }
+
+    /// Wraps `serialized_file`, already parsed into `ordered_info`.
+    pub fn from_info(serialized_file: SerializedFile<R>, ordered_info: OrderedPartInfos, hole_policy: HolePolicy) -> Self {
+        let len = ordered_info.end_offset();
+        Self{serialized_file, ordered_info, hole_policy, len, pos: 0}
+    }
+
+    /// Stream length, the end of the last part by default.
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Sets the stream length, e.g. to the full media size if known. A hole is then
+    /// read after the last part.
+    pub fn set_len(&mut self, len: u64) {
+        self.len = len;
+    }
+
+    pub fn ordered_info(&self) -> &OrderedPartInfos {
+        &self.ordered_info
+    }
+
+    pub fn into_inner(self) -> SerializedFile<R> {
+        self.serialized_file
+    }
+
+    /// The part covering `pos`, or the start of the next part if `pos` is in a hole.
+    fn locate(&self, pos: u64) -> Result<PartInfo, u64> {
+        let parts = self.ordered_info.parts();
+        let next_i = parts.partition_point(|pi| u64::from(pi.out_offset) <= pos);
+        parts[..next_i].iter()
+            .rev()
+            .find(|pi| pi.out_end() > pos)
+            .copied()
+            .ok_or_else(|| parts.get(next_i).map(|pi| u64::from(pi.out_offset)).unwrap_or(u64::MAX))
+    }
+}
+
+impl<R: Read + Seek> Read for MediaStream<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos >= self.len || buf.is_empty() {
+            return Ok(0);
+        }
+        let max_len = buf.len().min(usize::try_from(self.len - self.pos).unwrap_or(usize::MAX));
+
+        let n = match self.locate(self.pos) {
+            Ok(pi) => {
+                let part_offset = self.pos - u64::from(pi.out_offset);
+                let n = max_len.min((pi.out_end() - self.pos) as usize);
+                self.serialized_file.read_exact_at(pi.in_offset + part_offset, &mut buf[..n])?;
+                n
+            },
+            Err(hole_end) => {
+                let hole_end = hole_end.min(self.len);
+                if self.hole_policy == HolePolicy::Error {
+                    return Err(io::Error::other(Error::Hole{offset: self.pos, end: hole_end}));
+                }
+                let n = max_len.min(usize::try_from(hole_end - self.pos).unwrap_or(usize::MAX));
+                buf[..n].fill(0);
+                n
+            },
+        };
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl<R: Read + Seek> Seek for MediaStream<R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let new_pos = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => self.len.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.pos.checked_add_signed(offset),
+        };
+        self.pos = new_pos.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput,
+                "invalid seek to a negative or overflowing position"))?;
+        Ok(self.pos)
+    }
+}
@@ -0,0 +1,293 @@
+/*
+    This file is a part of telegram-media-deserialize.
+
+    Copyright (C) 2022 Apple Sheeple <AppleSheeple at github>
+
+    telegram-media-deserialize is free software: you can
+    redistribute it and/or modify it under the terms of
+    the Affero GNU General Public License as published by
+    the Free Software Foundation.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+    Affero GNU General Public License for more details.
+
+    You should have received a copy of the Affero GNU General Public License
+    along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+
+//! Reading the local key from Telegram Desktop's `tdata` directory.
+//!
+//! The local key (used to encrypt `media_cache` files, among others) is stored encrypted
+//! in `tdata/key_datas` (or `key_<name>s` for a non-default data name). The file follows
+//! Telegram's `TDF$` container format:
+//!
+//! ```text
+//! "TDF$" | version (i32 LE) | data | md5(data | data_len (i32 LE) | version (i32 LE) | "TDF$")
+//! ```
+//!
+//! `data` is a `QDataStream` of three byte arrays (each a u32 BE length followed by the bytes):
+//! `salt`, `key_encrypted` and `info_encrypted`.
+//!
+//! The passcode key is derived with PBKDF2-HMAC-SHA512 (256 bytes):
+//!
+//! ```text
+//! password   = sha512(salt | passcode | salt)
+//! iterations = 1 if passcode is empty, 100000 otherwise
+//! ```
+//!
+//! `key_encrypted` is then decrypted with it, using AES-256-IGE (with the `oldmtp` key/IV
+//! derivation). The first 16 bytes of `key_encrypted` are a message key, which has to match
+//! the first 16 bytes of the SHA1 of the decrypted data. A mismatch means a wrong passcode.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use aes::Aes256;
+use aes::cipher::{BlockDecrypt, KeyInit};
+use aes::cipher::generic_array::GenericArray;
+use md5::Md5;
+use sha1::Sha1;
+use sha2::{Digest, Sha512};
+
+use crate::encrypted::KEY_SIZE;
+use crate::{EncryptionKey, Error, Res};
+
+const TDF_MAGIC: &[u8; 4] = b"TDF$";
+const STRONG_ITERATIONS_COUNT: u32 = 100_000;
+const MSG_KEY_SIZE: usize = 16;
+const BLOCK_SIZE: usize = 16;
+
+/// The parsed contents of a `key_datas` file.
+#[derive(Debug, Clone)]
+pub struct KeyData {
+    path: PathBuf,
+    salt: Vec<u8>,
+    key_encrypted: Vec<u8>,
+    info_encrypted: Vec<u8>,
+}
+
+impl KeyData {
+    /// Reads `key_<data_name>s` from the `tdata` directory `tdata_dir`.
+    ///
+    /// Like Telegram Desktop, the legacy `key_<data_name>0`/`key_<data_name>1`
+    /// files are tried (newest first) if the modern `s` suffixed file doesn't exist.
+    pub fn from_tdata(tdata_dir: &Path, data_name: &str) -> Res<Self> {
+        let base = format!("key_{data_name}");
+        let modern = tdata_dir.join(format!("{base}s"));
+        if modern.exists() {
+            return Self::from_path(&modern);
+        }
+
+        let mut legacy = ["0", "1"]
+            .iter()
+            .map(|suffix| tdata_dir.join(format!("{base}{suffix}")))
+            .filter(|path| path.exists())
+            .collect::<Vec<_>>();
+        legacy.sort_by_key(|path| std::cmp::Reverse(fs::metadata(path).and_then(|m| m.modified()).ok()));
+
+        let mut ret = Err(Error::InputMissing{path: modern});
+        for path in legacy {
+            ret = Self::from_path(&path);
+            if ret.is_ok() {
+                break;
+            }
+        }
+        ret
+    }
+
+    /// Reads a `key_datas`-style file at `path`.
+    pub fn from_path(path: &Path) -> Res<Self> {
+        path.exists()
+            .then_some(())
+            .ok_or_else(|| Error::InputMissing{path: path.to_owned()})?;
+
+        let file_bytes = fs::read(path)
+            .map_err(|e| Error::io("reading", path, None, e))?;
+
+        let corrupt = |reason| Error::CorruptKeyFile{path: path.to_owned(), reason};
+
+        let data = read_tdf(&file_bytes).map_err(corrupt)?;
+
+        let mut stream = QDataStream(data);
+        let salt = stream.read_byte_array().ok_or_else(|| corrupt("truncated salt"))?;
+        let key_encrypted = stream.read_byte_array().ok_or_else(|| corrupt("truncated encrypted key"))?;
+        let info_encrypted = stream.read_byte_array().ok_or_else(|| corrupt("truncated encrypted info"))?;
+
+        Ok(Self {
+            path: path.to_owned(),
+            salt: salt.to_vec(),
+            key_encrypted: key_encrypted.to_vec(),
+            info_encrypted: info_encrypted.to_vec(),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The encrypted accounts info (not used here).
+    pub fn info_encrypted(&self) -> &[u8] {
+        &self.info_encrypted
+    }
+
+    /// Derives the passcode key and decrypts the local key with it.
+    ///
+    /// `passcode` is the local passcode set in Telegram Desktop, if any.
+    pub fn local_key(&self, passcode: Option<&[u8]>) -> Res<EncryptionKey> {
+        let passcode_key = create_local_key(passcode.unwrap_or_default(), &self.salt);
+        let data = decrypt_local(&self.key_encrypted, &passcode_key)
+            .map_err(|e| match e {
+                DecryptError::Corrupt(reason) => Error::CorruptKeyFile{path: self.path.clone(), reason},
+                DecryptError::WrongKey => Error::WrongPasscode{path: self.path.clone()},
+            })?;
+
+        let key = data.get(..KEY_SIZE)
+            .and_then(|key| <[u8; KEY_SIZE]>::try_from(key).ok())
+            .ok_or_else(|| Error::CorruptKeyFile{path: self.path.clone(), reason: "decrypted key too short"})?;
+
+        Ok(EncryptionKey::new(key))
+    }
+}
+
+/// Checks the magic and md5 of a `TDF$` file, returning its data.
+fn read_tdf(file_bytes: &[u8]) -> Result<&[u8], &'static str> {
+    const MD5_SIZE: usize = 16;
+
+    if file_bytes.len() < TDF_MAGIC.len() + 4 + MD5_SIZE {
+        return Err("file too short");
+    }
+    let (magic, rest) = file_bytes.split_at(TDF_MAGIC.len());
+    if magic != TDF_MAGIC {
+        return Err("bad magic");
+    }
+    let (version, rest) = rest.split_at(4);
+    let (data, md5) = rest.split_at(rest.len() - MD5_SIZE);
+
+    let data_len = i32::try_from(data.len()).map_err(|_| "file too large")?;
+    let computed = Md5::new()
+        .chain_update(data)
+        .chain_update(data_len.to_le_bytes())
+        .chain_update(version)
+        .chain_update(TDF_MAGIC)
+        .finalize();
+    if computed.as_slice() != md5 {
+        return Err("md5 mismatch");
+    }
+    Ok(data)
+}
+
+struct QDataStream<'a>(&'a [u8]);
+
+impl<'a> QDataStream<'a> {
+    /// Reads a `QByteArray` (a null array is read as empty).
+    fn read_byte_array(&mut self) -> Option<&'a [u8]> {
+        let (len, rest) = self.0.split_first_chunk::<4>()?;
+        let len = u32::from_be_bytes(*len);
+        if len == u32::MAX {
+            self.0 = rest;
+            return Some(&[]);
+        }
+        let len = usize::try_from(len).ok()?;
+        (rest.len() >= len).then_some(())?;
+        let (ret, rest) = rest.split_at(len);
+        self.0 = rest;
+        Some(ret)
+    }
+}
+
+/// PBKDF2-HMAC-SHA512 passcode key derivation (`CreateLocalKey`).
+fn create_local_key(passcode: &[u8], salt: &[u8]) -> [u8; KEY_SIZE] {
+    let password = Sha512::new()
+        .chain_update(salt)
+        .chain_update(passcode)
+        .chain_update(salt)
+        .finalize();
+    let iterations = match passcode.is_empty() {
+        true => 1,
+        false => STRONG_ITERATIONS_COUNT,
+    };
+    let mut key = [0; KEY_SIZE];
+    pbkdf2::pbkdf2_hmac::<Sha512>(&password, salt, iterations, &mut key);
+    key
+}
+
+enum DecryptError {
+    Corrupt(&'static str),
+    WrongKey,
+}
+
+/// Decrypts data encrypted with `EncryptLocal`, returning it without its length prefix.
+fn decrypt_local(encrypted: &[u8], key: &[u8; KEY_SIZE]) -> Result<Vec<u8>, DecryptError> {
+    if encrypted.len() <= MSG_KEY_SIZE || !encrypted.len().is_multiple_of(BLOCK_SIZE) {
+        return Err(DecryptError::Corrupt("bad encrypted data size"));
+    }
+    let (msg_key, encrypted_data) = encrypted.split_at(MSG_KEY_SIZE);
+    let msg_key: &[u8; MSG_KEY_SIZE] = msg_key.try_into().expect("split at MSG_KEY_SIZE");
+
+    let (aes_key, aes_iv) = prepare_aes_oldmtp(key, msg_key);
+    let mut decrypted = encrypted_data.to_vec();
+    aes_ige_decrypt(&mut decrypted, &aes_key, &aes_iv);
+
+    if Sha1::digest(&decrypted)[..MSG_KEY_SIZE] != msg_key[..] {
+        return Err(DecryptError::WrongKey);
+    }
+
+    let full_len = decrypted.len();
+    let data_len = u32::from_le_bytes(decrypted[..4].try_into().expect("4 bytes")) as usize;
+    if data_len > full_len || data_len + BLOCK_SIZE <= full_len || data_len < 4 {
+        return Err(DecryptError::Corrupt("bad decrypted data length"));
+    }
+
+    decrypted.truncate(data_len);
+    decrypted.drain(..4);
+    Ok(decrypted)
+}
+
+/// MTProto v1 key/IV derivation (`prepareAES_oldmtp`), in the receiving direction.
+fn prepare_aes_oldmtp(auth_key: &[u8; KEY_SIZE], msg_key: &[u8; MSG_KEY_SIZE]) -> ([u8; 32], [u8; 32]) {
+    let x = 8;
+    let sha1 = |parts: &[&[u8]]| parts.iter()
+        .fold(Sha1::new(), |h, p| h.chain_update(p))
+        .finalize();
+
+    let sha1_a = sha1(&[msg_key, &auth_key[x..x + 32]]);
+    let sha1_b = sha1(&[&auth_key[x + 32..x + 48], msg_key, &auth_key[x + 48..x + 64]]);
+    let sha1_c = sha1(&[&auth_key[x + 64..x + 96], msg_key]);
+    let sha1_d = sha1(&[msg_key, &auth_key[x + 96..x + 128]]);
+
+    let mut aes_key = [0; 32];
+    aes_key[..8].copy_from_slice(&sha1_a[..8]);
+    aes_key[8..20].copy_from_slice(&sha1_b[8..20]);
+    aes_key[20..].copy_from_slice(&sha1_c[4..16]);
+
+    let mut aes_iv = [0; 32];
+    aes_iv[..12].copy_from_slice(&sha1_a[8..20]);
+    aes_iv[12..20].copy_from_slice(&sha1_b[..8]);
+    aes_iv[20..24].copy_from_slice(&sha1_c[16..20]);
+    aes_iv[24..].copy_from_slice(&sha1_d[..8]);
+
+    (aes_key, aes_iv)
+}
+
+/// AES-256-IGE in-place decryption. `data` must be a multiple of the block size.
+fn aes_ige_decrypt(data: &mut [u8], key: &[u8; 32], iv: &[u8; 32]) {
+    let cipher = Aes256::new(key.into());
+    let mut prev_ciphertext = [0; BLOCK_SIZE];
+    let mut prev_plaintext = [0; BLOCK_SIZE];
+    prev_ciphertext.copy_from_slice(&iv[..BLOCK_SIZE]);
+    prev_plaintext.copy_from_slice(&iv[BLOCK_SIZE..]);
+
+    for chunk in data.chunks_exact_mut(BLOCK_SIZE) {
+        let ciphertext: [u8; BLOCK_SIZE] = chunk.try_into().expect("exact chunk");
+        let mut block = GenericArray::from(ciphertext);
+        block.iter_mut().zip(prev_plaintext).for_each(|(b, p)| *b ^= p);
+        cipher.decrypt_block(&mut block);
+        block.iter_mut().zip(prev_ciphertext).for_each(|(b, c)| *b ^= c);
+        chunk.copy_from_slice(&block);
+        prev_ciphertext = ciphertext;
+        prev_plaintext.copy_from_slice(chunk);
+    }
+}
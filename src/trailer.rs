@@ -0,0 +1,148 @@
+/*
+    This file is a part of telegram-media-deserialize.
+
+    Copyright (C) 2022 Apple Sheeple <AppleSheeple at github>
+
+    telegram-media-deserialize is free software: you can
+    redistribute it and/or modify it under the terms of
+    the Affero GNU General Public License as published by
+    the Free Software Foundation.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+    Affero GNU General Public License for more details.
+
+    You should have received a copy of the Affero GNU General Public License
+    along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+
+//! The bytes left after the last parsed slice, and the header slice metadata.
+//!
+//! As far as we can tell from Telegram Desktop's streaming reader, the serialized file is the
+//! cache entry of the *header slice*: a parts map (the first slice), optionally followed by the
+//! parts map of the first 8MiB (the second slice). Some files end with the header slice
+//! metadata, the media size and a full-in-cache flag (see [`Trailer::HeaderSlice`]). Others
+//! don't, Telegram Desktop knows the size from the document, and stores a media of up to 80
+//! parts in full in the header slice, so both are derived from the parts then, see
+//! [`HeaderSlice`]. This hasn't been checked against many real files, so unrecognized trailing
+//! bytes are kept as they are (see [`Trailer::Unknown`]).
+
+use crate::{ParserConfig, SliceInfo};
+
+/// Number of trailing bytes kept in [`Trailer::Unknown`].
+const TRAILER_HEAD_LEN: usize = 16;
+
+/// Size of the header slice metadata: the media size and the flags, as little-endian `u32`s
+/// like the slice and part headers.
+const HEADER_SLICE_LEN: usize = 8;
+
+/// Maximum size of the padding the encryption adds, up to a block.
+const MAX_PADDING_LEN: usize = 15;
+
+/// Number of trailing bytes read to decode them.
+pub(crate) const TRAILER_READ_LEN: usize = HEADER_SLICE_LEN + MAX_PADDING_LEN;
+
+/// The header slice metadata flag set if the whole media is stored in the header slice.
+const FULL_IN_CACHE_FLAG: u32 = 1;
+
+/// The bytes left unparsed at the end of the serialized file.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum Trailer {
+    /// No bytes were left.
+    #[default]
+    None,
+    /// Up to 15 zero bytes that may be the padding added by the encryption (see
+    /// [`SerializedFile::set_padded`](crate::SerializedFile::set_padded)).
+    Padding { len: u64 },
+    /// The header slice metadata at a slice boundary (possibly followed by padding): the media
+    /// size, which can't end before the parts, then flags where only the full-in-cache bit may
+    /// be set, in which case the size is the end of the parts.
+    HeaderSlice(HeaderSlice),
+    /// A part count of zero at a slice boundary (possibly followed by padding), i.e. an empty
+    /// parts map (which Telegram Desktop's parser accepts).
+    EmptySlice,
+    /// `len` bytes in an unknown format, of which the first 16 (at most) are kept in `head`.
+    Unknown { len: u64, head: Vec<u8> },
+}
+
+impl Trailer {
+    /// Decodes the `len` trailing bytes starting with `head` (of [`TRAILER_READ_LEN`] bytes at
+    /// most), after parts ending at `parts_end`. `at_slice_boundary` is whether they start where
+    /// a slice header was expected, and `padded` whether the file may end with padding.
+    pub(crate) fn decode(len: u64, head: &[u8], at_slice_boundary: bool, padded: bool, parts_end: u64) -> Self {
+        // whether the bytes after the first `at` are all there is, but for padding
+        let ends_at = |at: usize| match head.get(at..) {
+            Some(_) if len == at as u64 => true,
+            Some(rest) => padded && len - at as u64 <= MAX_PADDING_LEN as u64 && rest.iter().all(|&b| b == 0),
+            None => false,
+        };
+
+        if len == 0 {
+            return Self::None;
+        }
+        if padded && ends_at(0) {
+            return Self::Padding{len};
+        }
+        if at_slice_boundary && ends_at(HEADER_SLICE_LEN) {
+            if let Some(header_slice) = HeaderSlice::decode(&head[..HEADER_SLICE_LEN], parts_end) {
+                return Self::HeaderSlice(header_slice);
+            }
+        }
+        if at_slice_boundary && ends_at(4) && head[..4] == [0; 4] {
+            return Self::EmptySlice;
+        }
+        Self::Unknown{len, head: head[..head.len().min(TRAILER_HEAD_LEN)].to_vec()}
+    }
+}
+
+/// Metadata of the header slice (the first slice of the serialized file), decoded from the
+/// trailing bytes (see [`Trailer::HeaderSlice`]), or else derived from its parts.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct HeaderSlice {
+    /// Whether the whole media is stored in the header slice. If not decoded, its parts are
+    /// contiguous from offset 0, all full-sized but the last one, which is shorter (i.e. the end
+    /// of the media).
+    ///
+    /// Without the metadata, a media whose size is a multiple of the part size has no shorter
+    /// last part, and is never detected as full in cache.
+    pub full_in_cache: bool,
+    /// Size of the media, if decoded, or else if it's full in cache.
+    pub media_size: Option<u64>,
+}
+
+impl HeaderSlice {
+    /// Decodes the header slice metadata `bytes`, if they're valid for parts ending at
+    /// `parts_end`.
+    fn decode(bytes: &[u8], parts_end: u64) -> Option<Self> {
+        let (media_size, flags) = bytes.split_first_chunk::<4>()?;
+        let media_size = u64::from(u32::from_le_bytes(*media_size));
+        let flags = u32::from_le_bytes(flags.try_into().ok()?);
+        let full_in_cache = flags == FULL_IN_CACHE_FLAG;
+        let valid = flags & !FULL_IN_CACHE_FLAG == 0
+            && media_size >= parts_end.max(1)
+            && (!full_in_cache || media_size == parts_end);
+        valid.then_some(Self{full_in_cache, media_size: Some(media_size)})
+    }
+
+    /// Derives the metadata of the header `slice`, if it was fully parsed.
+    pub(crate) fn from_slice(slice: &SliceInfo, config: &ParserConfig) -> Option<Self> {
+        if slice.parts.len() != slice.parts_count as usize {
+            return None;
+        }
+        let mut parts = slice.parts.clone();
+        parts.sort_by_key(|pi| pi.out_offset);
+        let (last, init) = parts.split_last()?;
+
+        let mut out_offset = 0;
+        for pi in init {
+            if u64::from(pi.out_offset) != out_offset || pi.part_size != config.part_size {
+                return Some(Self::default());
+            }
+            out_offset = pi.out_end();
+        }
+        let full_in_cache = u64::from(last.out_offset) == out_offset && last.part_size < config.part_size;
+        Some(Self{full_in_cache, media_size: full_in_cache.then(|| last.out_end())})
+    }
+}
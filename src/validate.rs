@@ -0,0 +1,222 @@
+/*
+    This file is a part of telegram-media-deserialize.
+
+    Copyright (C) 2022 Apple Sheeple <AppleSheeple at github>
+
+    telegram-media-deserialize is free software: you can
+    redistribute it and/or modify it under the terms of
+    the Affero GNU General Public License as published by
+    the Free Software Foundation.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+    Affero GNU General Public License for more details.
+
+    You should have received a copy of the Affero GNU General Public License
+    along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+
+//! Checks of parts that real caches don't have: parts not aligned to the part size, and parts
+//! overlapping with different content.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::{Read, Seek};
+use std::ops::Range;
+
+use crate::{Error, OrderedPartInfos, PartInfo, Res, SerializedFile};
+
+/// Maximum number of overlaps reported by [`SerializedFile::validate`].
+pub const MAX_OVERLAPS: usize = 1024;
+
+const COMPARE_BUF_SIZE: usize = 64 * 1024;
+
+/// Two parts covering the same bytes of the deserialized media stream.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Overlap {
+    /// The part that comes first in the serialized file.
+    pub first: PartInfo,
+    /// The part that comes later in the serialized file.
+    pub second: PartInfo,
+    /// The overlapping range of the deserialized media stream.
+    pub range: Range<u64>,
+    /// Whether both parts have the same bytes in `range`.
+    pub identical: bool,
+}
+
+/// Suspicious parts found by [`SerializedFile::validate`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Validation {
+    /// Parts whose `out_offset` isn't a multiple of the part size.
+    pub misaligned: Vec<PartInfo>,
+    /// Overlapping parts, ordered by the start of the overlap.
+    pub overlaps: Vec<Overlap>,
+    /// Whether more than [`MAX_OVERLAPS`] overlaps were found (only the first ones are kept).
+    pub more_overlaps: bool,
+}
+
+impl Validation {
+    /// Overlaps with different content.
+    pub fn conflicts(&self) -> impl Iterator<Item=&Overlap> {
+        self.overlaps.iter().filter(|o| !o.identical)
+    }
+
+    /// Whether nothing suspicious was found.
+    pub fn is_clean(&self) -> bool {
+        self.misaligned.is_empty() && self.overlaps.is_empty()
+    }
+}
+
+impl fmt::Display for Validation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for pi in &self.misaligned {
+            writeln!(f, "Misaligned part: {pi:?}")?;
+        }
+        for o in &self.overlaps {
+            let content = if o.identical { "identical" } else { "CONFLICT" };
+            writeln!(f, "Overlap at {}..{} ({content}): in_offset={} and in_offset={}",
+                o.range.start, o.range.end, o.first.in_offset, o.second.in_offset)?;
+        }
+        if self.more_overlaps {
+            writeln!(f, "More than {MAX_OVERLAPS} overlaps, the rest are not listed")?;
+        }
+        Ok(())
+    }
+}
+
+/// Which copy of the bytes covered by overlapping parts is used.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OverlapPolicy {
+    /// The part that comes later in the serialized file (i.e. the one written last by Telegram
+    /// Desktop).
+    #[default]
+    Last,
+    /// The part that comes first in the serialized file.
+    First,
+}
+
+impl OverlapPolicy {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Last => "last",
+            Self::First => "first",
+        }
+    }
+}
+
+impl<R: Read + Seek> SerializedFile<R> {
+    /// Checks the parts of `ordered_info` for misaligned offsets, and for overlaps, whose bytes
+    /// are compared.
+    pub fn validate(&mut self, ordered_info: &OrderedPartInfos) -> Res<Validation> {
+        let part_size = self.config().part_size;
+        let parts = ordered_info.parts();
+        let misaligned = parts.iter()
+            .filter(|pi| pi.out_offset % part_size != 0)
+            .copied()
+            .collect();
+
+        // parts are ordered by out_offset, so every part overlapping parts[i] (and starting
+        // after it) directly follows it
+        let mut pairs = Vec::new();
+        let mut more_overlaps = false;
+        'pairs: for (i, a) in parts.iter().enumerate() {
+            for b in parts[i + 1..].iter().take_while(|b| u64::from(b.out_offset) < a.out_end()) {
+                if pairs.len() == MAX_OVERLAPS {
+                    more_overlaps = true;
+                    break 'pairs;
+                }
+                let (first, second) = if a.in_offset <= b.in_offset { (*a, *b) } else { (*b, *a) };
+                pairs.push((first, second, u64::from(b.out_offset)..a.out_end().min(b.out_end())));
+            }
+        }
+
+        let buf_len = pairs.iter()
+            .map(|(_, _, range)| range.end - range.start)
+            .max()
+            .map_or(0, |len| len.min(COMPARE_BUF_SIZE as u64) as usize);
+        let mut bufs = (vec![0; buf_len], vec![0; buf_len]);
+        let mut overlaps = Vec::with_capacity(pairs.len());
+        for (first, second, range) in pairs {
+            let identical = self.same_bytes(&first, &second, range.clone(), &mut bufs)?;
+            overlaps.push(Overlap{first, second, range, identical});
+        }
+        overlaps.sort_by_key(|o| (o.range.start, o.range.end));
+        Ok(Validation{misaligned, overlaps, more_overlaps})
+    }
+
+    /// Whether parts `a` and `b` have the same bytes in `range` of the deserialized media stream,
+    /// read into `bufs` (of the same length).
+    fn same_bytes(&mut self, a: &PartInfo, b: &PartInfo, range: Range<u64>, bufs: &mut (Vec<u8>, Vec<u8>)) -> Res<bool> {
+        let (a_buf, b_buf) = bufs;
+        let mut pos = range.start;
+        while pos < range.end {
+            let n = (range.end - pos).min(a_buf.len() as u64) as usize;
+            for (pi, buf) in [(a, &mut *a_buf), (b, &mut *b_buf)] {
+                let in_offset = pi.in_offset + (pos - u64::from(pi.out_offset));
+                self.read_exact_at(in_offset, &mut buf[..n])
+                    .map_err(|e| Error::io("reading part from", self.name(), Some(in_offset), e))?;
+            }
+            if a_buf[..n] != b_buf[..n] {
+                return Ok(false);
+            }
+            pos += n as u64;
+        }
+        Ok(true)
+    }
+}
+
+impl OrderedPartInfos {
+    /// A copy with overlapping parts split into non-overlapping pieces, keeping the bytes of the
+    /// part chosen by `policy`, so that every writer uses the same copy.
+    ///
+    /// A short final part may be split too, so [`media_size`](Self::media_size) should be
+    /// inferred from the parts before.
+    pub fn with_overlap_policy(&self, policy: OverlapPolicy) -> Self {
+        let mut by_priority = self.parts().to_vec();
+        match policy {
+            OverlapPolicy::Last => by_priority.sort_by_key(|pi| std::cmp::Reverse(pi.in_offset)),
+            OverlapPolicy::First => by_priority.sort_by_key(|pi| pi.in_offset),
+        }
+
+        // claimed ranges, start => end, merged when adjacent
+        let mut claimed = BTreeMap::<u64, u64>::new();
+        let mut pieces = Vec::with_capacity(by_priority.len());
+        for pi in by_priority {
+            let (start, end) = (u64::from(pi.out_offset), pi.out_end());
+            let mut pos = start;
+            let mut merged = start..end;
+            let first_key = claimed.range(..=start).next_back()
+                .filter(|&(_, &claimed_end)| claimed_end >= start)
+                .map_or(start, |(&claimed_start, _)| claimed_start);
+            let overlapping = claimed.range(first_key..=end)
+                .map(|(&s, &e)| (s, e))
+                .collect::<Vec<_>>();
+            for (claimed_start, claimed_end) in overlapping {
+                if claimed_start > pos {
+                    pieces.push(piece(&pi, pos, claimed_start));
+                }
+                pos = pos.max(claimed_end);
+                merged = merged.start.min(claimed_start)..merged.end.max(claimed_end);
+                claimed.remove(&claimed_start);
+            }
+            if pos < end {
+                pieces.push(piece(&pi, pos, end));
+            }
+            claimed.insert(merged.start, merged.end);
+        }
+        self.with_parts(pieces)
+    }
+}
+
+/// The piece of `pi` covering `start..end` of the deserialized media stream.
+fn piece(pi: &PartInfo, start: u64, end: u64) -> PartInfo {
+    let skip = start - u64::from(pi.out_offset);
+    PartInfo {
+        in_offset: pi.in_offset + skip,
+        // within the part, so it fits
+        out_offset: start as u32,
+        part_size: (end - start) as u32,
+    }
+}
@@ -0,0 +1,203 @@
+/*
+    This file is a part of telegram-media-deserialize.
+
+    Copyright (C) 2022 Apple Sheeple <AppleSheeple at github>
+
+    telegram-media-deserialize is free software: you can
+    redistribute it and/or modify it under the terms of
+    the Affero GNU General Public License as published by
+    the Free Software Foundation.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+    Affero GNU General Public License for more details.
+
+    You should have received a copy of the Affero GNU General Public License
+    along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+
+use std::fs::File;
+use std::io::{self, ErrorKind, Read, Write, Seek, SeekFrom};
+
+/// Size of the single buffer used to copy data from the input files.
+pub(crate) const COPY_BUF_SIZE: usize = 128 * 1024;
+
+/// A sink for the deserialized media stream, written with positioned writes.
+///
+/// Implemented for [`File`] and [`DeserializedFile`](crate::DeserializedFile) using `pwrite` (and `copy_file_range`
+/// on Linux when the input is a plain file too). Any other `Write + Seek` sink can be
+/// used by wrapping it in a [`SeekWriter`].
+pub trait WriteAt {
+    /// Writes all of `buf` at `offset`.
+    fn write_all_at(&mut self, buf: &[u8], offset: u64) -> io::Result<()>;
+
+    /// The underlying file, if data can be copied to it without going through userspace.
+    fn as_file(&self) -> Option<&File> {
+        None
+    }
+}
+
+impl WriteAt for File {
+    #[cfg(unix)]
+    fn write_all_at(&mut self, buf: &[u8], offset: u64) -> io::Result<()> {
+        std::os::unix::fs::FileExt::write_all_at(self, buf, offset)
+    }
+
+    #[cfg(not(unix))]
+    fn write_all_at(&mut self, buf: &[u8], offset: u64) -> io::Result<()> {
+        self.seek(SeekFrom::Start(offset))?;
+        self.write_all(buf)
+    }
+
+    fn as_file(&self) -> Option<&File> {
+        Some(self)
+    }
+}
+
+/// Adapts any `Write + Seek` sink (e.g. an [`io::Cursor`]) to [`WriteAt`],
+/// by seeking before every write.
+#[derive(Debug, Default)]
+pub struct SeekWriter<W>(pub W);
+
+impl<W: Write + Seek> WriteAt for SeekWriter<W> {
+    fn write_all_at(&mut self, buf: &[u8], offset: u64) -> io::Result<()> {
+        self.0.seek(SeekFrom::Start(offset))?;
+        self.0.write_all(buf)
+    }
+}
+
+/// Why a copy from an input file failed.
+pub(crate) enum CopyError {
+    /// Reading (or seeking) the input failed after `copied` bytes.
+    Read { copied: u64, source: io::Error },
+    /// Writing to the sink failed after `copied` bytes.
+    Write { copied: u64, source: io::Error },
+}
+
+/// Reads `len` bytes at `in_offset` of `src` through `buf`, handing each chunk to `f`.
+///
+/// `pos` is the known position of `src` (if any), so that sequential reads don't need a seek.
+/// Returns the number of bytes read, which is less than `len` only if `src` ended early.
+pub(crate) fn read_chunks<R, F>(src: &mut R, pos: &mut Option<u64>, in_offset: u64, len: u64,
+    buf: &mut [u8], mut f: F) -> Result<u64, CopyError>
+where
+    R: Read + Seek,
+    F: FnMut(&[u8], u64) -> io::Result<()>,
+{
+    if *pos != Some(in_offset) {
+        *pos = None;
+        src.seek(SeekFrom::Start(in_offset))
+            .map_err(|source| CopyError::Read{copied: 0, source})?;
+        *pos = Some(in_offset);
+    }
+
+    let mut copied = 0;
+    while copied < len {
+        let n = (len - copied).min(buf.len() as u64) as usize;
+        match src.read(&mut buf[..n]) {
+            Ok(0) => break,
+            Ok(n) => {
+                *pos = pos.map(|pos| pos + n as u64);
+                f(&buf[..n], copied)
+                    .map_err(|source| CopyError::Write{copied, source})?;
+                copied += n as u64;
+            },
+            Err(e) if e.kind() == ErrorKind::Interrupted => (),
+            Err(source) => {
+                *pos = None;
+                return Err(CopyError::Read{copied, source});
+            },
+        }
+    }
+    Ok(copied)
+}
+
+/// Hands (up to) `len` bytes at `in_offset` of `data` (e.g. a memory mapping) to `f` at once.
+///
+/// Returns the number of bytes handed, which is less than `len` only if `data` ends early.
+pub(crate) fn write_from_slice<F>(data: &[u8], in_offset: u64, len: u64, f: F) -> Result<u64, CopyError>
+where
+    F: FnOnce(&[u8]) -> io::Result<()>,
+{
+    let bytes = sub_slice(data, in_offset, len).unwrap_or_default();
+    f(bytes)
+        .map_err(|source| CopyError::Write{copied: 0, source})?;
+    Ok(bytes.len() as u64)
+}
+
+/// Up to `len` bytes of `data` at `offset`, `None` if `offset` is past its end.
+pub(crate) fn sub_slice(data: &[u8], offset: u64, len: u64) -> Option<&[u8]> {
+    let start = usize::try_from(offset).ok().filter(|&start| start <= data.len())?;
+    let len = usize::try_from(len).unwrap_or(usize::MAX).min(data.len() - start);
+    Some(&data[start..start + len])
+}
+
+/// Copies `len` bytes at `in_offset` of `src` to `out_offset` in `sink`.
+///
+/// If `direct` is `src` as a plain file, and `sink` is a file too, the data is copied
+/// in-kernel where supported. Otherwise it's read sequentially through `buf` (see
+/// [`read_chunks`]) and written with positioned writes.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn copy_at<R, W>(src: &mut R, direct: Option<&File>, pos: &mut Option<u64>, in_offset: u64,
+    len: u64, sink: &mut W, out_offset: u64, buf: &mut [u8]) -> Result<u64, CopyError>
+where
+    R: Read + Seek,
+    W: WriteAt,
+{
+    if let (Some(src_file), Some(sink_file)) = (direct, sink.as_file()) {
+        if let Some(copied) = copy_file_range(src_file, in_offset, sink_file, out_offset, len)? {
+            return Ok(copied);
+        }
+    }
+    read_chunks(src, pos, in_offset, len, buf, |chunk, copied| sink.write_all_at(chunk, out_offset + copied))
+}
+
+/// Copies `len` bytes in-kernel with `copy_file_range(2)`, without using the file positions.
+///
+/// Returns `None` if nothing was copied because the files don't support it (e.g. old kernels,
+/// or some filesystems), so the caller can fall back to a userspace copy.
+#[cfg(target_os = "linux")]
+fn copy_file_range(src: &File, in_offset: u64, sink: &File, out_offset: u64, len: u64)
+    -> Result<Option<u64>, CopyError>
+{
+    use std::os::unix::io::AsRawFd;
+
+    let (Ok(mut off_in), Ok(mut off_out)) = (i64::try_from(in_offset), i64::try_from(out_offset)) else {
+        return Ok(None);
+    };
+    let mut copied = 0;
+    while copied < len {
+        let n = (len - copied).min(1 << 30) as usize;
+        // SAFETY: both fds are valid for the duration of the call, and the offset
+        // pointers point to live i64 values.
+        let ret = unsafe {
+            libc::copy_file_range(src.as_raw_fd(), &mut off_in, sink.as_raw_fd(), &mut off_out, n, 0)
+        };
+        if ret < 0 {
+            let e = io::Error::last_os_error();
+            if e.kind() == ErrorKind::Interrupted {
+                continue;
+            }
+            return match e.raw_os_error() {
+                Some(libc::ENOSYS | libc::EXDEV | libc::EINVAL | libc::EOPNOTSUPP | libc::EBADF | libc::EPERM)
+                    if copied == 0 => Ok(None),
+                Some(libc::ENOSPC | libc::EFBIG | libc::EDQUOT) => Err(CopyError::Write{copied, source: e}),
+                _ => Err(CopyError::Read{copied, source: e}),
+            };
+        }
+        if ret == 0 {
+            break;
+        }
+        copied += ret as u64;
+    }
+    Ok(Some(copied))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn copy_file_range(_src: &File, _in_offset: u64, _sink: &File, _out_offset: u64, _len: u64)
+    -> Result<Option<u64>, CopyError>
+{
+    Ok(None)
+}
@@ -0,0 +1,223 @@
+/*
+    This file is a part of telegram-media-deserialize.
+
+    Copyright (C) 2022 Apple Sheeple <AppleSheeple at github>
+
+    telegram-media-deserialize is free software: you can
+    redistribute it and/or modify it under the terms of
+    the Affero GNU General Public License as published by
+    the Free Software Foundation.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+    Affero GNU General Public License for more details.
+
+    You should have received a copy of the Affero GNU General Public License
+    along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+
+//! The records of a `media_cache` binlog are applied in order, and slices of the same media are
+//! found through consecutive keys.
+
+mod common;
+
+use std::fs;
+use std::path::Path;
+
+use telegram_media_deserialize::binlog::{AccessTime, Binlog, CacheEntry, CacheKey};
+use telegram_media_deserialize::{EncryptionKey, Error};
+
+use common::{encrypt, local_key, temp_dir};
+
+const STORE: u8 = 0x01;
+const MULTI_STORE: u8 = 0x02;
+const MULTI_REMOVE: u8 = 0x03;
+const MULTI_ACCESS: u8 = 0x04;
+
+fn key(high: u64, low: u64) -> CacheKey {
+    CacheKey{high, low}
+}
+
+fn key_bytes(key: CacheKey) -> Vec<u8> {
+    [key.high.to_le_bytes(), key.low.to_le_bytes()].concat()
+}
+
+fn time_bytes(time: AccessTime) -> Vec<u8> {
+    [&(time.relative as u32).to_le_bytes()[..], &((time.relative >> 32) as u32).to_le_bytes(), &time.system.to_le_bytes()]
+        .concat()
+}
+
+/// The binlog header, with the *track estimated time* flag if `with_time`.
+fn header(with_time: bool) -> Vec<u8> {
+    let mut header = vec![0; 16];
+    header[1] = u8::from(with_time);
+    header
+}
+
+/// A Store record, on its own or as an item of a MultiStore record.
+fn store(key: CacheKey, tag: u8, size: u32, place: [u8; 7], time: Option<AccessTime>) -> Vec<u8> {
+    let mut record = vec![STORE, tag];
+    record.extend(&size.to_le_bytes()[..3]);
+    record.extend(place);
+    record.extend(0xdead_beefu32.to_le_bytes());
+    record.extend(key_bytes(key));
+    if let Some(time) = time {
+        record.extend(time_bytes(time));
+        record.extend([0; 4]);
+    }
+    record
+}
+
+/// A multi record of `kind`, with 12 bytes of `header` data, then `items`.
+fn multi(kind: u8, header: &[u8], items: &[Vec<u8>]) -> Vec<u8> {
+    let mut record = vec![kind];
+    record.extend(&(items.len() as u32).to_le_bytes()[..3]);
+    record.extend(header);
+    record.resize(16, 0);
+    record.extend(items.concat());
+    record
+}
+
+/// Writes the encrypted binlog `records` to the database directory `dir`, and reads it back.
+fn read(dir: &Path, records: &[Vec<u8>]) -> Result<Binlog, Error> {
+    let key = local_key(21);
+    fs::create_dir_all(dir).expect("creating database dir");
+    fs::write(dir.join("binlog"), encrypt(&key, 22, &records.concat())).expect("writing binlog");
+    Binlog::from_version_dir(dir, &EncryptionKey::new(key))
+}
+
+fn place(first: u8) -> [u8; 7] {
+    [first, 0xAB, 0x01, 0x23, 0x45, 0x67, 0x89]
+}
+
+fn sizes(binlog: &Binlog) -> Vec<(CacheKey, u32)> {
+    binlog.entries().map(|e| (e.key, e.size)).collect()
+}
+
+#[test]
+fn store_records() {
+    let dir = temp_dir("binlog-store");
+    let records = [
+        header(false),
+        store(key(1, 5), 7, 0x12_3456, place(0x12), None),
+        store(key(1, 6), 7, 1000, place(0xF0), None),
+        // a later record replaces the entry
+        store(key(1, 5), 8, 2000, place(0x12), None),
+    ];
+    let binlog = read(&dir, &records).expect("reading");
+    assert_eq!(sizes(&binlog), [(key(1, 5), 2000), (key(1, 6), 1000)]);
+
+    // each place byte is written low nibble first
+    let path = dir.join("21").join("BA1032547698");
+    assert_eq!(binlog.get(&key(1, 5)), Some(&CacheEntry{key: key(1, 5), path, size: 2000, tag: 8,
+        checksum: 0xdead_beef, access_time: None}));
+    assert_eq!(binlog.get(&key(1, 6)).expect("an entry").path, dir.join("0F").join("BA1032547698"));
+    assert_eq!(binlog.find_by_path(&dir.join("21/BA1032547698")).map(|e| e.key), Some(key(1, 5)));
+    assert_eq!(binlog.find_by_path(&dir.join("21/BA1032547699")), None);
+
+    // 3 bytes sizes
+    let binlog = read(&dir, &[header(false), store(key(1, 5), 7, 0x12_3456, place(0), None)]).expect("reading");
+    assert_eq!(sizes(&binlog), [(key(1, 5), 0x12_3456)]);
+
+    fs::remove_dir_all(dir).expect("removing temp dir");
+}
+
+#[test]
+fn multi_records_with_time() {
+    let dir = temp_dir("binlog-multi");
+    let stored = AccessTime{relative: 0x1_0000_0002, system: 1_670_000_000};
+    let accessed = AccessTime{relative: 0x3_0000_0004, system: 1_670_000_100};
+    let items = [0, 1, 3].map(|low| store(key(7, low), 1, 100 + low as u32, place(low as u8), Some(stored)));
+    let records = [
+        header(true),
+        multi(MULTI_STORE, &[], &items),
+        store(key(7, 4), 1, 104, place(4), Some(stored)),
+        multi(MULTI_REMOVE, &[], &[key_bytes(key(7, 1)), key_bytes(key(9, 9))]),
+        multi(MULTI_ACCESS, &time_bytes(accessed), &[key_bytes(key(7, 0)), key_bytes(key(7, 4)), key_bytes(key(7, 1))]),
+    ];
+    let binlog = read(&dir, &records).expect("reading");
+    assert_eq!(sizes(&binlog), [(key(7, 0), 100), (key(7, 3), 103), (key(7, 4), 104)]);
+    let times = binlog.entries().map(|e| e.access_time).collect::<Vec<_>>();
+    assert_eq!(times, [Some(accessed), Some(stored), Some(accessed)]);
+
+    // without the flag, the same records are misread, and reading stops at an unknown type
+    let binlog = read(&dir, &[header(false), store(key(7, 4), 1, 104, place(4), Some(stored))]).expect("reading");
+    assert_eq!(sizes(&binlog), [(key(7, 4), 104)]);
+    assert_eq!(binlog.get(&key(7, 4)).expect("an entry").access_time, None);
+
+    fs::remove_dir_all(dir).expect("removing temp dir");
+}
+
+#[test]
+fn truncated_records() {
+    let dir = temp_dir("binlog-truncated");
+    let complete = store(key(1, 1), 1, 10, place(1), None);
+    let next = store(key(1, 2), 1, 20, place(2), None);
+
+    // a truncated final record (e.g. the binlog was being written) is ignored, records are
+    // a multiple of the block size, so they are cut at a block boundary
+    let binlog = read(&dir, &[header(false), complete.clone(), next[..16].to_vec()]).expect("reading");
+    assert_eq!(sizes(&binlog), [(key(1, 1), 10)]);
+    let multi_store = multi(MULTI_STORE, &[], &[next.clone(), next.clone()]);
+    let binlog = read(&dir, &[header(false), complete.clone(), multi_store[..48].to_vec()]).expect("reading");
+    assert_eq!(sizes(&binlog), [(key(1, 1), 10)]);
+    let multi_remove = multi(MULTI_REMOVE, &[], &[key_bytes(key(1, 1)), key_bytes(key(1, 1))]);
+    let binlog = read(&dir, &[header(false), complete.clone(), multi_remove[..32].to_vec()]).expect("reading");
+    assert_eq!(sizes(&binlog), [(key(1, 1), 10)]);
+
+    // an unknown record type ends the binlog
+    let binlog = read(&dir, &[header(false), complete.clone(), vec![0x7f; 32], next]).expect("reading");
+    assert_eq!(sizes(&binlog), [(key(1, 1), 10)]);
+
+    // a truncated header is an error
+    let res = read(&dir, &[]);
+    assert!(matches!(res, Err(Error::CorruptBinlog{offset: 0, reason: "truncated header", ..})));
+
+    fs::remove_dir_all(dir).expect("removing temp dir");
+}
+
+#[test]
+fn slices() {
+    let dir = temp_dir("binlog-slices");
+    let keys = [key(1, 10), key(1, 11), key(1, 12), key(1, 14), key(2, 11), key(2, 12), key(1, u64::MAX)];
+    let mut records = vec![header(false)];
+    records.extend(keys.iter().enumerate().map(|(i, &k)| store(k, 1, i as u32, place(i as u8), None)));
+    let binlog = read(&dir, &records).expect("reading");
+
+    let slices = |first, max| binlog.slices(first, max).into_iter()
+        .map(|(index, entry)| (index, entry.key))
+        .collect::<Vec<_>>();
+    // a missing key is a missing slice, and neither the other media nor keys further than
+    // `max_slices` are included
+    assert_eq!(slices(key(1, 10), 1024), [(0, key(1, 10)), (1, key(1, 11)), (2, key(1, 12)), (4, key(1, 14))]);
+    assert_eq!(slices(key(1, 10), u64::MAX).len(), 5);
+    assert_eq!(slices(key(1, 10), 2), [(0, key(1, 10)), (1, key(1, 11)), (2, key(1, 12))]);
+    assert_eq!(slices(key(2, 11), 1024), [(0, key(2, 11)), (1, key(2, 12))]);
+    assert_eq!(slices(key(2, 12), 1024), [(0, key(2, 12))]);
+    assert_eq!(slices(key(2, 13), 1024), []);
+
+    let first = binlog.first_slices().map(|e| e.key).collect::<Vec<_>>();
+    assert_eq!(first, [key(1, 10), key(1, 14), key(1, u64::MAX), key(2, 11)]);
+
+    fs::remove_dir_all(dir).expect("removing temp dir");
+}
+
+#[test]
+fn version_file() {
+    let base = temp_dir("binlog-version");
+    let local_key = EncryptionKey::new(local_key(21));
+    read(&base.join("12"), &[header(false), store(key(3, 0), 1, 10, place(1), None)]).expect("reading");
+
+    let res = Binlog::from_media_cache(&base, &local_key);
+    assert!(matches!(res, Err(Error::Io{..})));
+    fs::write(base.join("version"), 12i32.to_le_bytes()).expect("writing version");
+    let binlog = Binlog::from_media_cache(&base, &local_key).expect("reading");
+    assert_eq!(binlog.entries().map(|e| e.path.clone()).collect::<Vec<_>>(), [base.join("12/10/BA1032547698")]);
+    fs::write(base.join("version"), b"12").expect("writing version");
+    let res = Binlog::from_media_cache(&base, &local_key);
+    assert!(matches!(res, Err(Error::CorruptBinlog{reason: "bad version file size", ..})));
+
+    fs::remove_dir_all(base).expect("removing temp dir");
+}
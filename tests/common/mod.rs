@@ -0,0 +1,134 @@
+/*
+    This file is a part of telegram-media-deserialize.
+
+    Copyright (C) 2022 Apple Sheeple <AppleSheeple at github>
+
+    telegram-media-deserialize is free software: you can
+    redistribute it and/or modify it under the terms of
+    the Affero GNU General Public License as published by
+    the Free Software Foundation.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+    Affero GNU General Public License for more details.
+
+    You should have received a copy of the Affero GNU General Public License
+    along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+
+//! Fixture helpers shared by the tests.
+
+#![allow(dead_code)]
+
+use std::fs;
+use std::io::Cursor;
+use std::path::PathBuf;
+
+use aes::Aes256;
+use aes::cipher::{BlockEncrypt, KeyInit};
+use aes::cipher::generic_array::GenericArray;
+use sha2::{Digest, Sha256};
+
+use telegram_media_deserialize::{HolePolicy, MediaStream, SerializedFile, Serializer, PART_SIZE};
+use telegram_media_deserialize::encrypted::KEY_SIZE;
+
+/// `len` bytes of pseudo-random data, seeded by `seed`.
+pub fn payload(len: usize, seed: u64) -> Vec<u8> {
+    let mut state = seed | 1;
+    (0..len).map(|_| {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        state as u8
+    }).collect()
+}
+
+/// A media stream of `len` bytes, without repeating patterns, so misplaced bytes are noticed.
+pub fn media(len: u32) -> Vec<u8> {
+    payload(len as usize, 0x9e37_79b9_7f4a_7c15)
+}
+
+/// `(out_offset, part_size)` reads of `PART_SIZE` parts covering `start..end`.
+pub fn reads(start: u32, end: u32) -> Vec<(u32, u32)> {
+    (start..end).step_by(PART_SIZE as usize)
+        .map(|out_offset| (out_offset, (end - out_offset).min(PART_SIZE)))
+        .collect()
+}
+
+/// `(out_offset, part_size)` reads of the `PART_SIZE` parts of a `len` bytes media stream with
+/// indices in `parts`.
+pub fn part_reads(len: usize, parts: &[u32]) -> Vec<(u32, u32)> {
+    parts.iter()
+        .map(|&i| (i * PART_SIZE, (len as u32 - i * PART_SIZE).min(PART_SIZE)))
+        .collect()
+}
+
+/// Serializes `slices` of `media` in memory, followed by `trailer`.
+pub fn serialize(media: &[u8], slices: &[Vec<(u32, u32)>], trailer: &[u8]) -> Vec<u8> {
+    let mut serializer = Serializer::from_writer("serialized".into(), Vec::new());
+    serializer.write_slices(&mut Cursor::new(media), slices).expect("serializing");
+    serializer.write_raw(trailer).expect("writing trailer");
+    serializer.finish().expect("finishing")
+}
+
+pub fn open(data: Vec<u8>) -> SerializedFile<Cursor<Vec<u8>>> {
+    SerializedFile::from_reader("serialized".into(), Cursor::new(data)).expect("opening")
+}
+
+/// The `PART_SIZE` parts of `media` with indices in `parts`, in one slice, as a media stream.
+pub fn parse(media: &[u8], parts: &[u32]) -> MediaStream<Cursor<Vec<u8>>> {
+    let data = serialize(media, &[part_reads(media.len(), parts)], &[]);
+    MediaStream::new(open(data), HolePolicy::Error).expect("parsing")
+}
+
+/// An MP4 box of `len` bytes (including the header), with filler data.
+pub fn mp4_box(kind: &[u8; 4], len: usize) -> Vec<u8> {
+    let mut b = (len as u32).to_be_bytes().to_vec();
+    b.extend_from_slice(kind);
+    b.resize(len, 0x55);
+    b
+}
+
+/// An empty directory for the test `name`, in the system temp directory.
+pub fn temp_dir(name: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("tmd-{name}-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&path);
+    fs::create_dir_all(&path).expect("creating temp dir");
+    path
+}
+
+/// A local key, seeded by `seed`.
+pub fn local_key(seed: u64) -> [u8; KEY_SIZE] {
+    payload(KEY_SIZE, seed).try_into().expect("key size")
+}
+
+/// Encrypts `payload` like `Storage::EncryptedFile` does, with a salt seeded by `seed`: the salt,
+/// then the 48 bytes `header` and the payload, padded to the AES block size, in one AES-256-CTR
+/// stream.
+pub fn encrypt_with_header(key: &[u8; KEY_SIZE], seed: u64, header: &[u8; 48], payload: &[u8]) -> Vec<u8> {
+    let salt = self::payload(64, seed);
+    let aes_key = Sha256::new().chain_update(&key[..128]).chain_update(&salt[..32]).finalize();
+    let iv = Sha256::new().chain_update(&key[128..]).chain_update(&salt[32..]).finalize();
+    let iv = u128::from_be_bytes(iv[..16].try_into().expect("IV size"));
+    let cipher = Aes256::new(&aes_key);
+
+    let mut data = [&header[..], payload].concat();
+    data.resize(data.len().next_multiple_of(16), 0);
+    for (i, block) in data.chunks_mut(16).enumerate() {
+        let mut counter = GenericArray::from(iv.wrapping_add(i as u128).to_be_bytes());
+        cipher.encrypt_block(&mut counter);
+        block.iter_mut().zip(counter).for_each(|(b, k)| *b ^= k);
+    }
+    [salt, data].concat()
+}
+
+/// Encrypts `payload` with a valid header (see [`encrypt_with_header`]).
+pub fn encrypt(key: &[u8; KEY_SIZE], seed: u64, payload: &[u8]) -> Vec<u8> {
+    let mut header = [0; 48];
+    // application version, and a checksum that isn't verified
+    header[8..16].copy_from_slice(&4_005_000u64.to_le_bytes());
+    header[16..].fill(0xcc);
+    encrypt_with_header(key, seed, &header, payload)
+}
@@ -0,0 +1,99 @@
+/*
+    This file is a part of telegram-media-deserialize.
+
+    Copyright (C) 2022 Apple Sheeple <AppleSheeple at github>
+
+    telegram-media-deserialize is free software: you can
+    redistribute it and/or modify it under the terms of
+    the Affero GNU General Public License as published by
+    the Free Software Foundation.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+    Affero GNU General Public License for more details.
+
+    You should have received a copy of the Affero GNU General Public License
+    along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+
+//! Files from builds with other streaming constants are parsed with a matching `ParserConfig`.
+
+mod common;
+
+use std::io::Cursor;
+
+use telegram_media_deserialize::{Error, ParserConfig, SeekWriter, SerializedFile, StopReason, PART_SIZE};
+
+use common::{media, serialize};
+
+fn open(data: Vec<u8>, config: Option<ParserConfig>) -> SerializedFile<Cursor<Vec<u8>>> {
+    let mut serialized_file = common::open(data);
+    if let Some(config) = config {
+        serialized_file.set_config(config);
+    }
+    serialized_file
+}
+
+#[test]
+fn default_config() {
+    assert_eq!(ParserConfig::default(), ParserConfig::TDESKTOP_4);
+}
+
+#[test]
+fn larger_parts() {
+    let part_size = 2 * PART_SIZE;
+    let media = media(3 * part_size);
+    let reads = (0..3).map(|i| (i * part_size, part_size)).collect::<Vec<_>>();
+    let data = serialize(&media, &[reads], &[]);
+
+    let res = open(data.clone(), None).get_info();
+    assert!(matches!(res, Err(Error::PartSizeOutOfRange{part_size: p, max: 131072, ..}) if p == part_size));
+
+    let mut serialized_file = open(data, Some(ParserConfig{max_part_size: part_size, ..ParserConfig::default()}));
+    let info = serialized_file.get_info().expect("parsing");
+    assert_eq!(info.stop_reason(), StopReason::EndOfFile);
+    assert_eq!(info.parts().len(), 3);
+    let mut out = SeekWriter(Cursor::new(Vec::new()));
+    serialized_file.write_to(&info, &mut out).expect("extracting");
+    assert_eq!(out.0.into_inner(), media);
+}
+
+#[test]
+fn more_parts() {
+    let media = media(100 * 1000);
+    let reads = (0..100).map(|i| (i * 1000, 1000)).collect::<Vec<_>>();
+    let data = serialize(&media, &[reads], &[]);
+
+    let res = open(data.clone(), None).get_info();
+    assert!(matches!(res, Err(Error::MalformedSliceHeader{parts: 100, max: 80, ..})));
+
+    let config = ParserConfig{max_parts_count: 100, ..ParserConfig::default()};
+    let info = open(data.clone(), Some(config)).get_info().expect("parsing");
+    assert_eq!(info.parts().len(), 100);
+    assert_eq!(info.coverage().covered(), vec![0..100 * 1000]);
+
+    let config = ParserConfig{max_parts_count: 99, ..ParserConfig::default()};
+    assert!(matches!(open(data, Some(config)).get_info(), Err(Error::MalformedSliceHeader{max: 99, ..})));
+}
+
+#[test]
+fn resync_part_size() {
+    // Bare parts are only recovered if aligned to the configured part size.
+    let part_size = 1000;
+    let media = media(4 * part_size);
+    let reads = (0..4).map(|i| (i * part_size, part_size)).collect::<Vec<_>>();
+    let mut data = serialize(&media, &[reads], &[]);
+    data[..4].copy_from_slice(&0u32.to_le_bytes());
+
+    let mut serialized_file = open(data.clone(), None);
+    serialized_file.set_resync(true);
+    assert!(serialized_file.get_info().expect("parsing").recovered().iter().all(|rp| rp.part.out_offset == 0));
+
+    let mut serialized_file = open(data, Some(ParserConfig{part_size, ..ParserConfig::default()}));
+    serialized_file.set_resync(true);
+    let info = serialized_file.get_info().expect("parsing");
+    assert_eq!(info.recovered().len(), 4);
+    assert_eq!(info.with_recovered(0.5).coverage().covered(), vec![0..u64::from(4 * part_size)]);
+}
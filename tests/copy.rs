@@ -0,0 +1,139 @@
+/*
+    This file is a part of telegram-media-deserialize.
+
+    Copyright (C) 2022 Apple Sheeple <AppleSheeple at github>
+
+    telegram-media-deserialize is free software: you can
+    redistribute it and/or modify it under the terms of
+    the Affero GNU General Public License as published by
+    the Free Software Foundation.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+    Affero GNU General Public License for more details.
+
+    You should have received a copy of the Affero GNU General Public License
+    along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+
+//! In-kernel copies (`copy_file_range` on Linux) and positioned writes give the same output as
+//! the userspace fallback.
+
+mod common;
+
+use std::fs::{self, File, OpenOptions};
+use std::io::Cursor;
+use std::path::Path;
+
+use telegram_media_deserialize::{DeserializedFile, Error, OrderedPartInfos, RawSlice, Res, SeekWriter, SerializedFile, PART_SIZE};
+
+use common::{media, reads, serialize, temp_dir};
+
+/// Raw slice size, so that the raw slice starts right after the parts.
+const SLICE_SIZE: u64 = 4 * PART_SIZE as u64;
+
+/// The outputs of extracting `cache` (with `raw_slice`, if any) in-kernel to a
+/// [`DeserializedFile`], with positioned writes of a reader without a plain file, and through a
+/// [`SeekWriter`], with the result of each.
+fn outputs(dir: &Path, cache: &Path, raw_slice: Option<&Path>, info: &OrderedPartInfos) -> Vec<(Vec<u8>, Res<()>)> {
+    let cache_name = cache.to_string_lossy().into_owned();
+    let slices = || raw_slice.map(|path| RawSlice::from_name(path.to_string_lossy().into_owned(), 1).expect("opening slice"));
+    let reader_slices = || raw_slice.map(|path| RawSlice::from_reader("slice".into(), File::open(path).expect("opening slice"), 1).expect("opening slice"));
+
+    let kernel = dir.join("kernel");
+    let mut sink = DeserializedFile::from_name(kernel.to_string_lossy().into_owned()).expect("creating output");
+    let kernel_result = SerializedFile::from_name(cache_name.clone()).expect("opening")
+        .write_with_slices_to(info, &mut Vec::from_iter(slices()), SLICE_SIZE, &mut sink);
+
+    let pwrite = dir.join("pwrite");
+    let mut sink = File::create(&pwrite).expect("creating output");
+    let mut reader = SerializedFile::from_reader("cache".into(), File::open(cache).expect("opening")).expect("opening");
+    let pwrite_result = reader.write_with_slices_to(info, &mut Vec::from_iter(reader_slices()), SLICE_SIZE, &mut sink);
+
+    let mut sink = SeekWriter(Cursor::new(Vec::new()));
+    let fallback_result = SerializedFile::from_name(cache_name).expect("opening")
+        .write_with_slices_to(info, &mut Vec::from_iter(slices()), SLICE_SIZE, &mut sink);
+
+    let outputs = vec![
+        (fs::read(&kernel).expect("reading output"), kernel_result),
+        (fs::read(&pwrite).expect("reading output"), pwrite_result),
+        (sink.0.into_inner(), fallback_result),
+    ];
+    fs::remove_file(kernel).expect("removing output");
+    fs::remove_file(pwrite).expect("removing output");
+    outputs
+}
+
+#[test]
+fn same_output() {
+    let dir = temp_dir("copy");
+    let len = 6 * PART_SIZE + 1000;
+    let media = media(len);
+    // out of order, with an unaligned part, then a raw slice
+    let slices = [reads(0, PART_SIZE), reads(3 * PART_SIZE, 4 * PART_SIZE), vec![(PART_SIZE, PART_SIZE), (2 * PART_SIZE + 10, 1000)]];
+    let cache = dir.join("cache");
+    fs::write(&cache, serialize(&media, &slices, &[0; 3])).expect("writing cache");
+    let raw_slice = dir.join("slice");
+    fs::write(&raw_slice, &media[SLICE_SIZE as usize..]).expect("writing slice");
+
+    let info = SerializedFile::from_name(cache.to_string_lossy().into_owned()).expect("opening")
+        .get_info().expect("parsing");
+    let mut expected = media.clone();
+    expected[2 * PART_SIZE as usize..2 * PART_SIZE as usize + 10].fill(0);
+    expected[2 * PART_SIZE as usize + 1010..3 * PART_SIZE as usize].fill(0);
+    for (out, result) in outputs(&dir, &cache, Some(&raw_slice), &info) {
+        result.expect("extracting");
+        assert!(out == expected);
+    }
+
+    fs::remove_dir_all(dir).expect("removing temp dir");
+}
+
+#[test]
+fn short_copies() {
+    let dir = temp_dir("short-copy");
+    let media = media(3 * PART_SIZE);
+    let cache = dir.join("cache");
+    fs::write(&cache, serialize(&media, &[reads(0, 3 * PART_SIZE)], &[])).expect("writing cache");
+    let raw_slice = dir.join("slice");
+    fs::write(&raw_slice, &media[..PART_SIZE as usize]).expect("writing slice");
+    let info = SerializedFile::from_name(cache.to_string_lossy().into_owned()).expect("opening")
+        .get_info().expect("parsing");
+
+    // the cache is cut short after parsing, in the middle of the last part
+    let last_part = 4 + 2 * (8 + u64::from(PART_SIZE)) + 8;
+    let cut = last_part + u64::from(PART_SIZE) - 1000;
+    OpenOptions::new().write(true).open(&cache).expect("opening").set_len(cut).expect("truncating");
+    for (out, result) in outputs(&dir, &cache, None, &info) {
+        match result {
+            Err(Error::TruncatedPart{in_offset, part_size, read, ..}) =>
+                assert_eq!((in_offset, part_size, read), (last_part, PART_SIZE, u64::from(PART_SIZE) - 1000)),
+            result => panic!("not a truncated part: {result:?}"),
+        }
+        assert!(out[..out.len() - 1000] == media[..out.len() - 1000]);
+    }
+
+    // and a raw slice cut short after it was opened, in-kernel and through a SeekWriter
+    fs::write(&cache, serialize(&media, &[reads(0, PART_SIZE)], &[])).expect("writing cache");
+    let cache_name = cache.to_string_lossy().into_owned();
+    let info = SerializedFile::from_name(cache_name.clone()).expect("opening").get_info().expect("parsing");
+    let open_slice = || RawSlice::from_name(raw_slice.to_string_lossy().into_owned(), 1).expect("opening slice");
+    let (mut kernel_slice, mut fallback_slice) = (open_slice(), open_slice());
+    OpenOptions::new().write(true).open(&raw_slice).expect("opening").set_len(1000).expect("truncating");
+
+    let kernel = dir.join("kernel");
+    let mut sink = DeserializedFile::from_name(kernel.to_string_lossy().into_owned()).expect("creating output");
+    let kernel_result = SerializedFile::from_name(cache_name.clone()).expect("opening")
+        .write_with_slices_to(&info, std::slice::from_mut(&mut kernel_slice), SLICE_SIZE, &mut sink);
+    let mut sink = SeekWriter(Cursor::new(Vec::new()));
+    let fallback_result = SerializedFile::from_name(cache_name).expect("opening")
+        .write_with_slices_to(&info, std::slice::from_mut(&mut fallback_slice), SLICE_SIZE, &mut sink);
+    for result in [kernel_result, fallback_result] {
+        assert!(matches!(result, Err(Error::TruncatedSlice{read: 1000, ..})), "{result:?}");
+    }
+    assert!(fs::read(kernel).expect("reading output") == sink.0.into_inner());
+
+    fs::remove_dir_all(dir).expect("removing temp dir");
+}
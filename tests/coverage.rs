@@ -0,0 +1,106 @@
+/*
+    This file is a part of telegram-media-deserialize.
+
+    Copyright (C) 2022 Apple Sheeple <AppleSheeple at github>
+
+    telegram-media-deserialize is free software: you can
+    redistribute it and/or modify it under the terms of
+    the Affero GNU General Public License as published by
+    the Free Software Foundation.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+    Affero GNU General Public License for more details.
+
+    You should have received a copy of the Affero GNU General Public License
+    along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+
+//! Covered ranges are merged, and missing ranges are derived from them.
+
+use telegram_media_deserialize::Coverage;
+
+#[test]
+fn merged_ranges() {
+    // overlapping, adjacent, nested, unsorted and empty ranges
+    let coverage = Coverage::new([300..400, 0..100, 50..150, 150..200, 310..320, 500..500], None);
+    assert_eq!(coverage.covered(), [0..200, 300..400]);
+    assert_eq!(coverage.missing(), vec![200..300]);
+    assert_eq!(coverage.total_len(), 400);
+    assert_eq!(coverage.covered_bytes(), 300);
+    assert_eq!(coverage.missing_bytes(), 100);
+    assert_eq!(coverage.percent(), 75.0);
+
+    let mut inserted = Coverage::new(Some(0..100), None);
+    inserted.insert(300..400);
+    inserted.insert(100..250);
+    inserted.insert(240..300);
+    assert_eq!(inserted.covered(), vec![0..400]);
+    assert!(inserted.missing().is_empty());
+    assert_eq!(inserted.to_string(), "Coverage: 400 of 400 bytes (100.00%)\n Covered: 0..400 (400 bytes)\n");
+}
+
+#[test]
+fn total_len() {
+    // past the covered end, the rest is missing
+    let coverage = Coverage::new(Some(100..200), Some(1000));
+    assert_eq!(coverage.missing(), [0..100, 200..1000]);
+    assert_eq!(coverage.covered_end(), 200);
+    assert_eq!(coverage.to_string(), "Coverage: 100 of 1000 bytes (10.00%)\n \
+        Missing: 0..100 (100 bytes)\n \
+        Covered: 100..200 (100 bytes)\n \
+        Missing: 200..1000 (800 bytes)\n");
+
+    // shorter than the covered end, only bytes before it count
+    let coverage = Coverage::new([0..100, 200..400], Some(300));
+    assert_eq!(coverage.total_len(), 300);
+    assert_eq!(coverage.missing(), vec![100..200]);
+    assert_eq!(coverage.covered_bytes(), 200);
+    assert_eq!(coverage.missing_bytes(), 100);
+
+    // set_total_len() never cuts covered ranges, insert() extends it
+    let mut coverage = Coverage::new(Some(100..200), Some(1000));
+    coverage.set_total_len(50);
+    assert_eq!(coverage.total_len(), 200);
+    coverage.insert(150..250);
+    assert_eq!(coverage.total_len(), 250);
+    assert_eq!(coverage.covered(), vec![100..250]);
+}
+
+#[test]
+fn empty() {
+    let coverage = Coverage::new([], None);
+    assert!(coverage.covered().is_empty());
+    assert!(coverage.missing().is_empty());
+    assert_eq!(coverage.total_len(), 0);
+    assert_eq!(coverage.percent(), 0.0);
+    assert!(!coverage.contains(0));
+    assert!(coverage.contains_range(0..0));
+    assert_eq!(coverage.covered_bytes_in(0..100), 0);
+    assert_eq!(coverage.to_string(), "Coverage: 0 of 0 bytes (0.00%)\n");
+
+    let coverage = Coverage::new(Some(10..10), Some(100));
+    assert_eq!(coverage.missing(), vec![0..100]);
+    assert_eq!(coverage.missing_bytes(), 100);
+}
+
+#[test]
+fn straddling_ranges() {
+    let coverage = Coverage::new([0..10, 20..30, 40..50], None);
+    // starting and ending inside covered ranges
+    assert_eq!(coverage.covered_bytes_in(5..45), 20);
+    // starting and ending in gaps
+    assert_eq!(coverage.covered_bytes_in(15..35), 10);
+    // inside one range, or one gap
+    assert_eq!(coverage.covered_bytes_in(22..28), 6);
+    assert_eq!(coverage.covered_bytes_in(10..20), 0);
+    // past the covered end
+    assert_eq!(coverage.covered_bytes_in(45..100), 5);
+
+    assert!(coverage.contains(0) && coverage.contains(29) && !coverage.contains(30));
+    assert!(coverage.contains_range(20..30));
+    assert!(!coverage.contains_range(5..25));
+    assert!(!coverage.contains_range(45..55));
+}
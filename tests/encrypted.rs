@@ -0,0 +1,167 @@
+/*
+    This file is a part of telegram-media-deserialize.
+
+    Copyright (C) 2022 Apple Sheeple <AppleSheeple at github>
+
+    telegram-media-deserialize is free software: you can
+    redistribute it and/or modify it under the terms of
+    the Affero GNU General Public License as published by
+    the Free Software Foundation.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+    Affero GNU General Public License for more details.
+
+    You should have received a copy of the Affero GNU General Public License
+    along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+
+//! Encrypted `media_cache` files are decrypted from any position and match a known-answer vector,
+//! and a wrong key or a truncated header is detected.
+
+mod common;
+
+use std::io::{Cursor, Read, Seek, SeekFrom};
+
+use telegram_media_deserialize::{EncryptedFile, EncryptionKey, Error, RawSlice, SeekWriter, SerializedFile, PART_SIZE, SLICE_SIZE};
+
+use common::{encrypt, encrypt_with_header, local_key, media, payload, reads, serialize};
+
+fn open(data: Vec<u8>, key: &[u8; 256]) -> Result<EncryptedFile<Cursor<Vec<u8>>>, Error> {
+    EncryptedFile::from_reader("encrypted".into(), Cursor::new(data), &EncryptionKey::new(*key))
+}
+
+#[test]
+fn decrypts_from_any_position() {
+    let key = local_key(1);
+    // long enough for the counter to carry over its last byte
+    let plain = payload(5000, 2);
+    let mut file = open(encrypt(&key, 3, &plain), &key).expect("opening");
+    // padded to the block size
+    assert_eq!(file.len(), 5008);
+
+    let mut all = Vec::new();
+    file.read_to_end(&mut all).expect("reading");
+    assert_eq!(all[..plain.len()], plain);
+    assert_eq!(all[plain.len()..], [0; 8]);
+
+    for start in (0..plain.len()).step_by(7).chain([15, 16, 17, 4095, 4096]) {
+        for len in [1, 15, 16, 17, 33, 300] {
+            let mut buf = vec![0; len];
+            file.seek(SeekFrom::Start(start as u64)).expect("seeking");
+            let n = file.read(&mut buf).expect("reading");
+            assert_eq!(n, len.min(5008 - start));
+            assert_eq!(buf[..n], all[start..start + n], "{len} bytes at {start}");
+        }
+    }
+
+    assert_eq!(file.seek(SeekFrom::End(-9)).expect("seeking"), 4999);
+    let mut buf = [0; 3];
+    file.read_exact(&mut buf).expect("reading");
+    assert_eq!(buf, [plain[4999], 0, 0]);
+    assert_eq!(file.seek(SeekFrom::Current(-20)).expect("seeking"), 4982);
+    file.read_exact(&mut buf).expect("reading");
+    assert_eq!(buf, plain[4982..4985]);
+    assert!(file.seek(SeekFrom::Current(-5000)).is_err());
+    file.seek(SeekFrom::Start(6000)).expect("seeking");
+    assert_eq!(file.read(&mut buf).expect("reading"), 0);
+}
+
+/// Salt and header with a 32 bytes payload, computed independently of the code under test (with
+/// Python's `cryptography`): local key bytes `0..=255`, salt bytes `255` down to `192`.
+const KNOWN_CIPHERTEXT: [u8; 80] = [
+    0x89, 0xe3, 0xca, 0xf4, 0x67, 0xc4, 0x93, 0xbe, 0x78, 0x85, 0x2d, 0xbc, 0xda, 0xa9, 0x31, 0x42,
+    0x4c, 0xed, 0xae, 0x81, 0xd5, 0xa5, 0x13, 0x43, 0xc0, 0x90, 0x27, 0x0e, 0x6c, 0x31, 0x72, 0x3a,
+    0x90, 0x45, 0xa2, 0xcd, 0x89, 0x93, 0x06, 0x47, 0x13, 0x3f, 0x81, 0xc9, 0xd2, 0x57, 0x07, 0x3f,
+    0xa8, 0x2d, 0x46, 0x55, 0x73, 0xb8, 0xb3, 0x61, 0xba, 0xf2, 0xd6, 0xc4, 0x9e, 0x99, 0xec, 0x20,
+    0x3f, 0xbf, 0x75, 0xa4, 0x5f, 0x18, 0x9d, 0xb2, 0x6c, 0x10, 0xd2, 0xea, 0xb1, 0xcf, 0xc3, 0x8e,
+];
+
+#[test]
+fn known_answer() {
+    let key = std::array::from_fn(|i| i as u8);
+    let salt: [u8; 64] = std::array::from_fn(|i| 255 - i as u8);
+    let mut file = open([&salt[..], &KNOWN_CIPHERTEXT].concat(), &key).expect("opening");
+    assert_eq!(file.len(), 32);
+
+    let mut plain = Vec::new();
+    file.read_to_end(&mut plain).expect("reading");
+    assert_eq!(plain, b"media_cache known-answer vector!");
+}
+
+#[test]
+fn parses_encrypted_serialized_file() {
+    let key = local_key(4);
+    let media = media(3 * PART_SIZE / 2);
+    let data = serialize(&media, &[reads(PART_SIZE, 3 * PART_SIZE / 2), reads(0, PART_SIZE)], &[]);
+    let file = open(encrypt(&key, 5, &data), &key).expect("opening");
+
+    let mut serialized_file = SerializedFile::from_reader("encrypted".into(), file).expect("opening");
+    let info = serialized_file.get_info().expect("parsing");
+    assert_eq!(info.parts().len(), 2);
+    let mut out = SeekWriter(Cursor::new(Vec::new()));
+    serialized_file.write_to(&info, &mut out).expect("extracting");
+    assert_eq!(out.0.into_inner(), media);
+}
+
+#[test]
+fn truncated_to_size() {
+    // the padding is dropped with the size from the cache database
+    let key = local_key(10);
+    let plain = payload(1000, 11);
+    let mut file = open(encrypt(&key, 12, &plain), &key).expect("opening");
+    assert!(file.is_padded());
+    file.truncate(2000);
+    assert_eq!(file.len(), 1008);
+    assert!(!file.is_padded());
+    file.truncate(plain.len() as u64);
+    assert_eq!(file.seek(SeekFrom::End(0)).expect("seeking"), 1000);
+
+    file.seek(SeekFrom::Start(990)).expect("seeking");
+    let mut buf = [0; 100];
+    assert_eq!(file.read(&mut buf).expect("reading"), 10);
+    file.rewind().expect("seeking");
+    let mut all = Vec::new();
+    file.read_to_end(&mut all).expect("reading");
+    assert_eq!(all, plain);
+
+    let slice = RawSlice::from_reader("slice".into(), file, 1).expect("opening slice");
+    assert_eq!(slice.out_range(SLICE_SIZE), SLICE_SIZE..SLICE_SIZE + 1000);
+}
+
+#[test]
+fn wrong_key() {
+    let key = local_key(6);
+    let data = encrypt(&key, 7, &[1; 100]);
+    assert!(open(data.clone(), &key).is_ok());
+
+    let mut other = key;
+    other[200] ^= 1;
+    assert!(matches!(open(data.clone(), &other), Err(Error::WrongKey{..})));
+    // the salt is part of the key derivation
+    let mut salted = data;
+    salted[40] ^= 1;
+    assert!(matches!(open(salted, &key), Err(Error::WrongKey{..})));
+
+    // a header with a non-zero format
+    let mut header = [0; 48];
+    header[0] = 1;
+    assert!(matches!(open(encrypt_with_header(&key, 7, &header, &[]), &key), Err(Error::WrongKey{..})));
+}
+
+#[test]
+fn truncated_header() {
+    let key = local_key(8);
+    // salt and header, with an empty payload
+    let data = encrypt(&key, 9, &[]);
+    assert_eq!(data.len(), 64 + 48);
+    let file = open(data.clone(), &key).expect("opening");
+    assert!(file.is_empty());
+
+    for len in [0, 63, 64 + 47] {
+        let res = open(data[..len].to_vec(), &key);
+        assert!(matches!(res, Err(Error::TruncatedHeader{len: l, ..}) if l == len as u64));
+    }
+}
@@ -0,0 +1,179 @@
+/*
+    This file is a part of telegram-media-deserialize.
+
+    Copyright (C) 2022 Apple Sheeple <AppleSheeple at github>
+
+    telegram-media-deserialize is free software: you can
+    redistribute it and/or modify it under the terms of
+    the Affero GNU General Public License as published by
+    the Free Software Foundation.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+    Affero GNU General Public License for more details.
+
+    You should have received a copy of the Affero GNU General Public License
+    along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+
+//! The parser and extractor must terminate without panicking on malformed input.
+
+use std::io::{self, Cursor, ErrorKind, Read, Seek, SeekFrom};
+
+use telegram_media_deserialize::{GapPolicy, PartInfo, RawSlice, SeekWriter, SerializedFile, Serializer, StopReason, WriteAt, PART_SIZE, SLICE_SIZE};
+
+/// Discards writes.
+struct Discard;
+
+impl WriteAt for Discard {
+    fn write_all_at(&mut self, _buf: &[u8], _offset: u64) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Reads at most one byte at a time, and is interrupted every other read.
+struct Trickle<R> {
+    inner: R,
+    interrupt: bool,
+}
+
+impl<R: Read> Read for Trickle<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.interrupt = !self.interrupt;
+        if self.interrupt {
+            return Err(ErrorKind::Interrupted.into());
+        }
+        let n = buf.len().min(1);
+        self.inner.read(&mut buf[..n])
+    }
+}
+
+impl<R: Seek> Seek for Trickle<R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.inner.seek(pos)
+    }
+}
+
+fn header(parts: u32) -> Vec<u8> {
+    parts.to_le_bytes().to_vec()
+}
+
+fn part(out_offset: u32, part_size: u32, payload_len: usize) -> Vec<u8> {
+    [&out_offset.to_le_bytes()[..], &part_size.to_le_bytes(), &vec![0xAA; payload_len]].concat()
+}
+
+fn open(data: Vec<u8>) -> SerializedFile<Cursor<Vec<u8>>> {
+    SerializedFile::from_reader("input".into(), Cursor::new(data)).expect("in-memory input")
+}
+
+#[test]
+fn empty_and_tiny() {
+    let info = open(Vec::new()).get_info().expect("parsing");
+    assert!(info.parts().is_empty());
+    assert_eq!(info.stop_reason(), StopReason::EndOfFile);
+
+    let info = open(vec![1, 0, 0]).get_info().expect("parsing");
+    assert_eq!(info.stop_reason(), StopReason::TruncatedSliceHeader{in_offset: 0});
+    assert_eq!(info.trailing_bytes(), 3);
+}
+
+#[test]
+fn truncated_part_header() {
+    let data = [header(1), part(0, 10, 10), header(2), part(10, 10, 10), vec![20, 0, 0, 0, 10]].concat();
+    let info = open(data).get_info().expect("parsing");
+    assert_eq!(info.parts().len(), 2);
+    assert_eq!(info.stop_reason(), StopReason::TruncatedPartHeader{in_offset: 44});
+    assert_eq!(info.trailing_bytes(), 5);
+}
+
+#[test]
+fn truncated_part_payload() {
+    let data = [header(2), part(0, 10, 10), part(10, PART_SIZE, 1000)].concat();
+    let mut serialized_file = SerializedFile::from_reader("input".into(), Trickle{inner: Cursor::new(data), interrupt: false})
+        .expect("in-memory input");
+    let info = serialized_file.get_info().expect("parsing");
+    assert_eq!(info.parts().last(), Some(&PartInfo{in_offset: 30, out_offset: 10, part_size: 1000}));
+    assert_eq!(info.stop_reason(), StopReason::TruncatedPart{in_offset: 22, out_offset: 10, part_size: PART_SIZE, available: 1000});
+    assert_eq!(info.trailing_bytes(), 0);
+    let coverage = info.coverage();
+    assert_eq!(coverage.missing(), vec![1010..u64::from(PART_SIZE) + 10]);
+
+    let mut out = SeekWriter(Cursor::new(Vec::new()));
+    serialized_file.write_to(&info, &mut out).expect("extracting");
+    assert_eq!(out.0.into_inner(), vec![0xAA; 1010]);
+}
+
+#[test]
+fn out_offset_near_u32_max() {
+    let data = [header(2), part(0, 10, 10), part(u32::MAX - 5, 10, 10)].concat();
+    let mut serialized_file = open(data);
+    let info = serialized_file.get_info().expect("parsing");
+    assert_eq!(info.end_offset(), u64::from(u32::MAX) + 5);
+    assert_eq!(info.coverage().covered(), [0..10, u64::from(u32::MAX) - 5..u64::from(u32::MAX) + 5]);
+    serialized_file.write_to(&info, &mut Discard).expect("extracting");
+    let written = serialized_file.write_stream_to(&info, &mut [] as &mut [RawSlice], SLICE_SIZE, GapPolicy::Cut, &mut io::sink())
+        .expect("streaming");
+    assert_eq!(written, 10);
+}
+
+#[test]
+fn raw_slice_index_overflow() {
+    let data = [header(1), part(0, 10, 10)].concat();
+    let mut serialized_file = open(data);
+    let info = serialized_file.get_info().expect("parsing");
+    let mut slices = [RawSlice::from_reader("slice".into(), Cursor::new(vec![0; 100]), u64::MAX).expect("in-memory slice")];
+    assert_eq!(slices[0].out_range(SLICE_SIZE), u64::MAX..u64::MAX);
+    let written = serialized_file.write_stream_to(&info, &mut slices, SLICE_SIZE, GapPolicy::Cut, &mut io::sink())
+        .expect("streaming");
+    assert_eq!(written, 10);
+}
+
+#[test]
+fn mutated_inputs() {
+    let media = (0..3 * PART_SIZE).map(|i| i as u8).collect::<Vec<_>>();
+    let mut serializer = Serializer::from_writer("input".into(), Vec::new());
+    serializer.write_slices(&mut Cursor::new(&media), &[
+        vec![(0, PART_SIZE), (2 * PART_SIZE, 1000)],
+        vec![(PART_SIZE, PART_SIZE), (2 * PART_SIZE, PART_SIZE)],
+    ]).expect("serializing");
+    serializer.write_raw(&[1, 2, 3]).expect("writing trailer");
+    let valid = serializer.finish().expect("finishing");
+    let header_offsets = [0, 4, 8, 131084, 131088, 132092, 132096, 132100, 263180, 263184];
+
+    let mut state = 0x2545_f491_4f6c_dd1du64;
+    let mut next = |bound: usize| {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        (state % bound as u64) as usize
+    };
+    for _ in 0..2000 {
+        let mut data = valid.clone();
+        for _ in 0..1 + next(4) {
+            match next(3) {
+                0 => {
+                    let offset = header_offsets[next(header_offsets.len())] + next(4);
+                    if let Some(byte) = data.get_mut(offset) {
+                        *byte = next(256) as u8;
+                    }
+                },
+                1 => {
+                    let offset = header_offsets[next(header_offsets.len())];
+                    if let Some(bytes) = data.get_mut(offset..offset + 4) {
+                        bytes.copy_from_slice(&[0xFF; 4]);
+                    }
+                },
+                _ => data.truncate(next(data.len() + 1)),
+            }
+        }
+
+        let mut serialized_file = open(data);
+        let Ok(info) = serialized_file.get_info() else {
+            continue;
+        };
+        let _ = serialized_file.write_to(&info, &mut Discard);
+        let _ = serialized_file.write_stream_to(&info, &mut [] as &mut [RawSlice], SLICE_SIZE, GapPolicy::Cut, &mut io::sink());
+    }
+}
@@ -0,0 +1,102 @@
+/*
+    This file is a part of telegram-media-deserialize.
+
+    Copyright (C) 2022 Apple Sheeple <AppleSheeple at github>
+
+    telegram-media-deserialize is free software: you can
+    redistribute it and/or modify it under the terms of
+    the Affero GNU General Public License as published by
+    the Free Software Foundation.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+    Affero GNU General Public License for more details.
+
+    You should have received a copy of the Affero GNU General Public License
+    along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+
+//! ddrescue mapfiles list every byte of the output as finished or missing.
+
+use telegram_media_deserialize::Coverage;
+use telegram_media_deserialize::mapfile::{write_mapfile, MissingStatus};
+
+fn mapfile(coverage: &Coverage, missing_status: MissingStatus) -> String {
+    let mut out = Vec::new();
+    write_mapfile(coverage, missing_status, &mut out).expect("writing");
+    String::from_utf8(out).expect("UTF-8")
+}
+
+/// The `(pos, size, status)` block lines of `mapfile`, after checking its header.
+fn parse_blocks(mapfile: &str) -> Vec<(u64, u64, char)> {
+    let mut lines = mapfile.lines();
+    assert!(lines.next().is_some_and(|l| l.starts_with("# Mapfile. Created by telegram-media-deserialize v")));
+    assert_eq!(lines.next(), Some("# current_pos  current_status  current_pass"));
+    assert_eq!(lines.next(), Some("0x00000000     +               1"));
+    assert_eq!(lines.next(), Some("#      pos        size  status"));
+    lines
+        .map(|line| {
+            let fields = line.split_whitespace().collect::<Vec<_>>();
+            let hex = |field: &str| u64::from_str_radix(field.strip_prefix("0x").expect("hex"), 16).expect("hex");
+            assert_eq!(fields.len(), 3, "{line}");
+            (hex(fields[0]), hex(fields[1]), fields[2].chars().next().expect("status"))
+        })
+        .collect()
+}
+
+/// Checks that `blocks` are contiguous over `0..total_len`.
+fn assert_contiguous(blocks: &[(u64, u64, char)], total_len: u64) {
+    let end = blocks.iter().fold(0, |pos, &(start, size, _)| {
+        assert_eq!(start, pos);
+        assert!(size > 0);
+        start + size
+    });
+    assert_eq!(end, total_len);
+}
+
+#[test]
+fn holes() {
+    // leading, middle and trailing holes
+    let coverage = Coverage::new([0x100..0x200, 0x300..0x1000], Some(0x12345));
+    let text = mapfile(&coverage, MissingStatus::NonTried);
+    assert!(text.ends_with("\
+        0x00000000  0x00000100  ?\n\
+        0x00000100  0x00000100  +\n\
+        0x00000200  0x00000100  ?\n\
+        0x00000300  0x00000D00  +\n\
+        0x00001000  0x00011345  ?\n"));
+    let blocks = parse_blocks(&text);
+    assert_contiguous(&blocks, 0x12345);
+
+    let bad = parse_blocks(&mapfile(&coverage, MissingStatus::Bad));
+    let statuses = bad.iter().map(|&(_, _, status)| status).collect::<String>();
+    assert_eq!(statuses, "-+-+-");
+}
+
+#[test]
+fn full_coverage() {
+    let coverage = Coverage::new([0..100, 100..5000], None);
+    let blocks = parse_blocks(&mapfile(&coverage, MissingStatus::Bad));
+    assert_eq!(blocks, [(0, 5000, '+')]);
+
+    // a covered range past the total length is cut
+    let coverage = Coverage::new([0..10, 20..30, 40..50], Some(45));
+    let blocks = parse_blocks(&mapfile(&coverage, MissingStatus::NonTried));
+    assert_contiguous(&blocks, 45);
+    assert_eq!(blocks.last(), Some(&(40, 5, '+')));
+
+    // large offsets keep all their digits
+    let coverage = Coverage::new(Some(0x1_0000_0000..0x1_0000_0010), None);
+    let blocks = parse_blocks(&mapfile(&coverage, MissingStatus::NonTried));
+    assert_eq!(blocks, [(0, 0x1_0000_0000, '?'), (0x1_0000_0000, 0x10, '+')]);
+}
+
+#[test]
+fn empty() {
+    let blocks = parse_blocks(&mapfile(&Coverage::new([], None), MissingStatus::NonTried));
+    assert!(blocks.is_empty());
+    let blocks = parse_blocks(&mapfile(&Coverage::new([], Some(100)), MissingStatus::NonTried));
+    assert_eq!(blocks, [(0, 100, '?')]);
+}
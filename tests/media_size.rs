@@ -0,0 +1,174 @@
+/*
+    This file is a part of telegram-media-deserialize.
+
+    Copyright (C) 2022 Apple Sheeple <AppleSheeple at github>
+
+    telegram-media-deserialize is free software: you can
+    redistribute it and/or modify it under the terms of
+    the Affero GNU General Public License as published by
+    the Free Software Foundation.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+    Affero GNU General Public License for more details.
+
+    You should have received a copy of the Affero GNU General Public License
+    along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+
+//! The media size is inferred from a short final part, and cross-checked with the container.
+
+mod common;
+
+use std::io::Cursor;
+
+use telegram_media_deserialize::{Container, ContainerSize, MediaSize, MediaStream, OrderedPartInfos, PartInfo, RawSlice, PART_SIZE};
+
+use common::{mp4_box, open, parse, serialize};
+
+/// `ftyp`, `mdat` and `moov` boxes (or `moov` before `mdat`), `len` bytes in total.
+fn mp4(len: usize, moov_at_end: bool) -> Vec<u8> {
+    let (ftyp, mdat, moov) = (mp4_box(b"ftyp", 24), mp4_box(b"mdat", len - 24 - 1000), mp4_box(b"moov", 1000));
+    if moov_at_end {
+        [ftyp, mdat, moov].concat()
+    } else {
+        [ftyp, moov, mdat].concat()
+    }
+}
+
+fn ebml_element(id: u32, data_len: usize) -> Vec<u8> {
+    let mut e = id.to_be_bytes().to_vec();
+    e.push(0x01);
+    e.extend_from_slice(&(data_len as u64).to_be_bytes()[1..]);
+    e
+}
+
+/// An EBML header and a segment, `len` bytes in total.
+fn mkv(len: usize) -> Vec<u8> {
+    let mut media = [ebml_element(0x1A45_DFA3, 4), vec![0x42, 0x82, 0x81, 0x01]].concat();
+    media.extend(ebml_element(0x1853_8067, len - media.len() - 12));
+    media.resize(len, 0x66);
+    media
+}
+
+fn infer(stream: &mut MediaStream<Cursor<Vec<u8>>>) -> MediaSize {
+    let ordered_info = stream.ordered_info().clone();
+    MediaSize::infer(&ordered_info, &ordered_info.coverage(), stream).expect("inferring")
+}
+
+#[test]
+fn short_final_part() {
+    let len = 5 * PART_SIZE as usize / 2;
+    let mut stream = parse(&mp4(len, true), &[0, 2, 1]);
+    let media_size = infer(&mut stream);
+    assert_eq!(media_size.inferred, Some(len as u64));
+    assert_eq!(media_size.container, Some(ContainerSize{container: Container::Mp4, size: len as u64}));
+    assert_eq!(media_size.consistent(), Some(true));
+    assert_eq!(media_size.to_string(), format!("Media size: {len} bytes (from the serialized file, matching mp4 headers)"));
+
+    // the index at the end was read first, the middle is missing
+    let len = 10 * PART_SIZE as usize + 100;
+    let mut stream = parse(&mp4(len, true), &[0, 9, 10]);
+    let media_size = infer(&mut stream);
+    assert_eq!(media_size.size(), Some(len as u64));
+    assert_eq!(media_size.consistent(), Some(true));
+}
+
+#[test]
+fn no_short_final_part() {
+    // only full parts, the index is at the start
+    let len = 10 * PART_SIZE as usize + 100;
+    let mut stream = parse(&mp4(len, false), &[0, 1]);
+    let media_size = infer(&mut stream);
+    assert_eq!(media_size.inferred, None);
+    assert_eq!(media_size.size(), Some(len as u64));
+    assert_eq!(media_size.to_string(), format!("Media size: {len} bytes (from mp4 headers)"));
+
+    // a short part that isn't at the end of the data
+    let ordered_info = OrderedPartInfos::new(vec![
+        PartInfo{in_offset: 0, out_offset: 0, part_size: 100},
+        PartInfo{in_offset: 100, out_offset: PART_SIZE, part_size: PART_SIZE},
+    ]);
+    assert_eq!(ordered_info.media_size(), None);
+
+    // a short part that isn't aligned
+    let ordered_info = OrderedPartInfos::new(vec![
+        PartInfo{in_offset: 0, out_offset: 100, part_size: 100},
+    ]);
+    assert_eq!(ordered_info.media_size(), None);
+}
+
+#[test]
+fn truncated_final_part() {
+    let len = 2 * PART_SIZE as usize + 5000;
+    let media = mp4(len, true);
+    let mut data = serialize(&media, &[vec![(0, PART_SIZE), (2 * PART_SIZE, 5000)]], &[]);
+    data.truncate(data.len() - 1000);
+    let ordered_info = open(data).get_info().expect("parsing");
+    // the full size of the truncated part is used
+    assert_eq!(ordered_info.media_size(), Some(len as u64));
+}
+
+#[test]
+fn matroska() {
+    let len = 3 * PART_SIZE as usize;
+    let mut stream = parse(&mkv(len), &[0]);
+    let media_size = infer(&mut stream);
+    assert_eq!(media_size.inferred, None);
+    assert_eq!(media_size.container, Some(ContainerSize{container: Container::Matroska, size: len as u64}));
+}
+
+#[test]
+fn inconsistent() {
+    // the container headers claim more than the media holds
+    let mut media = mp4(2 * PART_SIZE as usize, false);
+    media.truncate(PART_SIZE as usize + 10);
+    let mut stream = parse(&media, &[0, 1]);
+    let media_size = infer(&mut stream);
+    assert_eq!(media_size.inferred, Some(u64::from(PART_SIZE) + 10));
+    assert_eq!(media_size.container.map(|cs| cs.size), Some(2 * u64::from(PART_SIZE)));
+    assert_eq!(media_size.consistent(), Some(false));
+    assert_eq!(media_size.size(), Some(u64::from(PART_SIZE) + 10));
+
+    // no container
+    let mut stream = parse(&[7; 1000], &[0]);
+    let media_size = infer(&mut stream);
+    assert_eq!(media_size.container, None);
+    assert_eq!(media_size.consistent(), None);
+}
+
+#[test]
+fn raw_slices() {
+    let slice_size = 4 * u64::from(PART_SIZE);
+    let len = 2 * slice_size as usize + 5000;
+    let media = mp4(len, false);
+    let raw_slice = |index: u64, len: u64| {
+        let start = (index * slice_size) as usize;
+        let data = media[start..start + len as usize].to_vec();
+        RawSlice::from_reader(format!("slice{index}"), Cursor::new(data), index).expect("opening raw slice")
+    };
+    // the header slice, with a short part before the end of the raw slices
+    let mut stream = parse(&media[..2 * PART_SIZE as usize - 10], &[0, 1]);
+    let ordered_info = stream.ordered_info().clone();
+    let coverage = ordered_info.coverage();
+    let infer = |slices: &[RawSlice<_>], stream: &mut MediaStream<_>| {
+        MediaSize::infer_with_slices(&ordered_info, slices, slice_size, &coverage, stream).expect("inferring")
+    };
+    assert_eq!(infer(&[], &mut stream).inferred, Some(2 * u64::from(PART_SIZE) - 10));
+
+    // a short final slice ends the media
+    let media_size = infer(&[raw_slice(1, slice_size), raw_slice(2, 5000)], &mut stream);
+    assert_eq!(media_size.inferred, Some(len as u64));
+    assert_eq!(media_size.consistent(), Some(true));
+    // a full final slice doesn't, and the short part isn't the end
+    let media_size = infer(&[raw_slice(1, slice_size)], &mut stream);
+    assert_eq!(media_size.inferred, None);
+    assert_eq!(media_size.size(), Some(len as u64));
+    // only the slice at the end of the data counts, in any order
+    let media_size = infer(&[raw_slice(1, 5000), raw_slice(2, 5000)], &mut stream);
+    assert_eq!(media_size.inferred, Some(len as u64));
+    let media_size = infer(&[raw_slice(2, 5000), raw_slice(1, 5000)], &mut stream);
+    assert_eq!(media_size.inferred, Some(len as u64));
+}
@@ -0,0 +1,103 @@
+/*
+    This file is a part of telegram-media-deserialize.
+
+    Copyright (C) 2022 Apple Sheeple <AppleSheeple at github>
+
+    telegram-media-deserialize is free software: you can
+    redistribute it and/or modify it under the terms of
+    the Affero GNU General Public License as published by
+    the Free Software Foundation.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+    Affero GNU General Public License for more details.
+
+    You should have received a copy of the Affero GNU General Public License
+    along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+
+//! The memory-mapped parsing mode must give the same results as the file-backed one.
+
+use std::fs;
+use std::io::Cursor;
+use std::path::PathBuf;
+
+use telegram_media_deserialize::{SeekWriter, SerializedFile, Serializer};
+
+/// Serializes `slices` of `(out_offset, part_size)` parts (with payloads derived from the
+/// out offset), followed by `trailer`.
+fn serialize(slices: &[&[(u32, u32)]], trailer: &[u8]) -> Vec<u8> {
+    let media_len = slices.iter().flat_map(|parts| parts.iter()).map(|&(o, s)| o + s).max().unwrap_or(0);
+    let mut media = Cursor::new((0..media_len).map(|i| (i % 251) as u8).collect::<Vec<_>>());
+    let mut serializer = Serializer::from_writer("serialized".into(), Vec::new());
+    for parts in slices {
+        serializer.write_slice(&mut media, parts).expect("serializing");
+    }
+    serializer.write_raw(trailer).expect("writing trailer");
+    serializer.finish().expect("finishing")
+}
+
+fn temp_file(name: &str, data: &[u8]) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("tmd-mmap-{}-{name}", std::process::id()));
+    fs::write(&path, data).expect("writing temp file");
+    path
+}
+
+/// Parses and extracts the file at `path` with both modes, and checks that the results match.
+fn check_same(path: &PathBuf) {
+    let name = path.to_string_lossy().into_owned();
+    let mut file_backed = SerializedFile::from_name(name.clone()).expect("opening");
+    let mut mapped = SerializedFile::from_name_mmap(name).expect("mapping");
+    assert!(mapped.is_mapped() && !file_backed.is_mapped());
+
+    let info = file_backed.get_info().expect("parsing");
+    let mapped_info = mapped.get_info().expect("parsing mapped");
+    assert_eq!(info.parts(), mapped_info.parts());
+    assert_eq!(info.slices(), mapped_info.slices());
+    assert_eq!(info.stop_reason(), mapped_info.stop_reason());
+    assert_eq!(info.trailing_bytes(), mapped_info.trailing_bytes());
+
+    let mut out = SeekWriter(Cursor::new(Vec::new()));
+    let mut mapped_out = SeekWriter(Cursor::new(Vec::new()));
+    file_backed.write_to(&info, &mut out).expect("extracting");
+    mapped.write_to(&mapped_info, &mut mapped_out).expect("extracting mapped");
+    assert_eq!(out.0.into_inner(), mapped_out.0.into_inner());
+
+    fs::remove_file(path).expect("removing temp file");
+}
+
+#[test]
+fn same_part_infos() {
+    let path = temp_file("plain", &serialize(&[
+        &[(0, 131072), (131072, 131072)],
+        &[(1048576, 131072), (1179648, 1000)],
+        &[(262144, 131072)],
+    ], &[1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11]));
+    check_same(&path);
+}
+
+#[test]
+fn same_part_infos_after_bad_header() {
+    let mut data = serialize(&[&[(0, 131072)], &[(131072, 4096)]], &[]);
+    data.extend_from_slice(&1000u32.to_le_bytes());
+    data.extend_from_slice(&[0; 64]);
+    let path = temp_file("bad-count", &data);
+    check_same(&path);
+
+    let mut data = serialize(&[&[(0, 131072)]], &[]);
+    data.extend_from_slice(&1u32.to_le_bytes());
+    data.extend_from_slice(&131072u32.to_le_bytes());
+    data.extend_from_slice(&(1u32 << 20).to_le_bytes());
+    let path = temp_file("bad-size", &data);
+    check_same(&path);
+}
+
+#[test]
+fn same_salvaged_truncated_part() {
+    let mut data = serialize(&[&[(0, 131072), (131072, 131072)]], &[]);
+    data.truncate(data.len() - 100);
+    let path = temp_file("truncated", &data);
+    check_same(&path);
+}
@@ -0,0 +1,116 @@
+/*
+    This file is a part of telegram-media-deserialize.
+
+    Copyright (C) 2022 Apple Sheeple <AppleSheeple at github>
+
+    telegram-media-deserialize is free software: you can
+    redistribute it and/or modify it under the terms of
+    the Affero GNU General Public License as published by
+    the Free Software Foundation.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+    Affero GNU General Public License for more details.
+
+    You should have received a copy of the Affero GNU General Public License
+    along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+
+//! The top-level MP4 boxes of the deserialized media stream are checked for presence and
+//! completeness.
+
+mod common;
+
+use std::io::Cursor;
+
+use telegram_media_deserialize::{BoxStatus, Coverage, MediaStream, Mp4Check, TopLevelBox, WalkEnd, PART_SIZE};
+
+use common::{mp4_box, parse};
+
+/// `ftyp`, `mdat` and `moov` boxes, `len` bytes in total, with a 2000 bytes `moov` at the end.
+fn mp4(len: usize) -> Vec<u8> {
+    [mp4_box(b"ftyp", 24), mp4_box(b"mdat", len - 24 - 2000), mp4_box(b"moov", 2000)].concat()
+}
+
+/// Checks `stream`, whose media is `len` bytes long.
+fn check(stream: &mut MediaStream<Cursor<Vec<u8>>>, len: u64) -> Option<Mp4Check> {
+    let stream_coverage = stream.ordered_info().coverage();
+    let mut coverage = stream_coverage.clone();
+    coverage.set_total_len(len);
+    Mp4Check::walk(stream, &stream_coverage, &coverage).expect("walking")
+}
+
+#[test]
+fn complete() {
+    let len = 5 * PART_SIZE as usize / 2;
+    let mut stream = parse(&mp4(len), &[0, 1, 2]);
+    let check = check(&mut stream, len as u64).expect("an MP4 stream");
+    let kinds = check.boxes.iter().map(TopLevelBox::kind_str).collect::<Vec<_>>();
+    assert_eq!(kinds, ["ftyp", "mdat", "moov"]);
+    assert!(check.boxes.iter().all(TopLevelBox::is_covered));
+    assert_eq!(check.boxes[2].range(), len as u64 - 2000..len as u64);
+    assert_eq!(check.end, WalkEnd::End);
+    assert!(check.playable());
+    assert!(check.to_string().ends_with("should play"));
+}
+
+#[test]
+fn missing_mdat_data() {
+    // the start, and a forward seek to moov
+    let len = 7 * PART_SIZE as usize / 2;
+    let mut stream = parse(&mp4(len), &[0, 3]);
+    let check = check(&mut stream, len as u64).expect("an MP4 stream");
+    assert_eq!(check.boxes.len(), 3);
+    assert_eq!(check.boxes[1].missing_bytes, 2 * u64::from(PART_SIZE));
+    assert_eq!(check.status(b"ftyp"), BoxStatus::Complete);
+    assert_eq!(check.status(b"mdat"), BoxStatus::Incomplete);
+    assert_eq!(check.status(b"moov"), BoxStatus::Complete);
+    assert_eq!(check.end, WalkEnd::End);
+    assert!(!check.playable());
+}
+
+#[test]
+fn missing_header() {
+    // moov at the end was never read
+    let len = 5 * PART_SIZE as usize / 2;
+    let mut stream = parse(&mp4(len), &[0, 1]);
+    let check = check(&mut stream, len as u64).expect("an MP4 stream");
+    assert_eq!(check.end, WalkEnd::MissingHeader{offset: len as u64 - 2000});
+    assert_eq!(check.status(b"mdat"), BoxStatus::Incomplete);
+    assert_eq!(check.status(b"moov"), BoxStatus::Missing);
+    assert!(check.to_string().contains("later boxes are unknown"));
+}
+
+#[test]
+fn past_end() {
+    // the media size is known to be shorter than the boxes say (e.g. a truncated download)
+    let len = 5 * PART_SIZE as usize / 2;
+    let mut stream = parse(&mp4(len), &[0]);
+    let check = check(&mut stream, PART_SIZE.into()).expect("an MP4 stream");
+    assert_eq!(check.boxes.len(), 2);
+    assert_eq!(check.end, WalkEnd::PastEnd{end: len as u64 - 2000});
+    assert_eq!(check.status(b"mdat"), BoxStatus::Incomplete);
+}
+
+#[test]
+fn not_mp4() {
+    let media = [mp4_box(b"moov", 100), mp4_box(b"mdat", 100)].concat();
+    let mut stream = Cursor::new(&media);
+    let coverage = Coverage::new(Some(0..200), None);
+    assert_eq!(Mp4Check::walk(&mut stream, &coverage, &coverage).expect("walking"), None);
+    assert_eq!(coverage.covered_bytes_in(50..150), 100);
+}
+
+#[test]
+fn raw_slice_coverage() {
+    // the mdat data is present (e.g. in a raw slice), but can't be read from the stream
+    let media = mp4(1000 + 2024);
+    let mut stream = Cursor::new(&media);
+    let stream_coverage = Coverage::new([0..100, 1000..3024], None);
+    let coverage = Coverage::new(Some(0..3024), None);
+    let check = Mp4Check::walk(&mut stream, &stream_coverage, &coverage).expect("walking").expect("an MP4 stream");
+    assert!(check.playable());
+    assert_eq!(Coverage::new([0..10, 20..30, 40..50], None).covered_bytes_in(5..45), 20);
+}
@@ -0,0 +1,165 @@
+/*
+    This file is a part of telegram-media-deserialize.
+
+    Copyright (C) 2022 Apple Sheeple <AppleSheeple at github>
+
+    telegram-media-deserialize is free software: you can
+    redistribute it and/or modify it under the terms of
+    the Affero GNU General Public License as published by
+    the Free Software Foundation.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+    Affero GNU General Public License for more details.
+
+    You should have received a copy of the Affero GNU General Public License
+    along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+
+//! Misaligned and overlapping parts are flagged, and overlaps are resolved the same way by every
+//! writer.
+
+mod common;
+
+use std::io::{Cursor, Read};
+
+use telegram_media_deserialize::{GapPolicy, HolePolicy, MediaStream, OrderedPartInfos, OverlapPolicy, PartInfo,
+    RawSlice, SeekWriter, Serializer, SLICE_SIZE, PART_SIZE};
+
+use common::{media, open, serialize};
+
+/// A serialized file with one slice per item of `slices`, each holding `(out_offset, part_size, fill)`
+/// parts whose payload is `fill` bytes.
+fn serialized(slices: &[&[(u32, u32, u8)]]) -> Vec<u8> {
+    let mut serializer = Serializer::from_writer("serialized".into(), Vec::new());
+    for parts in slices {
+        let mut media = Vec::new();
+        for &(out_offset, part_size, fill) in *parts {
+            let (start, end) = (out_offset as usize, (out_offset + part_size) as usize);
+            media.resize(media.len().max(end), 0);
+            media[start..end].fill(fill);
+        }
+        let reads = parts.iter().map(|&(out_offset, part_size, _)| (out_offset, part_size)).collect::<Vec<_>>();
+        serializer.write_slice(&mut Cursor::new(media), &reads).expect("serializing");
+    }
+    serializer.finish().expect("finishing")
+}
+
+/// The output of `write_to`, `write_stream_to` and `MediaStream`, which must be the same.
+fn outputs(data: &[u8], info: &OrderedPartInfos) -> Vec<u8> {
+    let open = |data: &[u8]| open(data.to_vec());
+    let mut sink = SeekWriter(Cursor::new(Vec::new()));
+    open(data).write_to(info, &mut sink).expect("writing");
+    let written = sink.0.into_inner();
+
+    let mut streamed = Vec::new();
+    let no_slices: &mut [RawSlice<Cursor<Vec<u8>>>] = &mut [];
+    open(data).write_stream_to(info, no_slices, SLICE_SIZE, GapPolicy::Zero, &mut streamed).expect("streaming");
+    assert_eq!(streamed, written);
+
+    let mut read = Vec::new();
+    MediaStream::from_info(open(data), info.clone(), HolePolicy::Zero).read_to_end(&mut read).expect("reading");
+    assert_eq!(read, written);
+    written
+}
+
+#[test]
+fn clean() {
+    let len = 3 * PART_SIZE;
+    let media = media(len);
+    let data = serialize(&media, &[vec![(0, PART_SIZE)], vec![(PART_SIZE, 2 * PART_SIZE)]], &[]);
+
+    let mut serialized_file = open(data);
+    let info = serialized_file.get_info().expect("parsing");
+    let validation = serialized_file.validate(&info).expect("validating");
+    assert!(validation.is_clean());
+    assert_eq!(validation.to_string(), "");
+}
+
+#[test]
+fn misaligned() {
+    let data = serialized(&[&[(0, 100, 1), (1000, 100, 2)]]);
+    let mut serialized_file = open(data.clone());
+    let info = serialized_file.get_info().expect("parsing");
+    let validation = serialized_file.validate(&info).expect("validating");
+    assert_eq!(validation.misaligned, vec![PartInfo{in_offset: 120, out_offset: 1000, part_size: 100}]);
+    assert!(validation.overlaps.is_empty());
+}
+
+#[test]
+fn identical_overlap() {
+    // the same range read again in a later slice
+    let media = media(PART_SIZE);
+    let data = serialize(&media, &[vec![(0, PART_SIZE)], vec![(0, 1000)]], &[]);
+    let mut serialized_file = open(data.clone());
+    let info = serialized_file.get_info().expect("parsing");
+    let validation = serialized_file.validate(&info).expect("validating");
+    assert!(validation.misaligned.is_empty());
+    assert_eq!(validation.overlaps.len(), 1);
+    let overlap = &validation.overlaps[0];
+    assert_eq!(overlap.range, 0..1000);
+    assert!(overlap.identical);
+    assert_eq!(validation.conflicts().count(), 0);
+
+    for policy in [OverlapPolicy::Last, OverlapPolicy::First] {
+        assert_eq!(outputs(&data, &info.with_overlap_policy(policy)), media);
+    }
+}
+
+#[test]
+fn conflicting_overlap() {
+    let data = serialized(&[&[(0, 300, 1)], &[(100, 100, 2)]]);
+    let mut serialized_file = open(data.clone());
+    let info = serialized_file.get_info().expect("parsing");
+    let validation = serialized_file.validate(&info).expect("validating");
+    let conflicts = validation.conflicts().collect::<Vec<_>>();
+    assert_eq!(conflicts.len(), 1);
+    assert_eq!(conflicts[0].range, 100..200);
+    assert_eq!(conflicts[0].first.in_offset, 12);
+    assert_eq!(conflicts[0].second.in_offset, 324);
+    assert!(validation.to_string().contains("CONFLICT"));
+
+    let last = info.with_overlap_policy(OverlapPolicy::Last);
+    assert_eq!(last.parts(), [
+        PartInfo{in_offset: 12, out_offset: 0, part_size: 100},
+        PartInfo{in_offset: 324, out_offset: 100, part_size: 100},
+        PartInfo{in_offset: 212, out_offset: 200, part_size: 100},
+    ]);
+    let expected = [[1; 100], [2; 100], [1; 100]].concat();
+    assert_eq!(outputs(&data, &last), expected);
+
+    let first = info.with_overlap_policy(OverlapPolicy::First);
+    assert_eq!(first.parts(), [PartInfo{in_offset: 12, out_offset: 0, part_size: 300}]);
+    assert_eq!(outputs(&data, &first), [1; 300]);
+
+    // without resolving, the stream writer and the file writer disagree
+    let mut sink = SeekWriter(Cursor::new(Vec::new()));
+    open(data.clone()).write_to(&info, &mut sink).expect("writing");
+    assert_eq!(sink.0.into_inner(), expected);
+    let mut streamed = Vec::new();
+    let no_slices: &mut [RawSlice<Cursor<Vec<u8>>>] = &mut [];
+    open(data).write_stream_to(&info, no_slices, SLICE_SIZE, GapPolicy::Zero, &mut streamed).expect("streaming");
+    assert_eq!(streamed, [1; 300]);
+}
+
+#[test]
+fn overlaps_keep_coverage() {
+    // three overlapping parts, and one after a gap
+    let data = serialized(&[&[(0, 200, 1)], &[(150, 200, 2)], &[(100, 100, 3), (1000, 10, 4)]]);
+    let mut serialized_file = open(data.clone());
+    let info = serialized_file.get_info().expect("parsing");
+    let validation = serialized_file.validate(&info).expect("validating");
+    let ranges = validation.overlaps.iter().map(|o| o.range.clone()).collect::<Vec<_>>();
+    assert_eq!(ranges, [100..200, 150..200, 150..200]);
+    for policy in [OverlapPolicy::Last, OverlapPolicy::First] {
+        let resolved = info.with_overlap_policy(policy);
+        assert_eq!(resolved.coverage(), info.coverage());
+        assert!(resolved.parts().windows(2).all(|w| w[0].out_end() <= u64::from(w[1].out_offset)));
+    }
+    let last = outputs(&data, &info.with_overlap_policy(OverlapPolicy::Last));
+    assert_eq!(last[..350], [&[1; 100][..], &[3; 100], &[2; 150]].concat());
+    let first = outputs(&data, &info.with_overlap_policy(OverlapPolicy::First));
+    assert_eq!(first[..350], [&[1; 200][..], &[2; 150]].concat());
+}
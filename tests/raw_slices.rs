@@ -0,0 +1,148 @@
+/*
+    This file is a part of telegram-media-deserialize.
+
+    Copyright (C) 2022 Apple Sheeple <AppleSheeple at github>
+
+    telegram-media-deserialize is free software: you can
+    redistribute it and/or modify it under the terms of
+    the Affero GNU General Public License as published by
+    the Free Software Foundation.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+    Affero GNU General Public License for more details.
+
+    You should have received a copy of the Affero GNU General Public License
+    along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+
+//! Follow-up raw slices are written at their slice index, missing slices are left as holes, and
+//! parts covered by a raw slice are skipped.
+
+mod common;
+
+use std::io::{self, Cursor};
+use std::ops::Range;
+
+use telegram_media_deserialize::{GapPolicy, RawSlice, WriteAt, PART_SIZE};
+
+use common::{media, open, payload, reads, serialize};
+
+const SLICE_SIZE: u64 = 4 * PART_SIZE as u64;
+
+/// Records the ranges written to it.
+#[derive(Default)]
+struct Recorder {
+    data: Vec<u8>,
+    writes: Vec<Range<u64>>,
+}
+
+impl WriteAt for Recorder {
+    fn write_all_at(&mut self, buf: &[u8], offset: u64) -> io::Result<()> {
+        let range = offset as usize..offset as usize + buf.len();
+        self.data.resize(self.data.len().max(range.end), 0);
+        self.data[range.clone()].copy_from_slice(buf);
+        self.writes.push(range.start as u64..range.end as u64);
+        Ok(())
+    }
+}
+
+impl Recorder {
+    /// Whether any write overlaps `range`.
+    fn written(&self, range: Range<u64>) -> bool {
+        self.writes.iter().any(|w| w.start < range.end && range.start < w.end)
+    }
+}
+
+/// Slice `index` of `media`, as a raw slice file.
+fn raw_slice(media: &[u8], index: u64) -> RawSlice<Cursor<Vec<u8>>> {
+    let start = (index * SLICE_SIZE) as usize;
+    let data = media[start..media.len().min(start + SLICE_SIZE as usize)].to_vec();
+    RawSlice::from_reader(format!("slice{index}"), Cursor::new(data), index).expect("opening raw slice")
+}
+
+/// The serialized first slice of `media`, and the output of `write_with_slices_to` and of
+/// `write_stream_to` (with `gap_policy`) with `slices`.
+fn outputs(data: &[u8], slices: &mut [RawSlice<Cursor<Vec<u8>>>], gap_policy: GapPolicy) -> (Recorder, Vec<u8>) {
+    let mut serialized_file = open(data.to_vec());
+    let info = serialized_file.get_info().expect("parsing");
+    let mut written = Recorder::default();
+    serialized_file.write_with_slices_to(&info, slices, SLICE_SIZE, &mut written).expect("extracting");
+
+    let mut streamed = Vec::new();
+    let len = open(data.to_vec()).write_stream_to(&info, slices, SLICE_SIZE, gap_policy, &mut streamed)
+        .expect("streaming");
+    assert_eq!(len, streamed.len() as u64);
+    (written, streamed)
+}
+
+#[test]
+fn slices_at_index() {
+    let len = 3 * SLICE_SIZE as u32 + 1000;
+    let media = media(len);
+    let data = serialize(&media, &[reads(0, SLICE_SIZE as u32)], &[]);
+    // in any order
+    let mut slices = [raw_slice(&media, 3), raw_slice(&media, 1), raw_slice(&media, 2)];
+    let (written, streamed) = outputs(&data, &mut slices, GapPolicy::Zero);
+    assert_eq!(written.data, media);
+    assert_eq!(streamed, media);
+}
+
+#[test]
+fn missing_slice_is_a_hole() {
+    let len = 3 * SLICE_SIZE as u32 + 1000;
+    let media = media(len);
+    let data = serialize(&media, &[reads(0, SLICE_SIZE as u32)], &[]);
+    let hole = SLICE_SIZE..2 * SLICE_SIZE;
+
+    let mut slices = [raw_slice(&media, 2), raw_slice(&media, 3)];
+    let (written, streamed) = outputs(&data, &mut slices, GapPolicy::Zero);
+    // the data after the hole is not shifted
+    assert!(!written.written(hole.clone()));
+    assert_eq!(written.data[hole.end as usize..], media[hole.end as usize..]);
+    assert_eq!(written.data[..hole.start as usize], media[..hole.start as usize]);
+    assert_eq!(streamed.len(), media.len());
+    assert!(streamed[hole.start as usize..hole.end as usize].iter().all(|&b| b == 0));
+    assert_eq!(streamed[hole.end as usize..], media[hole.end as usize..]);
+
+    // the stream stops at the hole
+    let (_, streamed) = outputs(&data, &mut slices, GapPolicy::Cut);
+    assert_eq!(streamed, media[..hole.start as usize]);
+}
+
+#[test]
+fn parts_covered_by_a_slice() {
+    // the first slice, then a forward seek to the end, and one to the middle of slice 2
+    let len = 3 * SLICE_SIZE as u32 + 1000;
+    let media = media(len);
+    let end_part = (3 * SLICE_SIZE as u32, 1000);
+    let middle_part = (2 * SLICE_SIZE as u32 + PART_SIZE, PART_SIZE);
+    // different bytes in the serialized file, to tell which copy is written
+    let serialized_media = payload(len as usize, 31);
+    let data = serialize(&serialized_media, &[reads(0, SLICE_SIZE as u32), vec![end_part, middle_part]], &[]);
+    let range = |(out_offset, part_size): (u32, u32)| u64::from(out_offset)..u64::from(out_offset + part_size);
+
+    // slice 2 covers the middle part, so only the slice is written there
+    let mut slices = [raw_slice(&media, 1), raw_slice(&media, 2)];
+    let (written, streamed) = outputs(&data, &mut slices, GapPolicy::Zero);
+    let middle = range(middle_part);
+    assert_eq!(written.writes.iter().filter(|w| w.start < middle.end && middle.start < w.end).count(), 1);
+    assert_eq!(written.data[middle.start as usize..middle.end as usize], media[middle.start as usize..middle.end as usize]);
+    // the end part isn't covered, so it's kept after the hole of slice 3
+    let end = range(end_part);
+    assert_eq!(written.data[end.start as usize..], serialized_media[end.start as usize..]);
+    assert_eq!(written.data.len(), media.len());
+    assert_eq!(streamed, written.data);
+
+    // a part only partly covered (by a short slice) is kept, the slice is written after it
+    let mut short = raw_slice(&media, 2);
+    short.truncate(u64::from(PART_SIZE) + 10);
+    let (written, streamed) = outputs(&data, &mut [short], GapPolicy::Zero);
+    assert_eq!(streamed, written.data);
+    assert_eq!(written.data[middle.start as usize..middle.start as usize + 10],
+        media[middle.start as usize..middle.start as usize + 10]);
+    assert_eq!(written.data[middle.start as usize + 10..middle.end as usize],
+        serialized_media[middle.start as usize + 10..middle.end as usize]);
+}
@@ -0,0 +1,143 @@
+/*
+    This file is a part of telegram-media-deserialize.
+
+    Copyright (C) 2022 Apple Sheeple <AppleSheeple at github>
+
+    telegram-media-deserialize is free software: you can
+    redistribute it and/or modify it under the terms of
+    the Affero GNU General Public License as published by
+    the Free Software Foundation.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+    Affero GNU General Public License for more details.
+
+    You should have received a copy of the Affero GNU General Public License
+    along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+
+//! The JSON report is valid JSON, with the documented keys.
+
+mod common;
+
+use serde_json::Value;
+use telegram_media_deserialize::{MediaSize, Mp4Check, OrderedPartInfos, OverlapPolicy, PartInfo, RawSliceReport, Report, Validation, PART_SIZE};
+
+use common::{media, mp4_box, open, parse, reads, serialize};
+
+fn json(report: &Report) -> Value {
+    let mut out = Vec::new();
+    report.write_json(&mut out).expect("writing");
+    assert_eq!(out.iter().filter(|&&b| b == b'\n').count(), 1, "a single line");
+    serde_json::from_slice(&out).expect("valid JSON")
+}
+
+fn keys(value: &Value) -> Vec<&str> {
+    value.as_object().expect("an object").keys().map(String::as_str).collect()
+}
+
+#[test]
+fn full_report() {
+    let len = 5 * PART_SIZE / 2;
+    let media = [mp4_box(b"ftyp", 24), mp4_box(b"mdat", len as usize - 1024), mp4_box(b"moov", 1000)].concat();
+    let mut stream = parse(&media, &[0, 2]);
+    let ordered_info = stream.ordered_info().clone();
+    let coverage = ordered_info.coverage();
+    let media_size = MediaSize::infer(&ordered_info, &coverage, &mut stream).expect("inferring");
+    let mp4 = Mp4Check::walk(&mut stream, &coverage, &coverage).expect("walking");
+    let report = Report {
+        input: "cache",
+        ordered_info: &ordered_info,
+        raw_slices: vec![RawSliceReport{name: "slice".into(), index: 1, out_offset: 8 << 20, len: 100}],
+        coverage: &coverage,
+        media_size,
+        mp4,
+        validation: &Validation::default(),
+        overlap_policy: OverlapPolicy::Last,
+    };
+
+    let value = json(&report);
+    assert_eq!(keys(&value), [
+        "coverage", "header_slice", "input", "last_contiguous_offset", "media_size", "mp4", "raw_slices",
+        "recovered_parts", "slices", "stop_reason", "trailer", "trailing_bytes", "validation",
+    ]);
+    assert_eq!(value["input"], "cache");
+    assert_eq!(keys(&value["slices"][0]), ["in_offset", "index", "parts", "parts_count"]);
+    assert_eq!(keys(&value["slices"][0]["parts"][0]), ["in_offset", "out_offset", "part_size"]);
+    assert_eq!(value["slices"][0]["parts"][1]["out_offset"], 2 * PART_SIZE);
+    assert_eq!(value["raw_slices"][0], serde_json::json!({"name": "slice", "index": 1, "out_offset": 8 << 20, "len": 100}));
+    assert_eq!(value["stop_reason"]["kind"], "end_of_file");
+    assert_eq!(value["trailer"]["kind"], "none");
+    assert_eq!(value["media_size"]["size"], len);
+    assert_eq!(value["media_size"]["container"]["format"], "mp4");
+    assert_eq!(value["media_size"]["consistent"], true);
+    assert_eq!(value["mp4"]["moov"], "complete");
+    assert_eq!(value["mp4"]["mdat"], "incomplete");
+    assert_eq!(value["mp4"]["boxes"].as_array().map(Vec::len), Some(3));
+    assert_eq!(keys(&value["validation"]), ["conflicts", "misaligned", "more_overlaps", "overlap_policy", "overlaps"]);
+    assert_eq!(keys(&value["coverage"]), ["covered", "covered_bytes", "missing", "missing_bytes", "percent", "total_len"]);
+    assert_eq!(value["coverage"]["missing"], serde_json::json!([{"start": PART_SIZE, "end": 2 * PART_SIZE}]));
+    assert_eq!(value["coverage"]["percent"].as_f64(), Some(coverage.percent()));
+}
+
+#[test]
+fn escaping() {
+    let name = "a \"quoted\" \\ name\nwith\ttabs\r, \u{1}\u{1f} controls, \u{7f} and \u{e9}\u{1f600}";
+    let ordered_info = OrderedPartInfos::new(vec![PartInfo{in_offset: 12, out_offset: 0, part_size: 100}]);
+    let coverage = ordered_info.coverage();
+    let report = Report {
+        input: name,
+        ordered_info: &ordered_info,
+        raw_slices: vec![RawSliceReport{name: name.into(), index: 1, out_offset: 0, len: 0}],
+        coverage: &coverage,
+        media_size: MediaSize::default(),
+        mp4: None,
+        validation: &Validation::default(),
+        overlap_policy: OverlapPolicy::First,
+    };
+
+    let mut out = Vec::new();
+    report.write_json(&mut out).expect("writing");
+    let text = String::from_utf8(out).expect("UTF-8");
+    assert!(text.contains(r#""a \"quoted\" \\ name\nwith\ttabs\r, \u0001\u001f controls"#));
+    let value: Value = serde_json::from_str(&text).expect("valid JSON");
+    assert_eq!(value["input"], name);
+    assert_eq!(value["raw_slices"][0]["name"], name);
+    assert_eq!(value["mp4"], Value::Null);
+}
+
+#[test]
+fn recovered_parts() {
+    // two slices of two parts, the second slice header corrupt
+    let media = media(4 * PART_SIZE);
+    let mut data = serialize(&media, &[reads(0, 2 * PART_SIZE), reads(2 * PART_SIZE, 4 * PART_SIZE)], &[]);
+    let corrupt = 4 + 2 * (8 + PART_SIZE as usize);
+    data[corrupt..corrupt + 4].copy_from_slice(&0xFFFFu32.to_le_bytes());
+    let mut serialized_file = open(data);
+    serialized_file.set_resync(true);
+    let ordered_info = serialized_file.get_info().expect("parsing");
+    let coverage = ordered_info.coverage();
+    let report = Report {
+        input: "cache",
+        ordered_info: &ordered_info,
+        raw_slices: Vec::new(),
+        coverage: &coverage,
+        media_size: MediaSize::default(),
+        mp4: None,
+        validation: &Validation::default(),
+        overlap_policy: OverlapPolicy::Last,
+    };
+
+    let value = json(&report);
+    assert_eq!(value["stop_reason"]["kind"], "bad_parts_count");
+    let recovered = value["recovered_parts"].as_array().expect("an array");
+    assert_eq!(recovered.len(), 2);
+    assert_eq!(keys(&recovered[0]), ["confidence", "in_offset", "out_offset", "part_size", "slice_in_offset"]);
+    assert_eq!(recovered[0]["out_offset"], 2 * PART_SIZE);
+    assert_eq!(recovered[0]["slice_in_offset"], Value::Null);
+    for (rp, expected) in recovered.iter().zip(ordered_info.recovered()) {
+        assert_eq!(rp["confidence"].as_f64(), Some(expected.confidence));
+    }
+}
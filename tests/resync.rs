@@ -0,0 +1,137 @@
+/*
+    This file is a part of telegram-media-deserialize.
+
+    Copyright (C) 2022 Apple Sheeple <AppleSheeple at github>
+
+    telegram-media-deserialize is free software: you can
+    redistribute it and/or modify it under the terms of
+    the Affero GNU General Public License as published by
+    the Free Software Foundation.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+    Affero GNU General Public License for more details.
+
+    You should have received a copy of the Affero GNU General Public License
+    along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+
+//! Parts after a corrupt header are recovered by the opt-in resync.
+
+use std::io::Cursor;
+
+use telegram_media_deserialize::{Error, ParserConfig, SeekWriter, SerializedFile, Serializer, StopReason, PART_SIZE};
+
+fn media() -> Vec<u8> {
+    (0..8 * PART_SIZE).map(|i| (i % 253) as u8).collect()
+}
+
+/// Three slices of two full parts each, covering the first six parts of `media`, and the
+/// offsets of the slice headers.
+fn serialized(media: &[u8]) -> (Vec<u8>, Vec<u64>) {
+    let reads = |start: u32| vec![(start * PART_SIZE, PART_SIZE), ((start + 1) * PART_SIZE, PART_SIZE)];
+    let mut serializer = Serializer::from_writer("serialized".into(), Vec::new());
+    let slices = serializer.write_slices(&mut Cursor::new(media), &[reads(0), reads(2), reads(4)]).expect("serializing");
+    (serializer.finish().expect("finishing"), slices.iter().map(|si| si.in_offset).collect())
+}
+
+fn parse(data: Vec<u8>, resync: bool) -> (SerializedFile<Cursor<Vec<u8>>>, telegram_media_deserialize::OrderedPartInfos) {
+    let mut serialized_file = SerializedFile::from_reader("serialized".into(), Cursor::new(data)).expect("opening");
+    serialized_file.set_resync(resync);
+    let info = serialized_file.get_info().expect("parsing");
+    (serialized_file, info)
+}
+
+#[test]
+fn corrupt_slice_header() {
+    let media = media();
+    let (mut data, slice_offsets) = serialized(&media);
+    let corrupt = slice_offsets[1] as usize;
+    data[corrupt..corrupt + 4].copy_from_slice(&0xFFFFu32.to_le_bytes());
+
+    let (_, info) = parse(data.clone(), false);
+    assert_eq!(info.stop_reason(), StopReason::BadPartsCount{in_offset: slice_offsets[1], parts: 0xFFFF});
+    assert!(info.recovered().is_empty());
+
+    let (mut serialized_file, info) = parse(data, true);
+    assert_eq!(info.parts().len(), 2);
+    let recovered = info.recovered();
+    assert_eq!(recovered.len(), 4);
+    // The parts of the corrupt slice are found without their slice header.
+    assert_eq!(recovered[0].part.out_offset, 2 * PART_SIZE);
+    assert_eq!(recovered[0].part.in_offset, slice_offsets[1] + 12);
+    assert_eq!(recovered[0].slice_in_offset, None);
+    assert_eq!(recovered[0].confidence, 0.8);
+    // The next slice is found with its header.
+    assert_eq!(recovered[2].part.out_offset, 4 * PART_SIZE);
+    assert_eq!(recovered[2].slice_in_offset, Some(slice_offsets[2]));
+    assert_eq!(recovered[2].confidence, 1.0);
+
+    // Only the parts with their slice header pass a higher threshold.
+    assert_eq!(info.with_recovered(0.9).parts().len(), 4);
+
+    let merged = info.with_recovered(0.5);
+    assert_eq!(merged.coverage().covered(), vec![0..u64::from(6 * PART_SIZE)]);
+    let mut out = SeekWriter(Cursor::new(Vec::new()));
+    serialized_file.write_to(&merged, &mut out).expect("extracting");
+    assert_eq!(out.0.into_inner(), media[..6 * PART_SIZE as usize]);
+}
+
+#[test]
+fn corrupt_part_size() {
+    let media = media();
+    let (mut data, slice_offsets) = serialized(&media);
+    // Second part header of the second slice.
+    let corrupt = (slice_offsets[1] + 4 + 8 + u64::from(PART_SIZE) + 4) as usize;
+    data[corrupt..corrupt + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+
+    let (_, info) = parse(data, true);
+    assert!(matches!(info.stop_reason(), StopReason::BadPartSize{part_size: u32::MAX, ..}));
+    assert_eq!(info.parts().len(), 3);
+    let recovered = info.recovered().iter().map(|rp| rp.part.out_offset).collect::<Vec<_>>();
+    assert_eq!(recovered, [4 * PART_SIZE, 5 * PART_SIZE]);
+    assert_eq!(info.with_recovered(0.5).coverage().missing(), vec![u64::from(3 * PART_SIZE)..u64::from(4 * PART_SIZE)]);
+}
+
+#[test]
+fn corrupt_first_header() {
+    let media = media();
+    let (mut data, _) = serialized(&media);
+    data[..4].copy_from_slice(&0u32.to_le_bytes());
+
+    let mut serialized_file = SerializedFile::from_reader("serialized".into(), Cursor::new(data.clone())).expect("opening");
+    assert!(matches!(serialized_file.get_info(), Err(Error::MalformedSliceHeader{..})));
+
+    let (_, info) = parse(data, true);
+    assert!(info.parts().is_empty());
+    assert_eq!(info.recovered().len(), 6);
+    assert_eq!(info.with_recovered(0.5).parts().len(), 6);
+}
+
+#[test]
+fn bounded_scan() {
+    // more than the scan window, with parts straddling its ends
+    let media = (0..24 * PART_SIZE).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+    let reads = |start: u32| (start..start + 8).map(|i| (i * PART_SIZE, PART_SIZE)).collect::<Vec<_>>();
+    let mut serializer = Serializer::from_writer("serialized".into(), Vec::new());
+    let slices = serializer.write_slices(&mut Cursor::new(&media), &[reads(0), reads(8), reads(16)]).expect("serializing");
+    let mut data = serializer.finish().expect("finishing");
+    let corrupt = slices[1].in_offset as usize;
+    data[corrupt..corrupt + 4].copy_from_slice(&0xFFFFu32.to_le_bytes());
+
+    let (mut serialized_file, info) = parse(data.clone(), true);
+    assert_eq!(info.recovered().len(), 16);
+    let merged = info.with_recovered(0.5);
+    let mut out = SeekWriter(Cursor::new(Vec::new()));
+    serialized_file.write_to(&merged, &mut out).expect("extracting");
+    assert_eq!(out.0.into_inner(), media);
+
+    // the genuine runs win even with a single candidate at once
+    let mut serialized_file = SerializedFile::from_reader("serialized".into(), Cursor::new(data)).expect("opening");
+    serialized_file.set_config(ParserConfig{max_resync_candidates: 1, ..ParserConfig::default()});
+    serialized_file.set_resync(true);
+    let capped = serialized_file.get_info().expect("parsing");
+    assert_eq!(capped.recovered(), info.recovered());
+}
@@ -0,0 +1,124 @@
+/*
+    This file is a part of telegram-media-deserialize.
+
+    Copyright (C) 2022 Apple Sheeple <AppleSheeple at github>
+
+    telegram-media-deserialize is free software: you can
+    redistribute it and/or modify it under the terms of
+    the Affero GNU General Public License as published by
+    the Free Software Foundation.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+    Affero GNU General Public License for more details.
+
+    You should have received a copy of the Affero GNU General Public License
+    along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+
+//! deserialize(serialize(x)) == x, for various slice layouts.
+
+mod common;
+
+use std::fs;
+use std::io::{Cursor, Read};
+
+use telegram_media_deserialize::{HolePolicy, MediaStream, SeekWriter, SerializedFile, Serializer, StopReason, PART_SIZE};
+
+use common::{media, reads};
+
+/// Serializes `slices` of `media` in memory, deserializes the result, and checks that the
+/// parsed slices and the reassembled stream match.
+fn round_trip(media: &[u8], slices: &[Vec<(u32, u32)>], trailer: &[u8]) -> Vec<u8> {
+    let mut serializer = Serializer::from_writer("serialized".into(), Vec::new());
+    let written = serializer.write_slices(&mut Cursor::new(media), slices).expect("serializing");
+    serializer.write_raw(trailer).expect("writing trailer");
+    let serialized = serializer.finish().expect("finishing");
+
+    let mut serialized_file = SerializedFile::from_reader("serialized".into(), Cursor::new(serialized)).expect("opening");
+    let info = serialized_file.get_info().expect("parsing");
+    assert_eq!(info.slices(), written.as_slice());
+    assert_eq!(info.trailing_bytes(), trailer.len() as u64);
+
+    let mut out = SeekWriter(Cursor::new(Vec::new()));
+    serialized_file.write_to(&info, &mut out).expect("extracting");
+    let out = out.0.into_inner();
+
+    let mut streamed = Vec::new();
+    MediaStream::new(serialized_file, HolePolicy::Zero).expect("wrapping")
+        .read_to_end(&mut streamed).expect("reading stream");
+    assert_eq!(out, streamed);
+    out
+}
+
+#[test]
+fn contiguous() {
+    let media = media(5 * PART_SIZE / 2 + 1000);
+    let out = round_trip(&media, &[reads(0, media.len() as u32)], &[]);
+    assert_eq!(out, media);
+}
+
+#[test]
+fn player_pattern() {
+    // Header, seek to the index at the end, then back to the start.
+    let len = 40 * PART_SIZE + 12345;
+    let media = media(len);
+    let slices = [reads(0, 2 * PART_SIZE), reads(38 * PART_SIZE, len), reads(2 * PART_SIZE, 38 * PART_SIZE)];
+    let out = round_trip(&media, &slices, &[0; 3]);
+    assert_eq!(out, media);
+}
+
+#[test]
+fn overlapping_and_unaligned() {
+    let len = 4 * PART_SIZE;
+    let media = media(len);
+    let slices = [
+        vec![(0, PART_SIZE), (PART_SIZE, 1000)],
+        reads(500, 3 * PART_SIZE),
+        reads(3 * PART_SIZE - 500, len),
+    ];
+    let out = round_trip(&media, &slices, &[]);
+    assert_eq!(out, media);
+}
+
+#[test]
+fn gaps_are_zero() {
+    let len = 10 * PART_SIZE;
+    let media = media(len);
+    let slices = [reads(0, 2 * PART_SIZE), reads(6 * PART_SIZE, 7 * PART_SIZE), reads(9 * PART_SIZE, len)];
+    let out = round_trip(&media, &slices, &[1, 2, 3, 4, 5, 6, 7, 8, 9]);
+    assert_eq!(out.len(), media.len());
+    for (start, end) in [(0, 2), (6, 7), (9, 10)] {
+        let range = (start * PART_SIZE) as usize..(end * PART_SIZE) as usize;
+        assert_eq!(out[range.clone()], media[range]);
+    }
+    for (start, end) in [(2, 6), (7, 9)] {
+        let range = (start * PART_SIZE) as usize..(end * PART_SIZE) as usize;
+        assert!(out[range].iter().all(|&b| b == 0));
+    }
+}
+
+#[test]
+fn to_file() {
+    let media = media(3 * PART_SIZE);
+    let path = std::env::temp_dir().join(format!("tmd-roundtrip-{}", std::process::id()));
+    let name = path.to_string_lossy().into_owned();
+    let _ = fs::remove_file(&path);
+
+    let mut serializer = Serializer::from_name(name.clone()).expect("creating");
+    serializer.write_slices(&mut Cursor::new(&media), &[reads(PART_SIZE, 3 * PART_SIZE), reads(0, PART_SIZE)])
+        .expect("serializing");
+    serializer.finish().expect("finishing");
+    assert!(Serializer::from_name(name.clone()).is_err());
+
+    let mut serialized_file = SerializedFile::from_name(name).expect("opening");
+    let info = serialized_file.get_info().expect("parsing");
+    assert_eq!(info.stop_reason(), StopReason::EndOfFile);
+    let mut out = SeekWriter(Cursor::new(Vec::new()));
+    serialized_file.write_to(&info, &mut out).expect("extracting");
+    assert_eq!(out.0.into_inner(), media);
+
+    fs::remove_file(&path).expect("removing temp file");
+}
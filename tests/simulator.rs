@@ -0,0 +1,170 @@
+/*
+    This file is a part of telegram-media-deserialize.
+
+    Copyright (C) 2022 Apple Sheeple <AppleSheeple at github>
+
+    telegram-media-deserialize is free software: you can
+    redistribute it and/or modify it under the terms of
+    the Affero GNU General Public License as published by
+    the Free Software Foundation.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+    Affero GNU General Public License for more details.
+
+    You should have received a copy of the Affero GNU General Public License
+    along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+
+//! Simulated player caches of synthetic MP4 and Matroska files must reassemble to the media.
+
+mod common;
+
+use std::io::Cursor;
+
+use telegram_media_deserialize::simulator::{index_range, PlayerSimulator, SimulatedCache};
+use telegram_media_deserialize::{Coverage, RawSlice, SeekWriter, SerializedFile, Serializer, PART_SIZE, SLICE_SIZE};
+
+use common::payload;
+
+fn mp4_box(kind: &[u8; 4], data: &[u8]) -> Vec<u8> {
+    let mut b = ((data.len() + 8) as u32).to_be_bytes().to_vec();
+    b.extend_from_slice(kind);
+    b.extend_from_slice(data);
+    b
+}
+
+/// A minimal MP4 with an `mdat` of `mdat_len` bytes, and a `moov` of `moov_len` bytes
+/// before or after it.
+fn mp4(mdat_len: usize, moov_len: usize, moov_at_end: bool) -> Vec<u8> {
+    let ftyp = mp4_box(b"ftyp", b"isom\0\0\x02\0isomiso2avc1mp41");
+    let moov = mp4_box(b"moov", &payload(moov_len, 1));
+    let mdat = mp4_box(b"mdat", &payload(mdat_len, 2));
+    if moov_at_end {
+        [ftyp, mdat, moov].concat()
+    } else {
+        [ftyp, moov, mdat].concat()
+    }
+}
+
+fn ebml_element(id: u32, data: &[u8]) -> Vec<u8> {
+    let mut e = id.to_be_bytes().into_iter().skip_while(|&b| b == 0).collect::<Vec<_>>();
+    // 8 bytes size
+    e.push(0x01);
+    e.extend_from_slice(&(data.len() as u64).to_be_bytes()[1..]);
+    e.extend_from_slice(data);
+    e
+}
+
+/// A minimal Matroska file with two clusters, followed by `Cues` found through a SeekHead.
+fn mkv(cluster_len: usize, cues_len: usize) -> (Vec<u8>, std::ops::Range<u64>) {
+    let ebml = ebml_element(0x1A45_DFA3, &ebml_element(0x4282, b"matroska"));
+    let info = ebml_element(0x1549_A966, &payload(100, 3));
+    let clusters = [ebml_element(0x1F43_B675, &payload(cluster_len, 4)), ebml_element(0x1F43_B675, &payload(cluster_len, 5))].concat();
+    let cues = ebml_element(0x1C53_BB6B, &payload(cues_len, 6));
+
+    // SeekHead with one Seek entry (fixed size), pointing to the Cues.
+    let seek_head_len = ebml_element(0x114D_9B74, &ebml_element(0x4DBB,
+        &[ebml_element(0x53AB, &[0x1C, 0x53, 0xBB, 0x6B]), ebml_element(0x53AC, &[0; 8])].concat())).len();
+    let cues_position = (seek_head_len + info.len() + clusters.len()) as u64;
+    let seek_head = ebml_element(0x114D_9B74, &ebml_element(0x4DBB,
+        &[ebml_element(0x53AB, &[0x1C, 0x53, 0xBB, 0x6B]), ebml_element(0x53AC, &cues_position.to_be_bytes())].concat()));
+
+    let segment = ebml_element(0x1853_8067, &[seek_head, info, clusters, cues.clone()].concat());
+    let media = [ebml, segment].concat();
+    let cues = (media.len() - cues.len()) as u64..media.len() as u64;
+    (media, cues)
+}
+
+/// Writes the simulated cache files of `media` in memory, then reassembles them.
+fn reassemble(media: &[u8], cache: &SimulatedCache) -> (Vec<u8>, Coverage) {
+    let mut serializer = Serializer::from_writer("serialized".into(), Vec::new());
+    let written = cache.write_serialized(&mut Cursor::new(media), &mut serializer).expect("serializing");
+    let serialized = serializer.finish().expect("finishing");
+
+    let mut slices = (0..cache.raw_slices.len())
+        .map(|i| {
+            let mut raw = Vec::new();
+            cache.write_raw_slice(&mut Cursor::new(media), i, &mut raw).expect("writing raw slice");
+            RawSlice::from_reader(format!("slice{i}"), Cursor::new(raw), cache.raw_slices[i].0).expect("opening raw slice")
+        })
+        .collect::<Vec<_>>();
+
+    let mut serialized_file = SerializedFile::from_reader("serialized".into(), Cursor::new(serialized)).expect("opening");
+    let info = serialized_file.get_info().expect("parsing");
+    assert_eq!(info.slices(), written.as_slice());
+
+    let mut coverage = info.coverage();
+    slices.iter().for_each(|slice| coverage.insert(slice.out_range(SLICE_SIZE)));
+
+    let mut out = SeekWriter(Cursor::new(Vec::new()));
+    serialized_file.write_with_slices_to(&info, &mut slices, SLICE_SIZE, &mut out).expect("extracting");
+    (out.0.into_inner(), coverage)
+}
+
+#[test]
+fn mp4_moov_at_end() {
+    let media = mp4(20 << 20, 300_000, true);
+    let simulator = PlayerSimulator::from_media(&mut Cursor::new(&media)).expect("simulating");
+    let moov = simulator.index().expect("moov after mdat");
+    assert_eq!(moov.end, media.len() as u64);
+    assert_eq!(moov.end - moov.start, 300_008);
+
+    let cache = simulator.cache();
+    // The header slice holds the first part, then the parts of the moov box.
+    let header = &cache.serialized[0];
+    assert_eq!(header[0], (0, PART_SIZE));
+    assert_eq!(u64::from(header[1].0), moov.start / u64::from(PART_SIZE) * u64::from(PART_SIZE));
+    assert_eq!(header.last().map(|&(o, s)| u64::from(o + s)), Some(media.len() as u64));
+    // The rest of the first slice follows, in order.
+    assert_eq!(cache.serialized[1].first(), Some(&(PART_SIZE, PART_SIZE)));
+    assert_eq!(cache.serialized[1].len(), 63);
+    assert_eq!(cache.raw_slices.iter().map(|(i, _)| *i).collect::<Vec<_>>(), [1, 2]);
+
+    let (out, coverage) = reassemble(&media, &cache);
+    assert_eq!(out, media);
+    assert_eq!(coverage.covered(), cache.coverage().covered());
+}
+
+#[test]
+fn mp4_moov_first() {
+    let media = mp4(3 << 20, 5000, false);
+    let simulator = PlayerSimulator::from_media(&mut Cursor::new(&media)).expect("simulating");
+    assert_eq!(simulator.index(), None);
+
+    let cache = simulator.cache();
+    assert_eq!(cache.serialized[0], [(0, PART_SIZE)]);
+    assert!(cache.raw_slices.is_empty());
+    assert_eq!(reassemble(&media, &cache).0, media);
+}
+
+#[test]
+fn matroska_cues_at_end() {
+    let (media, cues) = mkv(9 << 20, 200_000);
+    assert_eq!(index_range(&mut Cursor::new(&media)).expect("probing"), Some(cues));
+
+    let simulator = PlayerSimulator::from_media(&mut Cursor::new(&media)).expect("simulating");
+    let cache = simulator.cache();
+    assert_eq!(reassemble(&media, &cache).0, media);
+}
+
+#[test]
+fn partly_played() {
+    let media = mp4(30 << 20, 500_000, true);
+    let mut simulator = PlayerSimulator::from_media(&mut Cursor::new(&media)).expect("simulating");
+    let moov = simulator.index().expect("moov after mdat");
+    simulator.set_played(10 << 20);
+    let cache = simulator.cache();
+    assert_eq!(cache.raw_slices, [(1, SLICE_SIZE..10 << 20)]);
+
+    let (out, coverage) = reassemble(&media, &cache);
+    let moov_start = moov.start / u64::from(PART_SIZE) * u64::from(PART_SIZE);
+    assert_eq!(coverage.covered(), [0..10 << 20, moov_start..media.len() as u64]);
+    assert_eq!(coverage.covered(), cache.coverage().covered());
+    for range in coverage.covered() {
+        let range = range.start as usize..range.end as usize;
+        assert_eq!(out[range.clone()], media[range]);
+    }
+}
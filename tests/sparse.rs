@@ -0,0 +1,80 @@
+/*
+    This file is a part of telegram-media-deserialize.
+
+    Copyright (C) 2022 Apple Sheeple <AppleSheeple at github>
+
+    telegram-media-deserialize is free software: you can
+    redistribute it and/or modify it under the terms of
+    the Affero GNU General Public License as published by
+    the Free Software Foundation.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+    Affero GNU General Public License for more details.
+
+    You should have received a copy of the Affero GNU General Public License
+    along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+
+//! Sparse output files have holes exactly at the missing ranges.
+
+#![cfg(target_os = "linux")]
+
+mod common;
+
+use std::fs;
+
+use telegram_media_deserialize::{expected_holes, Coverage, DeserializedFile, Error, WriteAt, PART_SIZE};
+
+use common::{media, open, part_reads, serialize, temp_dir};
+
+const PART: u64 = PART_SIZE as u64;
+
+#[test]
+fn holes_match_missing_ranges() {
+    let dir = temp_dir("sparse");
+    let media = media(8 * PART_SIZE + 1000);
+    // the start, and a forward seek to the end
+    let mut serialized_file = open(serialize(&media, &[part_reads(media.len(), &[0, 1, 8])], &[]));
+    let ordered_info = serialized_file.get_info().expect("parsing");
+    let coverage = ordered_info.coverage();
+
+    let name = dir.join("out").to_string_lossy().into_owned();
+    let mut deserialized_file = DeserializedFile::from_name(name.clone()).expect("creating");
+    deserialized_file.preallocate(&coverage).expect("preallocating");
+    serialized_file.write_to(&ordered_info, &mut deserialized_file).expect("extracting");
+
+    let block_size = deserialized_file.block_size().expect("block size");
+    assert!(block_size <= PART, "the hole must span whole blocks");
+    assert_eq!(deserialized_file.holes().expect("listing holes"), vec![2 * PART..8 * PART]);
+    assert_eq!(deserialized_file.check_holes(&coverage).expect("checking holes"), 1);
+
+    let out = fs::read(&name).expect("reading output");
+    assert_eq!(out.len(), media.len());
+    assert_eq!(out[..2 * PART as usize], media[..2 * PART as usize]);
+    assert!(out[2 * PART as usize..8 * PART as usize].iter().all(|&b| b == 0));
+    assert_eq!(out[8 * PART as usize..], media[8 * PART as usize..]);
+
+    // a hole filled with zeros
+    deserialized_file.write_all_at(&vec![0; PART as usize], 4 * PART).expect("writing");
+    let e = deserialized_file.check_holes(&coverage).expect_err("a mismatch");
+    assert_eq!(e.exit_code(), 18);
+    let Error::HoleMismatch{holes, expected, ..} = e else {
+        panic!("not a hole mismatch: {e}");
+    };
+    assert_eq!(holes, [2 * PART..4 * PART, 5 * PART..8 * PART]);
+    assert_eq!(expected, expected_holes(&coverage, block_size));
+
+    fs::remove_dir_all(dir).expect("removing temp dir");
+}
+
+#[test]
+fn expected_holes_are_whole_blocks() {
+    // missing ranges shorter than a block aren't holes, the final one doesn't end at a block
+    let coverage = Coverage::new([100..5000, 9000..10000], Some(20000));
+    assert_eq!(expected_holes(&coverage, 4096), vec![12288..20000]);
+    let coverage = Coverage::new([5000..6000, 20000..20100], None);
+    assert_eq!(expected_holes(&coverage, 4096), [0..4096, 8192..16384]);
+}
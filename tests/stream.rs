@@ -0,0 +1,130 @@
+/*
+    This file is a part of telegram-media-deserialize.
+
+    Copyright (C) 2022 Apple Sheeple <AppleSheeple at github>
+
+    telegram-media-deserialize is free software: you can
+    redistribute it and/or modify it under the terms of
+    the Affero GNU General Public License as published by
+    the Free Software Foundation.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+    Affero GNU General Public License for more details.
+
+    You should have received a copy of the Affero GNU General Public License
+    along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+
+//! MediaStream serves reads and seeks from the parts, with holes read as zeros or as errors.
+
+mod common;
+
+use std::io::{Cursor, ErrorKind, Read, Seek, SeekFrom};
+
+use telegram_media_deserialize::{Error, HolePolicy, MediaStream, PART_SIZE};
+
+use common::{media, open, part_reads, serialize};
+
+const PART: u64 = PART_SIZE as u64;
+
+/// A stream over parts 0, 1 and 3 (in different slices) of `media`, part 2 being a hole.
+fn stream(media: &[u8], hole_policy: HolePolicy) -> MediaStream<Cursor<Vec<u8>>> {
+    let data = serialize(media, &[part_reads(media.len(), &[0]), part_reads(media.len(), &[3, 1])], &[]);
+    MediaStream::new(open(data), hole_policy).expect("parsing")
+}
+
+fn read_at(stream: &mut MediaStream<Cursor<Vec<u8>>>, pos: SeekFrom, len: usize) -> std::io::Result<Vec<u8>> {
+    stream.seek(pos)?;
+    let mut buf = vec![0; len];
+    stream.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+fn hole(e: std::io::Error) -> (u64, u64) {
+    match e.into_inner().and_then(|e| e.downcast::<Error>().ok()).map(|e| *e) {
+        Some(Error::Hole{offset, end}) => (offset, end),
+        e => panic!("not a hole: {e:?}"),
+    }
+}
+
+#[test]
+fn reads_spanning_parts() {
+    let media = media(4 * PART_SIZE - 1000);
+    let mut stream = stream(&media, HolePolicy::Error);
+    // parts 0 and 1 are in different slices, and far apart in the serialized file
+    let start = PART as usize - 100;
+    assert_eq!(read_at(&mut stream, SeekFrom::Start(start as u64), 200).expect("reading"), media[start..start + 200]);
+    assert_eq!(stream.stream_position().expect("position"), PART + 100);
+
+    // a read stops at the end of a part
+    stream.seek(SeekFrom::Start(PART - 10)).expect("seeking");
+    let mut buf = [0; 100];
+    assert_eq!(stream.read(&mut buf).expect("reading"), 10);
+    assert_eq!(buf[..10], media[PART as usize - 10..PART as usize]);
+}
+
+#[test]
+fn holes_as_zeros() {
+    let media = media(4 * PART_SIZE - 1000);
+    let mut stream = stream(&media, HolePolicy::Zero);
+    // from part 1, through the hole, into part 3
+    let start = 2 * PART as usize - 50;
+    let read = read_at(&mut stream, SeekFrom::Start(start as u64), PART as usize + 100).expect("reading");
+    assert_eq!(read[..50], media[start..start + 50]);
+    assert!(read[50..50 + PART as usize].iter().all(|&b| b == 0));
+    assert_eq!(read[50 + PART as usize..], media[3 * PART as usize..3 * PART as usize + 50]);
+
+    // a seek into the hole
+    assert_eq!(read_at(&mut stream, SeekFrom::Start(2 * PART + 10), 10).expect("reading"), [0; 10]);
+
+    let mut all = Vec::new();
+    stream.rewind().expect("seeking");
+    stream.read_to_end(&mut all).expect("reading");
+    assert_eq!(all.len(), media.len());
+    assert_eq!(all[..2 * PART as usize], media[..2 * PART as usize]);
+}
+
+#[test]
+fn holes_as_errors() {
+    let media = media(4 * PART_SIZE - 1000);
+    let mut stream = stream(&media, HolePolicy::Error);
+    // a seek into the hole
+    let e = read_at(&mut stream, SeekFrom::Start(2 * PART + 10), 10).expect_err("a hole");
+    assert_eq!(e.kind(), ErrorKind::Other);
+    assert_eq!(hole(e), (2 * PART + 10, 3 * PART));
+
+    // a read up to the hole returns the available bytes first
+    stream.seek(SeekFrom::Start(2 * PART - 50)).expect("seeking");
+    let mut buf = vec![0; 100];
+    assert_eq!(stream.read(&mut buf).expect("reading"), 50);
+    assert_eq!(hole(stream.read(&mut buf).expect_err("a hole")), (2 * PART, 3 * PART));
+
+    // the hole after the last part, up to a known length
+    stream.set_len(media.len() as u64 + 100);
+    assert_eq!(hole(read_at(&mut stream, SeekFrom::End(-50), 10).expect_err("a hole")),
+        (media.len() as u64 + 50, media.len() as u64 + 100));
+}
+
+#[test]
+fn seek_from_end() {
+    // the media size is unknown, the end is the end of the last part
+    let media = media(10 * PART_SIZE);
+    let mut stream = stream(&media, HolePolicy::Error);
+    assert_eq!(stream.len(), 4 * PART);
+    assert_eq!(stream.seek(SeekFrom::End(0)).expect("seeking"), 4 * PART);
+    assert_eq!(read_at(&mut stream, SeekFrom::End(-10), 10).expect("reading"), media[4 * PART as usize - 10..4 * PART as usize]);
+    // past the end, reads are empty
+    assert_eq!(stream.seek(SeekFrom::End(10)).expect("seeking"), 4 * PART + 10);
+    assert_eq!(stream.read(&mut [0; 10]).expect("reading"), 0);
+    // before the start
+    let e = stream.seek(SeekFrom::End(-(4 * PART as i64) - 1)).expect_err("a negative position");
+    assert_eq!(e.kind(), ErrorKind::InvalidInput);
+    assert_eq!(stream.seek(SeekFrom::Current(-10)).expect("seeking"), 4 * PART);
+
+    // once it's known, from the media size
+    stream.set_len(media.len() as u64);
+    assert_eq!(stream.seek(SeekFrom::End(-10)).expect("seeking"), media.len() as u64 - 10);
+}
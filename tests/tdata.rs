@@ -0,0 +1,246 @@
+/*
+    This file is a part of telegram-media-deserialize.
+
+    Copyright (C) 2022 Apple Sheeple <AppleSheeple at github>
+
+    telegram-media-deserialize is free software: you can
+    redistribute it and/or modify it under the terms of
+    the Affero GNU General Public License as published by
+    the Free Software Foundation.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+    Affero GNU General Public License for more details.
+
+    You should have received a copy of the Affero GNU General Public License
+    along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+
+//! The local key is read from a `key_datas` file, with or without a passcode, and from a fixed
+//! known-answer file.
+
+mod common;
+
+use std::fs;
+use std::path::Path;
+use std::sync::OnceLock;
+
+use aes::Aes256;
+use aes::cipher::{BlockEncrypt, KeyInit};
+use aes::cipher::generic_array::GenericArray;
+use md5::Md5;
+use sha1::Sha1;
+use sha2::{Digest, Sha512};
+
+use telegram_media_deserialize::Error;
+use telegram_media_deserialize::tdata::KeyData;
+
+use common::{local_key, payload, temp_dir};
+
+/// `EncryptLocal`: the data with its length prefix, padded, and encrypted with AES-256-IGE
+/// after its message key.
+fn encrypt_local(data: &[u8], key: &[u8; 256]) -> Vec<u8> {
+    let mut plain = [&(data.len() as u32 + 4).to_le_bytes()[..], data].concat();
+    let len = plain.len().next_multiple_of(16);
+    plain.extend(payload(len - plain.len(), 11));
+    let msg_key = &Sha1::digest(&plain)[..16];
+
+    // oldmtp key and IV, in the receiving direction (x = 8)
+    let sha1 = |parts: &[&[u8]]| parts.iter().fold(Sha1::new(), |h, p| h.chain_update(p)).finalize();
+    let a = sha1(&[msg_key, &key[8..40]]);
+    let b = sha1(&[&key[40..56], msg_key, &key[56..72]]);
+    let c = sha1(&[&key[72..104], msg_key]);
+    let d = sha1(&[msg_key, &key[104..136]]);
+    let aes_key = [&a[..8], &b[8..20], &c[4..16]].concat();
+    let iv = [&a[8..20], &b[..8], &c[16..20], &d[..8]].concat();
+
+    // IGE: c[i] = E(p[i] ^ c[i - 1]) ^ p[i - 1]
+    let cipher = Aes256::new_from_slice(&aes_key).expect("key size");
+    let (mut prev_c, mut prev_p) = (iv[..16].to_vec(), iv[16..].to_vec());
+    let mut encrypted = msg_key.to_vec();
+    for block in plain.chunks(16) {
+        let mut c = GenericArray::clone_from_slice(block);
+        c.iter_mut().zip(&prev_c).for_each(|(b, x)| *b ^= x);
+        cipher.encrypt_block(&mut c);
+        c.iter_mut().zip(&prev_p).for_each(|(b, x)| *b ^= x);
+        prev_c = c.to_vec();
+        prev_p = block.to_vec();
+        encrypted.extend(c);
+    }
+    encrypted
+}
+
+/// `CreateLocalKey`.
+fn passcode_key(passcode: &[u8], salt: &[u8]) -> [u8; 256] {
+    let password = Sha512::new().chain_update(salt).chain_update(passcode).chain_update(salt).finalize();
+    let iterations = if passcode.is_empty() { 1 } else { 100_000 };
+    let mut key = [0; 256];
+    pbkdf2::pbkdf2_hmac::<Sha512>(&password, salt, iterations, &mut key);
+    key
+}
+
+/// A `TDF$` file holding `byte_arrays` in a `QDataStream`.
+fn tdf(byte_arrays: &[&[u8]]) -> Vec<u8> {
+    let data = byte_arrays.iter()
+        .flat_map(|b| [&(b.len() as u32).to_be_bytes()[..], b].concat())
+        .collect::<Vec<_>>();
+    let version = 4_005_000i32.to_le_bytes();
+    let md5 = Md5::new()
+        .chain_update(&data)
+        .chain_update((data.len() as i32).to_le_bytes())
+        .chain_update(version)
+        .chain_update(b"TDF$")
+        .finalize();
+    [&b"TDF$"[..], &version, &data, &md5].concat()
+}
+
+/// A `key_datas` file holding `local_key`, encrypted with `passcode`.
+fn key_datas(local_key: &[u8; 256], passcode: &[u8]) -> Vec<u8> {
+    let salt = payload(32, 12);
+    let key_encrypted = encrypt_local(local_key, &passcode_key(passcode, &salt));
+    tdf(&[&salt, &key_encrypted, &encrypt_local(&[0; 12], local_key)])
+}
+
+/// The local key of the known-answer vectors below.
+fn known_local_key() -> [u8; 256] {
+    std::array::from_fn(|i| i as u8)
+}
+
+/// `EncryptLocal` of `EncryptLocal known answer!!!` (no padding needed) with the known local key,
+/// computed independently (with Python's `hashlib` and `cryptography`, its AES-IGE checked against
+/// the OpenSSL test vectors).
+const KNOWN_ENCRYPTED: [u8; 48] = [
+    0xbe, 0x25, 0xaa, 0x84, 0xa1, 0x2e, 0x02, 0x4d, 0x03, 0x69, 0xba, 0x08, 0xb6, 0x40, 0x66, 0x34,
+    0xd9, 0x32, 0xa6, 0xdb, 0x92, 0x76, 0x7d, 0xd1, 0x00, 0x88, 0xcd, 0x1f, 0x13, 0xae, 0x36, 0x3d,
+    0x51, 0xa7, 0xcb, 0xf8, 0x5c, 0xcc, 0x21, 0xa0, 0x1e, 0xe8, 0xe6, 0x14, 0x90, 0x7e, 0xf7, 0xa2,
+];
+
+/// A `key_datas` file holding the known local key, encrypted with the passcode `secret` (salt
+/// bytes `0x40..0x60`, zero padding), computed like [`KNOWN_ENCRYPTED`].
+const KNOWN_KEY_DATAS: [u8; 388] = [
+    0x54, 0x44, 0x46, 0x24, 0x88, 0x1c, 0x3d, 0x00, 0x00, 0x00, 0x00, 0x20, 0x40, 0x41, 0x42, 0x43,
+    0x44, 0x45, 0x46, 0x47, 0x48, 0x49, 0x4a, 0x4b, 0x4c, 0x4d, 0x4e, 0x4f, 0x50, 0x51, 0x52, 0x53,
+    0x54, 0x55, 0x56, 0x57, 0x58, 0x59, 0x5a, 0x5b, 0x5c, 0x5d, 0x5e, 0x5f, 0x00, 0x00, 0x01, 0x20,
+    0x2e, 0x7d, 0x2f, 0x37, 0x9b, 0x46, 0xe8, 0x93, 0xd9, 0xbf, 0x79, 0x2d, 0x3d, 0x28, 0x2b, 0x33,
+    0x5b, 0x19, 0x22, 0x5f, 0x85, 0xfc, 0x7e, 0x0e, 0x64, 0x89, 0xf5, 0x39, 0x4e, 0xfe, 0x11, 0x65,
+    0x5f, 0xa9, 0xae, 0x7c, 0x8c, 0xf9, 0x49, 0xe7, 0xcb, 0xd7, 0xf9, 0xd2, 0x17, 0xa5, 0x4a, 0x32,
+    0x1b, 0xed, 0xfe, 0xf5, 0x8a, 0x9f, 0x06, 0xa6, 0x8d, 0x56, 0xf7, 0x01, 0x09, 0x08, 0x93, 0x61,
+    0xdf, 0xbe, 0x63, 0x6a, 0xc0, 0x1c, 0x35, 0x6f, 0xfc, 0x76, 0xf5, 0x95, 0xb8, 0x02, 0x6e, 0xf9,
+    0xe6, 0x9c, 0x9c, 0x54, 0x01, 0xe1, 0x2e, 0x07, 0x49, 0xd8, 0x1a, 0xe9, 0xf9, 0xf9, 0xfb, 0x4d,
+    0xad, 0x8a, 0xe0, 0x82, 0x32, 0x09, 0x98, 0xa1, 0xbd, 0xdb, 0x42, 0x95, 0x10, 0xdc, 0xb3, 0x5f,
+    0x14, 0xd9, 0x1a, 0xfb, 0x3c, 0x54, 0x48, 0xdc, 0xa2, 0x38, 0xdf, 0x65, 0xf2, 0xf9, 0x1f, 0x40,
+    0x40, 0xc8, 0x62, 0x28, 0x21, 0x7d, 0x75, 0x9d, 0x6b, 0x6f, 0x39, 0x58, 0x50, 0x57, 0xa5, 0xaa,
+    0x62, 0x9a, 0xae, 0xa9, 0x5b, 0x94, 0xff, 0x1f, 0xc6, 0x03, 0xbb, 0x0b, 0x3b, 0x2a, 0x1d, 0x57,
+    0x80, 0xab, 0x40, 0x58, 0x96, 0x9d, 0xd8, 0x94, 0x03, 0x2d, 0x7e, 0x0f, 0x1e, 0xd6, 0xbd, 0xe8,
+    0xca, 0x88, 0x71, 0x14, 0x0f, 0x31, 0xfb, 0x3c, 0x57, 0x22, 0xbe, 0x97, 0xb3, 0x72, 0x47, 0x82,
+    0xbb, 0x3f, 0x41, 0x16, 0xbe, 0x93, 0x78, 0x17, 0x95, 0x85, 0xe5, 0x85, 0x95, 0x4b, 0x6b, 0xa2,
+    0x65, 0xec, 0x93, 0x06, 0x54, 0x2d, 0x40, 0x46, 0xa6, 0x65, 0xcd, 0x3c, 0x9e, 0xb1, 0x1c, 0xcd,
+    0x2b, 0x77, 0xbe, 0x3a, 0x37, 0x11, 0x35, 0x1e, 0x1c, 0xfb, 0xe7, 0xbb, 0x78, 0xd2, 0x0d, 0xe7,
+    0x90, 0x14, 0x6b, 0xef, 0x8f, 0x3e, 0xe7, 0x91, 0x13, 0x4a, 0x80, 0xb1, 0x18, 0xb2, 0x88, 0x50,
+    0xd7, 0x34, 0x31, 0xcf, 0x24, 0x24, 0xec, 0x1e, 0xaf, 0x87, 0x28, 0x7f, 0x81, 0x4d, 0x5b, 0xf9,
+    0xab, 0x2c, 0x3e, 0xf7, 0x12, 0xc8, 0x9a, 0x06, 0xb2, 0x71, 0x1a, 0xd8, 0x8f, 0x0b, 0xa1, 0x51,
+    0x00, 0x00, 0x00, 0x20, 0xa0, 0xd6, 0xcf, 0x9c, 0x1c, 0x96, 0xed, 0x59, 0x91, 0xdd, 0x21, 0xdd,
+    0xc3, 0xd8, 0xf4, 0x9e, 0xed, 0xcf, 0xd4, 0x25, 0x59, 0x67, 0x9f, 0x1c, 0xf8, 0x70, 0x9f, 0xe5,
+    0x29, 0x7e, 0x08, 0xc5, 0xab, 0x15, 0x25, 0x02, 0xee, 0xa6, 0x7b, 0x8a, 0x17, 0x77, 0x08, 0x42,
+    0xef, 0xf3, 0xe3, 0xbe,
+];
+
+fn write(dir: &Path, name: &str, data: &[u8]) {
+    fs::write(dir.join(name), data).expect("writing key file");
+}
+
+#[test]
+fn known_answers() {
+    // the helpers used by the other tests
+    assert_eq!(encrypt_local(b"EncryptLocal known answer!!!", &known_local_key()), KNOWN_ENCRYPTED);
+
+    let dir = temp_dir("tdata-known");
+    write(&dir, "key_datas", &KNOWN_KEY_DATAS);
+    let key_data = KeyData::from_path(&dir.join("key_datas")).expect("reading");
+    assert_eq!(key_data.local_key(Some(b"secret")).expect("decrypting").data(), &known_local_key());
+    assert!(matches!(key_data.local_key(None), Err(Error::WrongPasscode{..})));
+
+    fs::remove_dir_all(dir).expect("removing temp dir");
+}
+
+#[test]
+fn without_passcode() {
+    let dir = temp_dir("tdata-no-passcode");
+    let key = local_key(13);
+    write(&dir, "key_datas", &key_datas(&key, b""));
+
+    let key_data = KeyData::from_tdata(&dir, "data").expect("reading");
+    assert_eq!(key_data.path(), dir.join("key_datas"));
+    assert_eq!(key_data.local_key(None).expect("decrypting").data(), &key);
+    assert_eq!(key_data.local_key(Some(b"")).expect("decrypting").data(), &key);
+
+    fs::remove_dir_all(dir).expect("removing temp dir");
+}
+
+/// A `key_datas` file encrypted with the passcode `secret` (deriving its key is slow, so it's
+/// shared by the tests).
+fn with_passcode_file() -> &'static (Vec<u8>, [u8; 256]) {
+    static FILE: OnceLock<(Vec<u8>, [u8; 256])> = OnceLock::new();
+    FILE.get_or_init(|| {
+        let key = local_key(14);
+        (key_datas(&key, b"secret"), key)
+    })
+}
+
+#[test]
+fn with_passcode() {
+    let dir = temp_dir("tdata-passcode");
+    let (file, key) = with_passcode_file();
+    // another data name, in a legacy file
+    write(&dir, "key_other0", file);
+
+    let key_data = KeyData::from_tdata(&dir, "other").expect("reading");
+    assert_eq!(key_data.local_key(Some(b"secret")).expect("decrypting").data(), key);
+    assert!(matches!(key_data.local_key(None), Err(Error::WrongPasscode{..})));
+    assert!(matches!(KeyData::from_tdata(&dir, "data"), Err(Error::InputMissing{..})));
+
+    fs::remove_dir_all(dir).expect("removing temp dir");
+}
+
+#[test]
+fn wrong_passcode() {
+    let dir = temp_dir("tdata-wrong-passcode");
+    write(&dir, "key_datas", &with_passcode_file().0);
+
+    let key_data = KeyData::from_path(&dir.join("key_datas")).expect("reading");
+    assert!(matches!(key_data.local_key(Some(b"Secret")), Err(Error::WrongPasscode{..})));
+
+    fs::remove_dir_all(dir).expect("removing temp dir");
+}
+
+#[test]
+fn corrupt_key_file() {
+    let dir = temp_dir("tdata-corrupt");
+    let file = key_datas(&local_key(15), b"");
+    let corrupt = |data: &[u8]| {
+        write(&dir, "key_datas", data);
+        match KeyData::from_tdata(&dir, "data").and_then(|key_data| key_data.local_key(None)) {
+            Err(Error::CorruptKeyFile{reason, ..}) => reason,
+            res => panic!("expected a corrupt key file, got {res:?}"),
+        }
+    };
+
+    let mut md5 = file.clone();
+    *md5.last_mut().expect("md5") ^= 1;
+    assert_eq!(corrupt(&md5), "md5 mismatch");
+    // changed data with the old md5
+    let mut data = file.clone();
+    data[20] ^= 1;
+    assert_eq!(corrupt(&data), "md5 mismatch");
+    let mut magic = file.clone();
+    magic[3] = b'%';
+    assert_eq!(corrupt(&magic), "bad magic");
+    assert_eq!(corrupt(&file[..20]), "file too short");
+    assert_eq!(corrupt(&tdf(&[&[1; 32]])), "truncated encrypted key");
+    // an encrypted key that isn't a whole number of blocks
+    assert_eq!(corrupt(&tdf(&[&[1; 32], &[2; 40], &[]])), "bad encrypted data size");
+
+    fs::remove_dir_all(dir).expect("removing temp dir");
+}
@@ -0,0 +1,152 @@
+/*
+    This file is a part of telegram-media-deserialize.
+
+    Copyright (C) 2022 Apple Sheeple <AppleSheeple at github>
+
+    telegram-media-deserialize is free software: you can
+    redistribute it and/or modify it under the terms of
+    the Affero GNU General Public License as published by
+    the Free Software Foundation.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+    Affero GNU General Public License for more details.
+
+    You should have received a copy of the Affero GNU General Public License
+    along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+
+//! The bytes after the last slice, padding and the header slice metadata are decoded.
+
+mod common;
+
+use telegram_media_deserialize::{HeaderSlice, OrderedPartInfos, StopReason, Trailer, PART_SIZE};
+
+use common::{media, open, reads, serialize};
+
+fn get_info(media: &[u8], slices: &[Vec<(u32, u32)>], trailer: &[u8]) -> OrderedPartInfos {
+    open(serialize(media, slices, trailer)).get_info().expect("parsing")
+}
+
+/// Like [`get_info`], for a file that may end with padding.
+fn get_padded_info(media: &[u8], slices: &[Vec<(u32, u32)>], trailer: &[u8]) -> OrderedPartInfos {
+    let mut serialized_file = open(serialize(media, slices, trailer));
+    serialized_file.set_padded(true);
+    serialized_file.get_info().expect("parsing")
+}
+
+/// Header slice metadata for a media of `media_size` bytes.
+fn metadata(media_size: u32, full_in_cache: bool) -> Vec<u8> {
+    [media_size.to_le_bytes(), u32::from(full_in_cache).to_le_bytes()].concat()
+}
+
+#[test]
+fn full_in_cache() {
+    let len = 5 * PART_SIZE / 2;
+    let media = media(len);
+    // parts in the header slice aren't necessarily ordered
+    let mut header = reads(0, len);
+    header.reverse();
+    let info = get_info(&media, &[header], &[]);
+    assert_eq!(info.header_slice(), Some(HeaderSlice{full_in_cache: true, media_size: Some(len.into())}));
+    assert_eq!(info.trailer(), &Trailer::None);
+}
+
+#[test]
+fn not_full_in_cache() {
+    let len = 10 * PART_SIZE + 100;
+    let media = media(len);
+    let not_full = Some(HeaderSlice{full_in_cache: false, media_size: None});
+
+    // the end of the media is in another slice
+    let info = get_info(&media, &[reads(0, PART_SIZE), reads(PART_SIZE, len)], &[]);
+    assert_eq!(info.header_slice(), not_full);
+    // a gap in the header slice
+    let info = get_info(&media, &[[reads(0, PART_SIZE), reads(2 * PART_SIZE, len)].concat()], &[]);
+    assert_eq!(info.header_slice(), not_full);
+    // a media size that's a multiple of the part size
+    let info = get_info(&media, &[reads(0, 2 * PART_SIZE)], &[]);
+    assert_eq!(info.header_slice(), not_full);
+}
+
+#[test]
+fn header_slice_cut_short() {
+    let media = media(3 * PART_SIZE);
+    let mut data = serialize(&media, &[reads(0, 3 * PART_SIZE - 10)], &[]);
+    data.truncate(data.len() - 100);
+    let info = open(data).get_info().expect("parsing");
+    assert!(matches!(info.stop_reason(), StopReason::TruncatedPart{..}));
+    assert_eq!(info.header_slice(), None);
+}
+
+#[test]
+fn trailers() {
+    let media = media(PART_SIZE + 10);
+    let slices = [reads(0, PART_SIZE + 10)];
+
+    let info = get_info(&media, &slices, &[0; 4]);
+    assert_eq!(info.stop_reason(), StopReason::BadPartsCount{in_offset: u64::from(PART_SIZE) + 30, parts: 0});
+    assert_eq!(info.trailing_bytes(), 4);
+    assert_eq!(info.trailer(), &Trailer::EmptySlice);
+
+    let info = get_info(&media, &slices, &[1, 2, 3]);
+    assert_eq!(info.trailer(), &Trailer::Unknown{len: 3, head: vec![1, 2, 3]});
+
+    let info = get_info(&media, &slices, &[0xff; 40]);
+    assert_eq!(info.trailer(), &Trailer::Unknown{len: 40, head: vec![0xff; 16]});
+
+    // zeros after a complete slice header aren't an empty slice
+    let info = get_info(&media, &slices, &[1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+    assert_eq!(info.stop_reason(), StopReason::BadPartSize{in_offset: u64::from(PART_SIZE) + 34, part_size: 0});
+    assert_eq!(info.trailer(), &Trailer::Unknown{len: 8, head: vec![0; 8]});
+}
+
+#[test]
+fn padding() {
+    let media = media(PART_SIZE + 10);
+    let slices = [reads(0, PART_SIZE + 10)];
+
+    // zeros that fit in the padding aren't an empty slice
+    for len in [1, 4, 15] {
+        let info = get_padded_info(&media, &slices, &vec![0; len]);
+        assert_eq!(info.trailer(), &Trailer::Padding{len: len as u64});
+    }
+    let info = get_padded_info(&media, &slices, &[0; 19]);
+    assert_eq!(info.trailer(), &Trailer::EmptySlice);
+    let info = get_padded_info(&media, &slices, &[0; 20]);
+    assert_eq!(info.trailer(), &Trailer::Unknown{len: 20, head: vec![0; 16]});
+    let info = get_padded_info(&media, &slices, &[0, 0, 1]);
+    assert_eq!(info.trailer(), &Trailer::Unknown{len: 3, head: vec![0, 0, 1]});
+}
+
+#[test]
+fn header_slice_metadata() {
+    let len = 10 * PART_SIZE + 100;
+    let media = media(len);
+    let slices = [reads(0, 2 * PART_SIZE)];
+    let decoded = HeaderSlice{full_in_cache: false, media_size: Some(len.into())};
+
+    let info = get_info(&media, &slices, &metadata(len, false));
+    assert_eq!(info.trailer(), &Trailer::HeaderSlice(decoded));
+    assert_eq!(info.header_slice(), Some(decoded));
+    assert_eq!(info.media_size(), Some(len.into()));
+    // followed by padding
+    let padded = [metadata(len, false), vec![0; 15]].concat();
+    assert_eq!(get_padded_info(&media, &slices, &padded).trailer(), &Trailer::HeaderSlice(decoded));
+    assert!(matches!(get_info(&media, &slices, &padded).trailer(), Trailer::Unknown{len: 23, ..}));
+
+    // the whole media, whose size is a multiple of the part size
+    let full = HeaderSlice{full_in_cache: true, media_size: Some((2 * PART_SIZE).into())};
+    let info = get_info(&media, &slices, &metadata(2 * PART_SIZE, true));
+    assert_eq!(info.header_slice(), Some(full));
+    assert_eq!(info.media_size(), Some((2 * PART_SIZE).into()));
+
+    // a media ending before the parts, other flags, and a full media not ending with the parts
+    for trailer in [metadata(PART_SIZE, false), [len.to_le_bytes(), 2u32.to_le_bytes()].concat(), metadata(len, true)] {
+        let info = get_info(&media, &slices, &trailer);
+        assert_eq!(info.trailer(), &Trailer::Unknown{len: 8, head: trailer});
+        assert_eq!(info.header_slice(), Some(HeaderSlice{full_in_cache: false, media_size: None}));
+    }
+}